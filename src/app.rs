@@ -1,8 +1,16 @@
 use leptos::{logging::log, prelude::*, task};
 use leptos_meta::{provide_meta_context, MetaTags, Stylesheet, Title};
-use leptos_router::{components::*, path};
+use leptos_router::{
+    components::*,
+    hooks::{use_navigate, use_params_map, use_query_map},
+    path, SsrMode,
+};
 use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, time::Duration};
 
+use crate::diff::{diff_lines, DiffKind};
+use crate::error::AppError;
+use crate::query::{format_time, normalize_tag, ExperienceLevel, PostQuery, PostSort, ReplyFilter};
 use crate::server;
 
 pub fn shell(options: LeptosOptions) -> impl IntoView {
@@ -23,11 +31,8 @@ pub fn shell(options: LeptosOptions) -> impl IntoView {
     }
 }
 
-#[derive(Clone, Debug)]
-struct User {
-    jwt: String,
-    name: String,
-}
+// ログイン中のユーザー (IDと表示用の名前)
+type User = server::UserInfo;
 
 #[component]
 pub fn App() -> impl IntoView {
@@ -38,10 +43,6 @@ pub fn App() -> impl IntoView {
     provide_context(user);
     provide_context(user_write);
 
-    let (post, set_post) = signal(None::<server::Post>);
-    provide_context(post);
-    provide_context(set_post);
-
     view! {
         // injects a stylesheet into the document <head>
         // id=leptos means cargo-leptos will hot-reload this stylesheet
@@ -51,84 +52,292 @@ pub fn App() -> impl IntoView {
         //<Title text="Welcome to Leptos"/>
 
         <Title text="Biestar"/>
-        <Show when=move || {
-            match user.get(){
-                None => false,
-                Some(_) => true
-            }
-        } fallback=Login>
 
+        // ルートの一覧をサーバー側でも拾えるように、Routesはログイン状態と関係なく置く
         <Router>
-            <Header/>
             <Routes fallback=|| "NotFound">
-                <Route path=path!("/") view=MainScreen/>
-                <Route path=path!("/post") view=PostScreen/>
-                <Route path=path!("/responce") view=ResponceScreen/>
-                </Routes>
+                <ParentRoute path=path!("") view=Layout>
+                    <Route path=path!("/") view=MainScreen/>
+                    <Route path=path!("/post") view=PostScreen/>
+                    // タイトルまでサーバーで埋めるため、全部読み込んでから返す
+                    <Route path=path!("/posts/:id") view=PostDetailScreen ssr=SsrMode::Async/>
+                    <Route path=path!("/posts/:id/reply") view=ResponceScreen/>
+                    <Route path=path!("/posts/:id/edit") view=PostScreen/>
+                    <Route path=path!("/posts/:id/history") view=PostHistoryScreen/>
+                    <Route path=path!("/tags") view=TagTreeScreen/>
+                    <Route path=path!("/drafts") view=DraftsScreen/>
+                    <Route path=path!("/settings") view=SettingsScreen/>
+                    <Route path=path!("/users/:name") view=UserScreen/>
+                    <Route path=path!("/tags/merge") view=TagMergeScreen/>
+                </ParentRoute>
+            </Routes>
         </Router>
+    }
+}
 
-        </Show>
+// ログインしていなければログイン画面、していればヘッダーと各画面を出す
+#[component]
+fn Layout() -> impl IntoView {
+    let user = use_context::<ReadSignal<Option<User>>>().unwrap();
+    let user_write = use_context::<WriteSignal<Option<User>>>().unwrap();
+
+    // トークンはHttpOnlyのcookieにあるので、ログイン状態はサーバーに聞いて戻す
+    // (SSRのときにcookieの更新が間に合うようにblockingにしておく)
+    let me = Resource::new_blocking(|| (), |_| server::current_user());
+
+    view! {
+        <Suspense>
+        {move || Suspend::new(async move {
+            if let Ok(Some(me)) = me.await {
+                user_write.set(Some(me));
+            }
+            view! {
+                <Show when=move || user.get().is_some() fallback=Login>
+                    <Header/>
+                    <Outlet/>
+                </Show>
+            }
+        })}
+        </Suspense>
     }
 }
 
+// 新しい投稿を書いている間、下書きに自動で保存する間隔
+const AUTOSAVE_INTERVAL: Duration = Duration::from_secs(10);
+
+// 新しい投稿 (/post、下書きの続きは /post?draft=ID) と投稿の編集 (/posts/:id/edit)
 #[component]
 fn PostScreen() -> impl IntoView {
-    let (search_tag, set_search_tag) = signal(Vec::<String>::new());
+    let params = use_params_map();
+    let editing = params.read_untracked().get("id");
+    let is_editing = editing.is_some();
+    // 続きを書いている下書き (新しく書き始めたときは最初に保存したときに決まる)
+    let draft_id = RwSignal::new(use_query_map().read_untracked().get("draft"));
+
+    let (search_tag, set_search_tag) = signal(Vec::<server::TagSuggestion>::new());
     let (select_tag, set_select_tag) = signal(Vec::<String>::new());
 
     let (search_string, set_search_string) = signal(String::new());
 
     let (title, set_title) = signal(String::new());
     let (body, set_body) = signal(String::new());
-    let (advanced, set_advanced) = signal(false);
+    let (level, set_level) = signal(ExperienceLevel::default());
+    // 自分で選んだ (か下書き・投稿から入れた) ら、タグを変えても既定値に戻さない
+    let level_chosen = RwSignal::new(false);
+    let choose_level = move |l: ExperienceLevel| {
+        level_chosen.set(true);
+        set_level.set(l);
+    };
+
+    let attachments = RwSignal::new(Vec::<server::Attachment>::new());
 
     let (is_sending, set_is_sending) = signal(false);
+    let (error, set_error) = signal(None::<AppError>);
+    // 最後に下書きを保存した時間と、そのときの内容 (変わっていなければ自動保存しない)
+    let (draft_saved, set_draft_saved) = signal(None::<i64>);
+    let saved_content = StoredValue::new(None::<(String, String, Vec<String>, ExperienceLevel)>);
+    let is_saving = StoredValue::new(false);
+
+    // 下書きの続きを書くときはその内容を入れておく
+    if let (false, Some(id)) = (is_editing, draft_id.get_untracked()) {
+        Effect::new(move |_| {
+            let id = id.clone();
+            task::spawn_local(async move {
+                match server::get_draft(id).await {
+                    Ok(draft) => {
+                        set_title.set(draft.title.clone());
+                        set_body.set(draft.body.clone());
+                        set_select_tag.set(draft.tag.clone());
+                        choose_level(draft.level);
+                        saved_content.set_value(Some((draft.title, draft.body, draft.tag, draft.level)));
+                        set_draft_saved.set(Some(draft.updated_at));
+                    }
+                    Err(e) => {
+                        // 見つからなければ新しい下書きとして書く
+                        draft_id.set(None);
+                        set_error.set(Some(e));
+                    }
+                }
+            });
+        });
+    }
 
-    let post = move |title, body, tag, is_advanced, user: Option<User>, is_sending: bool| {
+    // force = true なら、内容が変わっていなくても空でも保存する (「下書きに保存」ボタン)
+    let save_draft = move |force: bool| {
+        if is_editing || is_saving.get_value() || is_sending.get_untracked() {
+            return;
+        }
+        let content = (
+            title.get_untracked(),
+            body.get_untracked(),
+            select_tag.get_untracked(),
+            level.get_untracked(),
+        );
+        let is_empty = content.0.trim().is_empty() && content.1.trim().is_empty() && content.2.is_empty();
+        let is_saved = saved_content.with_value(|saved| saved.as_ref() == Some(&content));
+        if !force && (is_empty || is_saved) {
+            return;
+        }
+        is_saving.set_value(true);
+        task::spawn_local(async move {
+            let (title, body, tag, level) = content.clone();
+            match server::save_draft(draft_id.get_untracked(), title, body, Some(tag), level).await {
+                Ok(draft) => {
+                    draft_id.set(Some(draft.id));
+                    saved_content.set_value(Some(content));
+                    set_draft_saved.set(Some(draft.updated_at));
+                }
+                Err(e) if force => set_error.set(Some(e)),
+                Err(e) => log!("下書きの自動保存に失敗: {e:?}"),
+            }
+            is_saving.set_value(false);
+        });
+    };
+    if !is_editing {
+        // Effectの中ならブラウザでだけ動く (サーバーで描画するときにタイマーは作れない)
+        Effect::new(move |_| {
+            if let Ok(handle) = set_interval_with_handle(move || save_draft(false), AUTOSAVE_INTERVAL) {
+                on_cleanup(move || handle.clear());
+            }
+        });
+    }
+
+    // 編集するときは今の内容を入れておく
+    if let Some(id) = editing.clone() {
+        Effect::new(move |_| {
+            let id = id.clone();
+            task::spawn_local(async move {
+                match server::get_post(id).await {
+                    Ok(post) => {
+                        set_title.set(post.title);
+                        set_body.set(post.body);
+                        set_select_tag.set(post.tag);
+                        choose_level(post.level);
+                    }
+                    Err(e) => set_error.set(Some(e)),
+                }
+            });
+        });
+    }
+
+    // 新しく書くときは、選んだタグの自分の経験 (プロフィールで設定したもの) を既定にする
+    let experiences = RwSignal::new(Vec::<server::TagExperience>::new());
+    if !is_editing {
+        let user = use_context::<ReadSignal<Option<User>>>().unwrap();
+        Effect::new(move |_| {
+            let Some(name) = user.with_untracked(|u| u.as_ref().map(|u| u.name.clone())) else {
+                return;
+            };
+            task::spawn_local(async move {
+                match server::get_profile(name).await {
+                    Ok(profile) => experiences.set(profile.experiences),
+                    Err(e) => log!("経験の読み込みに失敗: {e:?}"),
+                }
+            });
+        });
+        Effect::new(move |_| {
+            let default = experiences.with(|e| select_tag.with(|tags| server::default_level(e, tags)));
+            if !level_chosen.get_untracked() {
+                set_level.set(default);
+            }
+        });
+    }
+
+    let post = move |title: String, body: String, tag: Vec<String>, level, is_sending: bool| {
         if !is_sending{
             set_is_sending.set(true);
+            set_error.set(None);
+            let editing = editing.clone();
             task::spawn_local(async move {
-            if let Some(u) = user {
-                server::do_post(u.name, u.jwt, title, body, Some(tag), is_advanced)
-                    .await
-                    .unwrap();
-            }
+            let result = match editing {
+                Some(id) => server::edit_post(id, title, body, Some(tag), level).await,
+                None => {
+                    let attachments = Some(attachments.get_untracked());
+                    server::do_post(title, body, Some(tag), Some(level), draft_id.get_untracked(), attachments).await
+                }
+            };
             set_is_sending.set(false);
+            if let Err(e) = result {
+                set_error.set(Some(e));
+                // 投稿できなかった内容は下書きに残しておく
+                save_draft(false);
+            }
         })}
     };
 
-    Resource::new(
-        move || search_string.get(),
-        move |s| async move {
-            if !s.is_empty() {
-                task::spawn_local(async move {
-                    set_search_tag.set(server::search_tag_with_prefix(s, 3).await.unwrap());
-                });
-            } else {
-                set_search_tag.set(vec![]);
+    // 候補にないタグは新しく作れるようにする (実際に作られるのは投稿したとき)
+    let new_tag = move || {
+        let tag = normalize_tag(&search_string.get());
+        let exists = search_tag.with(|tags| tags.iter().any(|t| t.tag == tag))
+            || select_tag.with(|tags| tags.contains(&tag));
+        if tag.is_empty() || exists { None } else { Some(tag) }
+    };
+    let add_new_tag = move || {
+        if let Some(tag) = new_tag() {
+            set_select_tag.write().push(tag);
+            set_search_string.set(String::new());
+            set_search_tag.set(vec![]);
+        }
+    };
+
+    let fetch_tags = move |input: String| {
+        if input.trim().is_empty() {
+            set_search_tag.set(vec![]);
+            return;
+        }
+        task::spawn_local(async move {
+            let result = server::suggest_tags(input.clone()).await;
+            // 待っている間に入力が変わっていたら捨てる
+            if search_string.get_untracked() != input {
+                return;
             }
-        },
-    );
+            match result {
+                Ok(tags) => set_search_tag.set(tags),
+                Err(e) => {
+                    log!("タグ候補の取得に失敗: {e:?}");
+                    set_search_tag.set(vec![]);
+                }
+            }
+        });
+    };
+    // キーを押すたびに問い合わせないように、入力が少し止まってから候補を読む
+    let debounce = StoredValue::new(None::<TimeoutHandle>);
+    let on_tag_input = move |input: String| {
+        set_search_string.set(input.clone());
+        if let Some(handle) = debounce.get_value() {
+            handle.clear();
+        }
+        let handle = set_timeout_with_handle(move || fetch_tags(input), Duration::from_millis(150));
+        debounce.set_value(handle.ok());
+    };
 
     view! {
         <div class="box" id="side-space-left">
             <div class="tag-function">
-                <input class="tag-search-window" type="text" placeholder="タグを検索" on:input:target=move |ev| {set_search_string.set(ev.target().value())}/>
+                <input class="tag-search-window" type="text" placeholder="タグを検索"
+                    prop:value=move || search_string.get()
+                    on:input:target=move |ev| on_tag_input(ev.target().value())
+                    on:keydown=move |ev| if ev.key() == "Enter" { add_new_tag() }/>
                 <div class="tag-predict">
                     <p>"タグ候補"</p>
                     <For
                         each=move || search_tag.get()
-                        key=|tag| tag.clone()
+                        key=|tag| tag.tag.clone()
                         let(tag)
                     >
-                        <TagSelect tag=tag set_select_tag=set_select_tag/>
+                        <TagSelect tag=tag.tag count=tag.count path=tag.path set_select_tag=set_select_tag/>
                     </For>
+                    {move || new_tag().map(|tag| view! {
+                        <div class="tag-object tag-new" on:click=move |_| add_new_tag()>
+                            <p> {format!("＋「{tag}」を新しく作る")} </p>
+                        </div>
+                    })}
                 </div>
             </div>
         </div>
         <div class="outer">
                 <div class="post-function">
-                    <input class="title-space" type="text" placeholder="タイトル" on:input:target=move |ev| {set_title.set(ev.target().value())}/> <br/>
+                    <input class="title-space" type="text" placeholder="タイトル" maxlength=server::TITLE_MAX_LENGTH prop:value=move || title.get() on:input:target=move |ev| {set_title.set(ev.target().value())}/> <br/>
                     <div class="tag-space">
                         <For
                             each=move || select_tag.get()
@@ -138,33 +347,84 @@ fn PostScreen() -> impl IntoView {
                             <TagSearch tag=tag set_select_tag=set_select_tag/>
                         </For>
                     </div>
-                        <textarea class="text-area-space" placeholder="内容を入力" on:input:target=move |ev| {set_body.set(ev.target().value())}/>
+                    <PreviewTabs text=body>
+                        <textarea class="text-area-space" placeholder="内容を入力 (Markdownが使えます)" maxlength=server::BODY_MAX_LENGTH prop:value=move || body.get() on:input:target=move |ev| {set_body.set(ev.target().value())}/>
+                    </PreviewTabs>
+                    // 添付ファイルは新しく投稿するときだけ付けられる
+                    {(!is_editing).then(|| view! {<AttachmentPicker attachments=attachments/>})}
                     <div class="post-button">
-                        <img src="/images/mailing_fill72.png" on:click=move |_| {post(title.get(), body.get(), select_tag.get(), advanced.get(), use_context::<ReadSignal<Option<User>>>().unwrap().get(), is_sending.get())}/>
+                        <img src="/images/mailing_fill72.png" on:click=move |_| {post(title.get(), body.get(), select_tag.get(), level.get(), is_sending.get())}/>
                     </div>
+                    <ErrorMessage error=error/>
+                    {(!is_editing).then(|| view! {
+                        <div class="draft-status">
+                            <span class="draft-save" on:click=move |_| save_draft(true)>"下書きに保存"</span>
+                            {move || draft_saved.get().map(|t| format!("下書きを保存しました ({})", format_time(t)))}
+                        </div>
+                    })}
                 </div>
         </div>
         <div class="form-check">
-            <input class="form-check-input" type="radio"
-                prop:checked=move || !advanced.get()
-                on:change=move |_| set_advanced.set(false)
+            {ExperienceLevel::ALL.into_iter().map(|l| view! {
+                <input class="form-check-input" type="radio"
+                    prop:checked=move || level.get() == l
+                    on:change=move |_| choose_level(l)
                 ></input>
-            <label class="form-check-label" on:click=move |_| set_advanced.set(false)>
-                "初心者"
-            </label>
-            <input class="form-check-input" type="radio"
-                prop:checked=move || advanced.get()
-                on:change=move |_| set_advanced.set(true)
-            ></input>
-            <label class="form-check-label" on:click=move |_| set_advanced.set(true)>
-                "経験者"
-            </label>
+                <label class="form-check-label" on:click=move |_| choose_level(l)>
+                    {l.label()}
+                </label>
+            }).collect_view()}
+        </div>
+    }
+}
+
+// 「書く」と「プレビュー」の切り替え (childrenは入力欄)
+// プレビューは表示と同じものになるように、サーバーでHTMLにしてもらう
+#[component]
+fn PreviewTabs(text: ReadSignal<String>, children: Children) -> impl IntoView {
+    let (previewing, set_previewing) = signal(false);
+    let (html, set_html) = signal(String::new());
+    let (error, set_error) = signal(None::<AppError>);
+    let preview = move || {
+        set_previewing.set(true);
+        set_html.set(String::new());
+        set_error.set(None);
+        task::spawn_local(async move {
+            match server::render_markdown(text.get_untracked()).await {
+                Ok(rendered) => set_html.set(rendered),
+                Err(e) => set_error.set(Some(e)),
+            }
+        });
+    };
+
+    view! {
+        <div class="preview-tabs">
+            <span class="preview-tab" class:preview-tab-active=move || !previewing.get()
+                on:click=move |_| set_previewing.set(false)>"書く"</span>
+            <span class="preview-tab" class:preview-tab-active=previewing
+                on:click=move |_| preview()>"プレビュー"</span>
         </div>
+        // 入力欄は消さずに隠すだけにする
+        <div style:display=move || if previewing.get() { "none" } else { "" }>
+            {children()}
+        </div>
+        <Show when=move || previewing.get()>
+            <div class="preview markdown" inner_html=html/>
+            <ErrorMessage error=error/>
+        </Show>
     }
 }
 
 #[component]
-fn TagSelect(tag: String, set_select_tag: WriteSignal<Vec<String>>) -> impl IntoView {
+fn TagSelect(
+    tag: String,
+    // 指定するとタグが付いた投稿の数も出す
+    #[prop(optional)] count: Option<i64>,
+    // 親のタグがあれば "親 › 子" のように出す
+    #[prop(optional)] path: Vec<String>,
+    set_select_tag: WriteSignal<Vec<String>>,
+) -> impl IntoView {
+    let label = if path.len() > 1 { path.join(" › ") } else { tag.clone() };
     view! {
         <div class="tag-object" on:click=move |_| {
             let mut l = set_select_tag.write();
@@ -173,7 +433,7 @@ fn TagSelect(tag: String, set_select_tag: WriteSignal<Vec<String>>) -> impl Into
             }
             log!("{:?}", *l);
         }>
-            <p> {tag.clone()} </p>
+            <p> {label} {count.map(|c| view! {<span class="tag-count"> {c} </span>})} </p>
         </div>
     }
 }
@@ -194,43 +454,142 @@ fn TagSearch(tag: String, set_select_tag: WriteSignal<Vec<String>>) -> impl Into
     }
 }
 
+// タイムラインの読み込み状態 (Headerの検索とMainScreenのスクロールで共有する)
+// キーワードがあれば全文検索、なければ条件で絞り込んだ一覧
+#[derive(Clone, Copy)]
+struct Feed {
+    query: RwSignal<PostQuery>,
+    // 検索欄の文字列 (絞り込みパネルからも書き換える)
+    input: RwSignal<String>,
+    // 次のページのカーソル (Noneならもうない)
+    next: RwSignal<Option<String>>,
+    // 全文検索の結果 (投稿のIDから引く)
+    hits: RwSignal<HashMap<String, server::SearchHit>>,
+    loading: RwSignal<bool>,
+    set_posts: WriteSignal<Vec<server::Post>>,
+    set_error: WriteSignal<Option<AppError>>,
+}
+
+impl Feed {
+    // 最初のページから読み直す
+    fn search(self, query: PostQuery) {
+        self.query.set(query);
+        self.next.set(None);
+        self.hits.set(HashMap::new());
+        self.set_posts.set(vec![]);
+        self.fetch(None);
+    }
+
+    // 次のページを読んで後ろに足す
+    fn load_more(self) {
+        if self.loading.get_untracked() {
+            return;
+        }
+        if let Some(cursor) = self.next.get_untracked() {
+            self.fetch(Some(cursor));
+        }
+    }
+
+    fn fetch(self, cursor: Option<String>) {
+        self.loading.set(true);
+        let query = self.query.get_untracked();
+        task::spawn_local(async move {
+            let first = cursor.is_none();
+            let result = if query.text.is_empty() {
+                server::search(query.clone(), cursor).await
+            } else {
+                server::search_text(query.clone()).await.map(|hits| {
+                    let posts = hits.iter().map(|h| h.post.clone()).collect();
+                    self.hits
+                        .set(hits.into_iter().map(|h| (h.post.id.clone(), h)).collect());
+                    server::PostPage {
+                        posts,
+                        next_cursor: None,
+                    }
+                })
+            };
+            // 読んでいる間に別の検索が始まっていたら捨てる
+            if self.query.get_untracked() != query {
+                return;
+            }
+            match result {
+                Ok(page) => {
+                    self.set_error.set(None);
+                    if first {
+                        self.set_posts.set(page.posts);
+                    } else {
+                        self.set_posts.update(|posts| posts.extend(page.posts));
+                    }
+                    self.next.set(page.next_cursor);
+                }
+                Err(e) => {
+                    self.set_error.set(Some(e));
+                    if first {
+                        self.set_posts.set(vec![]);
+                    }
+                }
+            }
+            self.loading.set(false);
+        });
+    }
+}
+
 #[component]
 fn Header() -> impl IntoView {
     let (posts, set_posts) = signal(vec![]);
+    let (search_error, set_search_error) = signal(None::<AppError>);
 
     provide_context(posts);
+    provide_context(set_posts);
+    provide_context(search_error);
 
-    let search = move |query: String| {
-        task::spawn_local(async move {
-            let q = {
-                if query.is_empty() {
-                    None
-                } else {
-                    Some(query)
-                }
-            };
-            let result = server::search(q).await.unwrap();
-            set_posts.set(result);
-        });
+    let feed = Feed {
+        query: RwSignal::new(PostQuery::default()),
+        input: RwSignal::new(String::new()),
+        next: RwSignal::new(None),
+        hits: RwSignal::new(HashMap::new()),
+        loading: RwSignal::new(false),
+        set_posts,
+        set_error: set_search_error,
     };
+    provide_context(feed);
 
-    search(String::new());
+    let search = move || feed.search(PostQuery::parse(&feed.input.get_untracked()));
 
-    let (query, set_query) = signal(String::new());
+    // 最初の一覧はクライアント側で読み込む (spawn_localはSSR中には使えない)
+    Effect::new(move |_| search());
+
+    let user = use_context::<ReadSignal<Option<User>>>().unwrap();
+    let user_write = use_context::<WriteSignal<Option<User>>>().unwrap();
+    let log_out = move |everywhere: bool| {
+        task::spawn_local(async move {
+            if let Err(e) = server::log_out(everywhere).await {
+                log!("ログアウトに失敗: {e:?}");
+            }
+            user_write.set(None);
+        });
+    };
 
     view! {
         <header class="header">
             <label for="sidemenu" style="margin-left: 10px">
-                <img src="./images/menu_line72.png" alt="メニュー" height="40px"/>
+                <img src="/images/menu_line72.png" alt="メニュー" height="40px"/>
             </label>
             <div class="divider"></div>
-            <img src="./images/tabicon.JPG" alt="アイコン" class="logo" height="40px"/>
+            <img src="/images/tabicon.JPG" alt="アイコン" class="logo" height="40px"/>
             <div class="search-wrap">
-                <img src="./images/search_fill48.png" class="search-icon" on:click=move |_| {search(query.get())}/>
-                <input type="text" class="searchbar" placeholder="タグ検索" on:input:target=move |ev| set_query.set(ev.target().value())/>
+                <img src="/images/search_fill48.png" class="search-icon" on:click=move |_| {search()}/>
+                <input type="text" class="searchbar" placeholder="キーワード・tag:タグ・author:名前で検索"
+                    prop:value=move || feed.input.get()
+                    on:input:target=move |ev| feed.input.set(ev.target().value())
+                    on:keydown=move |ev| if ev.key() == "Enter" { search() }/>
             </div>
-            <img src="./images/beru.png" alt="アイコン" class="beru" height="40px"/>
-            <img src="./images/kariicon.jpg" alt="アイコン" class="kariicon" height="40px"/>
+            <img src="/images/beru.png" alt="アイコン" class="beru" height="40px"/>
+            {move || user.get().map(|u| view! {
+                <A href=format!("/users/{}", u.name)>
+                    <img src=avatar_src(u.avatar.as_deref()) alt="アイコン" class="kariicon" height="40px"/>
+                </A>
+            })}
         </header>
         <input type="checkbox" id="sidemenu" hidden/>
         <label for="sidemenu" class="overlay"></label>
@@ -238,7 +597,15 @@ fn Header() -> impl IntoView {
         <nav class="sidebar">
             <A href="/">"ホーム"</A>
             <A href="/post">"投稿"</A>
-            <img src="./images/bear.png" alt="熊" width="150px"/>
+            <A href="/drafts">"下書き"</A>
+            <A href="/tags">"タグ"</A>
+            <A href="/settings">"設定"</A>
+            <Show when=move || user.get().is_some_and(|u| u.is_moderator)>
+                <A href="/tags/merge">"タグの統合"</A>
+            </Show>
+            <a class="sidebar-logout" on:click=move |_| log_out(false)>"ログアウト"</a>
+            <a class="sidebar-logout-all" on:click=move |_| log_out(true)>"すべての端末からログアウト"</a>
+            <img src="/images/bear.png" alt="熊" width="150px"/>
         </nav>
     }
 }
@@ -246,21 +613,36 @@ fn Header() -> impl IntoView {
 #[component]
 fn MainScreen() -> impl IntoView {
     let posts = use_context::<ReadSignal<Vec<server::Post>>>().unwrap();
+    let search_error = use_context::<ReadSignal<Option<AppError>>>().unwrap();
+    let feed = use_context::<Feed>().unwrap();
 
     let (right_post, set_right_post) = signal(None::<server::Post>);
+    // 検索し直したら右側の表示を消す (次のページを足したときはそのまま)
     Effect::new(move |_| {
-        let _ = posts.get();
+        feed.query.track();
         set_right_post.set(None);
     });
+
+    // 下の端に近づいたら次のページを読む
+    let on_scroll = move |el: leptos::web_sys::HtmlDivElement| {
+        if el.scroll_top() + el.client_height() >= el.scroll_height() - 200 {
+            feed.load_more();
+        }
+    };
     view! {
         <div class="main-layout">
             <Show
-            when=move || {posts.get().is_empty()}>
+            when=move || {posts.get().is_empty() && !feed.loading.get()}>
             <div class="cantlook">
-                    <span>"記事が見つかりませんでした"</span>
+                    <span>{move || match search_error.get() {
+                        Some(e) => e.to_string(),
+                        None => "記事が見つかりませんでした".to_string(),
+                    }}</span>
             </div>
         </Show>
-            <div class="timeline">
+            <div class="timeline" on:scroll:target=move |ev| on_scroll(ev.target())>
+                <FeedTabs/>
+                <FilterPanel/>
                 <For
                     each=move || posts.get()
                     key=|post| post.id.clone()
@@ -268,63 +650,349 @@ fn MainScreen() -> impl IntoView {
                 >
                     <MainScreenPost on:click=move |_| {
                         set_right_post.set(Some(post.clone()));
-                        let p = use_context::<WriteSignal<Option<server::Post>>>().unwrap();
-                        p.set(Some(post.clone()));
-                    } post=post.clone() is_preview=true has_responce=false/>
+                    } post=post.clone() is_preview=true has_responce=false
+                        hit=feed.hits.with_untracked(|h| h.get(&post.id).cloned())/>
                 </For>
+                <Show when=move || feed.loading.get()>
+                    <Loading/>
+                </Show>
+                // 画面が埋まらずスクロールできないとき用
+                <Show when=move || !feed.loading.get() && feed.next.get().is_some()>
+                    <div class="timeline-more" on:click=move |_| feed.load_more()>"もっと見る"</div>
+                </Show>
             </div>
             <Show when=move || {right_post.get().is_some()}>
                 <div class="post-right">
-                    <MainScreenPost post=(|| {right_post.get().unwrap()})() is_preview=false has_responce=true/>
+                    <MainScreenPost post=right_post.get().unwrap() is_preview=false has_responce=true/>
                 </div>
             </Show>
         </div>
     }
 }
 
+// すべての投稿とフォロー中のタグの投稿 (先に出す) の切り替え
 #[component]
-fn MainScreenPost(post: server::Post, is_preview: bool, has_responce: bool) -> impl IntoView {
+fn FeedTabs() -> impl IntoView {
+    let feed = use_context::<Feed>().unwrap();
+    let following = move || feed.query.with(|q| q.following);
+    let set_following = move |following: bool| {
+        let mut query = feed.query.get_untracked();
+        if query.following == following {
+            return;
+        }
+        query.following = following;
+        feed.input.set(query.to_string());
+        feed.search(query);
+    };
+
+    view! {
+        <div class="feed-tabs">
+            <span class="feed-tab" class:active=move || !following() on:click=move |_| set_following(false)>
+                "すべての投稿"
+            </span>
+            <span class="feed-tab" class:active=following on:click=move |_| set_following(true)>
+                "フォロー中のタグ"
+            </span>
+        </div>
+    }
+}
+
+// 検索条件を項目ごとに入れるパネル
+// 検索欄の文字列と同じ条件になり、絞り込むと検索欄にも書き戻す
+#[component]
+fn FilterPanel() -> impl IntoView {
+    let feed = use_context::<Feed>().unwrap();
+
+    let all_tags = RwSignal::new(String::new());
+    let any_tags = RwSignal::new(String::new());
+    let not_tags = RwSignal::new(String::new());
+    let level = RwSignal::new(String::new());
+    let author = RwSignal::new(String::new());
+    let since = RwSignal::new(String::new());
+    let until = RwSignal::new(String::new());
+    let replies = RwSignal::new(String::new());
+    let sort = RwSignal::new(String::new());
+
+    // 検索欄から検索したときもパネルの中身を合わせる
+    Effect::new(move |_| {
+        let query = feed.query.get();
+        all_tags.set(query.all_tags.join(" "));
+        any_tags.set(query.any_tags.iter().map(|g| g.join(" ")).collect::<Vec<_>>().join(", "));
+        not_tags.set(query.not_tags.join(" "));
+        level.set(query.level.map_or("", ExperienceLevel::as_str).to_string());
+        author.set(query.author.unwrap_or_default());
+        since.set(query.since.unwrap_or_default());
+        until.set(query.until.unwrap_or_default());
+        replies.set(match query.replies {
+            ReplyFilter::Any => "",
+            ReplyFilter::Answered => "answered",
+            ReplyFilter::Unanswered => "unanswered",
+        }.to_string());
+        sort.set(match query.sort {
+            Some(PostSort::Newest) => "new",
+            Some(PostSort::MostReplied) => "replies",
+            Some(PostSort::MostActive) => "active",
+            None => "",
+        }.to_string());
+    });
+
+    let tags = |s: RwSignal<String>| {
+        s.get_untracked()
+            .split_whitespace()
+            .map(|t| t.trim_start_matches('#').to_string())
+            .filter(|t| !t.is_empty())
+            .collect::<Vec<_>>()
+    };
+    let text = |s: RwSignal<String>| Some(s.get_untracked().trim().to_string()).filter(|s| !s.is_empty());
+    let apply = move || {
+        let query = PostQuery {
+            all_tags: tags(all_tags),
+            // カンマで区切ると別のグループ (どのグループからもどれかが付いている)
+            any_tags: any_tags
+                .get_untracked()
+                .split(',')
+                .map(|group| {
+                    group
+                        .split_whitespace()
+                        .map(|t| t.trim_start_matches('#').to_string())
+                        .filter(|t| !t.is_empty())
+                        .collect::<Vec<_>>()
+                })
+                .filter(|group| !group.is_empty())
+                .collect(),
+            not_tags: tags(not_tags),
+            level: ExperienceLevel::parse(&level.get_untracked()),
+            author: text(author),
+            since: text(since),
+            until: text(until),
+            replies: match replies.get_untracked().as_str() {
+                "answered" => ReplyFilter::Answered,
+                "unanswered" => ReplyFilter::Unanswered,
+                _ => ReplyFilter::Any,
+            },
+            sort: match sort.get_untracked().as_str() {
+                "new" => Some(PostSort::Newest),
+                "replies" => Some(PostSort::MostReplied),
+                "active" => Some(PostSort::MostActive),
+                _ => None,
+            },
+            following: feed.query.get_untracked().following,
+            text: feed.query.get_untracked().text,
+        };
+        feed.input.set(query.to_string());
+        feed.search(query);
+    };
+    // すべての投稿 / フォロー中のタグ の切り替えはそのまま
+    let clear = move || {
+        let query = PostQuery {
+            following: feed.query.get_untracked().following,
+            ..PostQuery::default()
+        };
+        feed.input.set(query.to_string());
+        feed.search(query);
+    };
+
+    view! {
+        <details class="filter-panel">
+            <summary>"絞り込み"</summary>
+            <div class="filter-fields">
+                <label>"すべて含むタグ"
+                    <input type="text" placeholder="rust web" prop:value=move || all_tags.get()
+                        on:input:target=move |ev| all_tags.set(ev.target().value())/>
+                </label>
+                <label>"どれかを含むタグ"
+                    <input type="text" placeholder="go zig, vim emacs" prop:value=move || any_tags.get()
+                        on:input:target=move |ev| any_tags.set(ev.target().value())/>
+                </label>
+                <label>"除くタグ"
+                    <input type="text" prop:value=move || not_tags.get()
+                        on:input:target=move |ev| not_tags.set(ev.target().value())/>
+                </label>
+                <label>"投稿者"
+                    <input type="text" prop:value=move || author.get()
+                        on:input:target=move |ev| author.set(ev.target().value())/>
+                </label>
+                <label>"レベル"
+                    <select prop:value=move || level.get() on:change:target=move |ev| level.set(ev.target().value())>
+                        <option value="">"すべて"</option>
+                        {ExperienceLevel::ALL.into_iter().map(|l| view! {
+                            <option value=l.as_str()>{l.label()}</option>
+                        }).collect_view()}
+                    </select>
+                </label>
+                <label>"返信"
+                    <select prop:value=move || replies.get() on:change:target=move |ev| replies.set(ev.target().value())>
+                        <option value="">"すべて"</option>
+                        <option value="answered">"返信あり"</option>
+                        <option value="unanswered">"返信なし"</option>
+                    </select>
+                </label>
+                <label>"投稿日"
+                    <span class="filter-dates">
+                        <input type="date" prop:value=move || since.get()
+                            on:input:target=move |ev| since.set(ev.target().value())/>
+                        "〜"
+                        <input type="date" prop:value=move || until.get()
+                            on:input:target=move |ev| until.set(ev.target().value())/>
+                    </span>
+                </label>
+                <label>"並び順"
+                    <select prop:value=move || sort.get() on:change:target=move |ev| sort.set(ev.target().value())>
+                        <option value="">"指定なし"</option>
+                        <option value="new">"新しい順"</option>
+                        <option value="replies">"返信が多い順"</option>
+                        <option value="active">"最近動きがあった順"</option>
+                    </select>
+                </label>
+            </div>
+            <div class="filter-buttons">
+                <button on:click=move |_| apply()>"絞り込む"</button>
+                <button on:click=move |_| clear()>"クリア"</button>
+            </div>
+        </details>
+    }
+}
+
+#[component]
+fn MainScreenPost(
+    post: server::Post,
+    is_preview: bool,
+    has_responce: bool,
+    // 指定すると各返信に「返信」ボタンを出す
+    #[prop(optional)] on_reply: Option<Callback<server::Comment>>,
+    // 返信を編集・削除・非表示にしたとき
+    #[prop(optional)] on_comment_change: Option<Callback<server::Comment>>,
+    // 全文検索で見つけた投稿なら、当たったところを強調して出す
+    #[prop(optional_no_strip)] hit: Option<server::SearchHit>,
+) -> impl IntoView {
+    let feed = use_context::<Feed>().unwrap();
+    let user = use_context::<ReadSignal<Option<User>>>().unwrap();
+    let navigate = use_navigate();
+    let set_posts = use_context::<WriteSignal<Vec<server::Post>>>().unwrap();
+    let viewer = user.with_untracked(|u| u.as_ref().map(|u| u.id.clone()));
+    let is_mine = viewer.as_deref() == Some(post.author_id.as_str());
+    let (error, set_error) = signal(None::<AppError>);
+    let delete = {
+        let id = post.id.clone();
+        move || {
+            if !window().confirm_with_message("この投稿を削除しますか？").unwrap_or(false) {
+                return;
+            }
+            let (id, navigate) = (id.clone(), navigate.clone());
+            task::spawn_local(async move {
+                match server::delete_post(id).await {
+                    Ok(()) => {
+                        // タイムラインからも消す
+                        feed.search(feed.query.get_untracked());
+                        navigate("/", Default::default());
+                    }
+                    Err(e) => set_error.set(Some(e)),
+                }
+            });
+        }
+    };
+
+    // 本文はサーバーでMarkdownからHTMLにしたものを出す (全文検索の結果は当たったところの抜粋)
+    let (title, body) = match hit {
+        Some(hit) if is_preview => (
+            segments_view(hit.title).into_any(),
+            view! {<div class="post-text-preview"> {segments_view(hit.snippet)} </div>}.into_any(),
+        ),
+        _ => (
+            post.title.clone().into_any(),
+            view! {
+                <div class="markdown" class:post-text-preview=is_preview class:post-text=!is_preview
+                    inner_html=post.body_html.clone()/>
+            }
+            .into_any(),
+        ),
+    };
+
     let tags = post
         .tag
         .iter()
         .map(|t| view! {<span class="post-tag"> {t.to_string()} </span>})
         .collect_view();
 
+    // 開いている投稿には返信もスレッドにして出す
+    let comments = if is_preview || post.comment.is_empty() {
+        ().into_any()
+    } else {
+        let (comments, set_comments) = signal(post.comment.clone());
+        let thread = CommentThread {
+            post_id: StoredValue::new(post.id.clone()),
+            post_author: StoredValue::new(post.author_id.clone()),
+            viewer: StoredValue::new(viewer),
+            on_reply,
+            on_change: on_comment_change,
+            set_comments,
+            set_posts,
+        };
+        view! {
+            <div class="post-comments">
+                {move || comments.with(|comments| comment_tree(comments, None, thread))}
+            </div>
+        }
+        .into_any()
+    };
+
     view! {
         <div class="post">
-            <div class="post-icon"><img src="./images/kariicon.jpg" alt="アイコン" class="kariicon" height="40px"/></div>
+            <div class="post-icon">
+                <A href=format!("/users/{}", post.name)>
+                    <img src=avatar_src(post.avatar.as_deref()) alt="アイコン" class="kariicon" height="40px"/>
+                </A>
+            </div>
 
             <div class="post-content">
                 <div class="post-header">
-                    <span class="post-title"> {post.title.clone()}</span>
-                    <span class="post-username"> {post.name.clone()} </span>
-                    <span class="post-attribute" class:post-attribute-experience=post.is_advanced> {
-                        if post.is_advanced{
-                            "経験者"
+                    {
+                        // 開いている投稿のタイトルは投稿のページへのリンクにする
+                        if is_preview {
+                            view! {<span class="post-title"> {title}</span>}.into_any()
                         } else {
-                            "初心者"
+                            view! {
+                                <A href=format!("/posts/{}", post.id)>
+                                    <span class="post-title"> {post.title.clone()}</span>
+                                </A>
+                            }.into_any()
                         }
-                    }</span>/*経験者の時post-attribute-experience*/
-                </div>
-                <div class:post-text-preview = is_preview class:post-text = !is_preview>
-                    {post.body.clone()}
+                    }
+                    <A href=format!("/users/{}", post.name) attr:class="post-username"> {post.display_name.clone()} </A>
+                    <span class="post-time"> {format_time(post.created_at)} </span>
+                    {post.updated_at.map(|updated_at| view! {
+                        <A href=format!("/posts/{}/history", post.id) attr:class="post-edited" attr:title=format_time(updated_at)>
+                            "(編集済み)"
+                        </A>
+                    })}
+                    {level_badge(post.level)}
                 </div>
+                {body}
+                {(!is_preview).then(|| attachments_view(&post.attachments))}
                 <div class="post-actions">
                     {
                         tags.collect_view()
                     }
                 </div>
+                {comments}
                 {
                     if has_responce{
                         view!{
                             <div class="post-footer">
+                                {is_mine.then(|| view! {
+                                    <div class="check-btn">
+                                        <A href=format!("/posts/{}/edit", post.id)> "編集" </A>
+                                    </div>
+                                    <div class="check-btn" on:click=move |_| delete()> "削除" </div>
+                                })}
                                 <div class="check-btn">
-                                    <A href="/responce"> "返信" </A>
+                                    <A href=format!("/posts/{}/reply", post.id)> "返信" </A>
                                 </div>
                             </div>
+                            <ErrorMessage error=error/>
                         }.into_any()
                     } else {
-                        view!{}.into_any()
+                        ().into_any()
                     }
                 }
             </div>
@@ -332,16 +1000,1207 @@ fn MainScreenPost(post: server::Post, is_preview: bool, has_responce: bool) -> i
     }
 }
 
+// 経験の度合いのバッジ
+fn level_badge(level: ExperienceLevel) -> impl IntoView {
+    view! {
+        <span class="post-attribute"
+            class:post-attribute-intermediate=level == ExperienceLevel::Intermediate
+            class:post-attribute-experience=level == ExperienceLevel::Advanced
+        > {level.label()} </span>
+    }
+}
+
+// 全文検索で当たったところを<mark>で囲む
+fn segments_view(segments: Vec<server::Segment>) -> impl IntoView {
+    segments
+        .into_iter()
+        .map(|s| {
+            if s.highlight {
+                view! {<mark class="search-highlight">{s.text}</mark>}.into_any()
+            } else {
+                s.text.into_any()
+            }
+        })
+        .collect_view()
+}
+
+// 返信のスレッドを出すのに使うもの
+#[derive(Clone, Copy)]
+struct CommentThread {
+    post_id: StoredValue<String>,
+    post_author: StoredValue<String>,
+    // ログインしているユーザーのID
+    viewer: StoredValue<Option<String>>,
+    on_reply: Option<Callback<server::Comment>>,
+    on_change: Option<Callback<server::Comment>>,
+    set_comments: WriteSignal<Vec<server::Comment>>,
+    set_posts: WriteSignal<Vec<server::Post>>,
+}
+
+impl CommentThread {
+    // 書き換えた返信を、開いている投稿とタイムラインの両方に反映する
+    fn replace(self, comment: server::Comment) {
+        let replace = |comments: &mut Vec<server::Comment>| {
+            if let Some(c) = comments.iter_mut().find(|c| c.id == comment.id) {
+                *c = comment.clone();
+            }
+        };
+        self.set_comments.update(replace);
+        let post_id = self.post_id.get_value();
+        self.set_posts.update(|posts| {
+            if let Some(p) = posts.iter_mut().find(|p| p.id == post_id) {
+                replace(&mut p.comment);
+            }
+        });
+        if let Some(on_change) = self.on_change {
+            on_change.run(comment);
+        }
+    }
+}
+
+// parentにぶら下がる返信を再帰的に並べる
+// 返信先が見つからない返信は投稿への直接の返信として扱う
+fn comment_tree(
+    comments: &[server::Comment],
+    parent: Option<&str>,
+    thread: CommentThread,
+) -> AnyView {
+    let is_child = |c: &server::Comment| match (&c.parent_id, parent) {
+        (Some(p), Some(parent)) => p == parent,
+        (Some(p), None) => !comments.iter().any(|c| c.id == *p),
+        (None, parent) => parent.is_none(),
+    };
+    comments
+        .iter()
+        .filter(|c| is_child(c))
+        .map(|c| {
+            let children = comment_tree(comments, Some(&c.id), thread);
+            view! {
+                <CommentItem comment=c.clone() thread=thread>
+                    {children}
+                </CommentItem>
+            }
+        })
+        .collect_view()
+        .into_any()
+}
+
+// 返信1つ (childrenはその返信への返信)
+// 削除・非表示にした返信も、スレッドの形が崩れないようにその旨だけ出して残す
+#[component]
+fn CommentItem(comment: server::Comment, thread: CommentThread, children: Children) -> impl IntoView {
+    let viewer = thread.viewer.get_value();
+    let is_mine = viewer.as_deref() == Some(comment.author_id.as_str());
+    let is_post_author = viewer.is_some() && viewer == Some(thread.post_author.get_value());
+    let is_visible = !comment.deleted && !comment.hidden;
+    let (editing, set_editing) = signal(false);
+    let (body, set_body) = signal(comment.body.clone());
+    let (is_sending, set_is_sending) = signal(false);
+    let (error, set_error) = signal(None::<AppError>);
+
+    // サーバーで書き換えて、返ってきた返信に差し替える
+    let id = StoredValue::new(comment.id.clone());
+    let send = move |action: &'static str| {
+        if is_sending.get_untracked() {
+            return;
+        }
+        let confirm = match action {
+            "delete" => Some("この返信を削除しますか？"),
+            "hide" => Some("この返信を非表示にしますか？"),
+            _ => None,
+        };
+        if confirm.is_some_and(|m| !window().confirm_with_message(m).unwrap_or(false)) {
+            return;
+        }
+        set_is_sending.set(true);
+        set_error.set(None);
+        let (post_id, comment_id) = (thread.post_id.get_value(), id.get_value());
+        task::spawn_local(async move {
+            let result = match action {
+                "edit" => server::edit_comment(post_id, comment_id, body.get_untracked()).await,
+                "delete" => server::delete_comment(post_id, comment_id).await,
+                "hide" => server::hide_comment(post_id, comment_id, true).await,
+                _ => server::hide_comment(post_id, comment_id, false).await,
+            };
+            set_is_sending.set(false);
+            match result {
+                Ok(comment) => thread.replace(comment),
+                Err(e) => set_error.set(Some(e)),
+            }
+        });
+    };
+
+    let content = if comment.deleted {
+        view! {<div class="post-comment-body post-comment-removed">"この返信は削除されました"</div>}.into_any()
+    } else if comment.hidden {
+        view! {<div class="post-comment-body post-comment-removed">"この返信は投稿者が非表示にしました"</div>}.into_any()
+    } else {
+        let html = comment.body_html.clone();
+        view! {
+            <Show
+                when=move || editing.get()
+                fallback=move || view! {<div class="post-comment-body markdown" inner_html=html.clone()/>}
+            >
+                <textarea class="post-comment-edit" prop:value=body on:input:target=move |ev| set_body.set(ev.target().value())/>
+                <div class="post-comment-actions">
+                    <span class="post-comment-action" on:click=move |_| send("edit")>"保存"</span>
+                    <span class="post-comment-action" on:click=move |_| set_editing.set(false)>"キャンセル"</span>
+                </div>
+            </Show>
+            {attachments_view(&comment.attachments)}
+        }
+        .into_any()
+    };
+
+    let reply_button = thread.on_reply.filter(|_| is_visible).map(|on_reply| {
+        let c = comment.clone();
+        view! {
+            <span class="post-comment-reply" on:click=move |_| on_reply.run(c.clone())>"返信"</span>
+        }
+    });
+    let own_buttons = (is_mine && !comment.deleted).then(|| view! {
+        <Show when=move || !editing.get()>
+            {(!comment.hidden).then(|| view! {
+                <span class="post-comment-action" on:click=move |_| set_editing.set(true)>"編集"</span>
+            })}
+            <span class="post-comment-action" on:click=move |_| send("delete")>"削除"</span>
+        </Show>
+    });
+    let hide_button = (is_post_author && !comment.deleted).then(|| {
+        let (action, label) = if comment.hidden {
+            ("show", "再表示")
+        } else {
+            ("hide", "非表示")
+        };
+        view! {<span class="post-comment-action" on:click=move |_| send(action)>{label}</span>}
+    });
+
+    view! {
+        <div class="post-comment">
+            <A href=format!("/users/{}", comment.name) attr:class="post-comment-name"> {comment.name.clone()} </A>
+            <span class="post-comment-time"> {format_time(comment.created_at)} </span>
+            {comment.updated_at.filter(|_| !comment.deleted).map(|updated_at| view! {
+                <span class="post-comment-edited" title=format_time(updated_at)> "(編集済み)" </span>
+            })}
+            {content}
+            <div class="post-comment-actions">
+                {reply_button}
+                {own_buttons}
+                {hide_button}
+            </div>
+            <ErrorMessage error=error/>
+            <div class="post-comment-children">
+                {children()}
+            </div>
+        </div>
+    }
+}
+
+// 投稿のページ (/posts/:id)
+// サーバー側で本文と返信まで描画するので、そのままリンクやブックマークに使える
+#[component]
+fn PostDetailScreen() -> impl IntoView {
+    let params = use_params_map();
+    let post = Resource::new_blocking(
+        move || params.read().get("id").unwrap_or_default(),
+        server::get_post,
+    );
+
+    view! {
+        <Suspense fallback=Loading>
+        {move || Suspend::new(async move {
+            match post.await {
+                Ok(post) => view! {
+                    <Title text=format!("{} - Biestar", post.title)/>
+                    <div class="post-detail">
+                        <MainScreenPost post=post is_preview=false has_responce=true/>
+                    </div>
+                }.into_any(),
+                Err(e) => view! {
+                    <div class="cantlook">
+                        <span>{e.to_string()}</span>
+                    </div>
+                }.into_any(),
+            }
+        })}
+        </Suspense>
+    }
+}
+
+// 投稿の編集履歴 (/posts/:id/history)
+// 新しい版から順に、1つ前の版との差分を出す
+#[component]
+fn PostHistoryScreen() -> impl IntoView {
+    let params = use_params_map();
+    let history = Resource::new(
+        move || params.read().get("id").unwrap_or_default(),
+        server::get_post_history,
+    );
+
+    view! {
+        <Title text="編集履歴 - Biestar"/>
+        <div class="post-history">
+            <h2>"編集履歴"</h2>
+            <Suspense fallback=Loading>
+            {move || Suspend::new(async move {
+                match history.await {
+                    Ok(versions) => versions_view(&versions).into_any(),
+                    Err(e) => view! {
+                        <div class="cantlook">
+                            <span>{e.to_string()}</span>
+                        </div>
+                    }.into_any(),
+                }
+            })}
+            </Suspense>
+        </div>
+    }
+}
+
+fn versions_view(versions: &[server::PostVersion]) -> impl IntoView {
+    (0..versions.len())
+        .rev()
+        .map(|i| {
+            let version = &versions[i];
+            let changes = match i.checked_sub(1).map(|j| &versions[j]) {
+                None => view! {<p class="history-first">"最初の投稿"</p>}.into_any(),
+                Some(old) => {
+                    let title = (old.title != version.title).then(|| view! {
+                        <p class="history-field">"タイトル: "<del>{old.title.clone()}</del>" → "<ins>{version.title.clone()}</ins></p>
+                    });
+                    let tags = (old.tag != version.tag).then(|| view! {
+                        <p class="history-field">"タグ: "<del>{old.tag.join(" ")}</del>" → "<ins>{version.tag.join(" ")}</ins></p>
+                    });
+                    let level = (old.level != version.level).then(|| view! {
+                        <p class="history-field">"レベル: "{old.level.label()}" → "{version.level.label()}</p>
+                    });
+                    let body = diff_lines(&old.body, &version.body)
+                        .into_iter()
+                        .map(|line| {
+                            let class = match line.kind {
+                                DiffKind::Same => "diff-line",
+                                DiffKind::Added => "diff-line diff-added",
+                                DiffKind::Removed => "diff-line diff-removed",
+                            };
+                            view! {<div class=class>{line.text}</div>}
+                        })
+                        .collect_view();
+                    view! {
+                        {title}
+                        {tags}
+                        {level}
+                        <div class="history-diff">{body}</div>
+                    }.into_any()
+                }
+            };
+            view! {
+                <div class="history-version">
+                    <div class="history-header">
+                        <span class="post-title">{version.title.clone()}</span>
+                        <span class="post-time">{format_time(version.created_at)}</span>
+                    </div>
+                    {changes}
+                </div>
+            }
+        })
+        .collect_view()
+}
+
+#[component]
+fn Loading() -> impl IntoView {
+    view! {<p class="loading">"読み込み中です"</p>}
+}
+
+// 添付ファイルを選ぶところ (ドラッグ&ドロップかクリックで選ぶ)
+// 選んだファイルはすぐにアップロードして、投稿するときはアップロードしたファイルのIDを送る
+#[component]
+fn AttachmentPicker(attachments: RwSignal<Vec<server::Attachment>>) -> impl IntoView {
+    let (uploading, set_uploading) = signal(0usize);
+    let (dragging, set_dragging) = signal(false);
+    let (error, set_error) = signal(None::<AppError>);
+    let input = NodeRef::<leptos::html::Input>::new();
+
+    let add_files = move |files: Option<leptos::web_sys::FileList>| {
+        let Some(files) = files else {
+            return;
+        };
+        set_error.set(None);
+        for file in (0..files.length()).filter_map(|i| files.get(i)) {
+            if attachments.with_untracked(Vec::len) + uploading.get_untracked() >= server::ATTACHMENT_MAX {
+                set_error.set(Some(AppError::validation(format!(
+                    "添付できるファイルは{}個までです",
+                    server::ATTACHMENT_MAX
+                ))));
+                break;
+            }
+            set_uploading.update(|n| *n += 1);
+            task::spawn_local(async move {
+                match upload_attachment(file).await {
+                    Ok(a) => attachments.update(|list| {
+                        if !list.iter().any(|x| x.id == a.id) {
+                            list.push(a);
+                        }
+                    }),
+                    Err(e) => set_error.set(Some(e)),
+                }
+                set_uploading.update(|n| *n -= 1);
+            });
+        }
+    };
+
+    view! {
+        <div class="attachment-picker" class:attachment-picker-dragging=dragging
+            on:dragover=move |ev| {
+                ev.prevent_default();
+                set_dragging.set(true);
+            }
+            on:dragleave=move |_| set_dragging.set(false)
+            on:drop=move |ev| {
+                ev.prevent_default();
+                set_dragging.set(false);
+                add_files(ev.data_transfer().and_then(|d| d.files()));
+            }
+            on:click=move |_| if let Some(input) = input.get() { input.click() }
+        >
+            "ファイルをここにドロップするか、クリックして選んでください (画像・PDF・テキスト)"
+            <input type="file" multiple hidden node_ref=input
+                on:click=|ev| ev.stop_propagation()
+                on:change:target=move |ev| {
+                    add_files(ev.target().files());
+                    // 同じファイルをもう一度選んでもchangeが来るように
+                    ev.target().set_value("");
+                }/>
+        </div>
+        <Show when=move || uploading.get() != 0>
+            <p class="attachment-uploading">"アップロードしています"</p>
+        </Show>
+        <div class="attachment-list">
+            <For
+                each=move || attachments.get()
+                key=|a| a.id.clone()
+                let(a)
+            >
+                {
+                    let id = a.id.clone();
+                    view! {
+                        <div class="attachment-item">
+                            <span> {format!("{} ({})", a.name, format_size(a.size))} </span>
+                            <span class="attachment-remove" on:click=move |_| attachments.update(|list| list.retain(|x| x.id != id))>"×"</span>
+                        </div>
+                    }
+                }
+            </For>
+        </div>
+        <ErrorMessage error=error/>
+    }
+}
+
+// 添付ファイルをアップロードする
+// サーバー関数ではファイルの中身をそのまま送れないので、/api/attachments に普通にPOSTする
+#[cfg(feature = "hydrate")]
+async fn upload_attachment(file: leptos::web_sys::File) -> Result<server::Attachment, AppError> {
+    use leptos::wasm_bindgen::JsCast;
+    use wasm_bindgen_futures::JsFuture;
+
+    let url = format!("/api/attachments?name={}", percent_encode(&file.name()));
+    // アクセストークンが切れていたら、ログイン状態を聞き直して (cookieが更新される) もう一度送る
+    for retried in [false, true] {
+        let init = leptos::web_sys::RequestInit::new();
+        init.set_method("POST");
+        init.set_body(&file);
+        let response: leptos::web_sys::Response = JsFuture::from(window().fetch_with_str_and_init(&url, &init))
+            .await
+            .map_err(|_| AppError::Network)?
+            .unchecked_into();
+        let text = JsFuture::from(response.text().map_err(|_| AppError::Network)?)
+            .await
+            .map_err(|_| AppError::Network)?
+            .as_string()
+            .unwrap_or_default();
+        if response.ok() {
+            return serde_json::from_str(&text).map_err(|e| AppError::Internal(e.to_string()));
+        }
+        // 上限を大きく超えたファイルはハンドラまで届かずに切られる
+        if response.status() == 413 {
+            return Err(AppError::validation("ファイルが大きすぎます"));
+        }
+        match serde_json::from_str::<AppError>(&text) {
+            Ok(AppError::TokenExpired) if !retried => {
+                server::current_user().await?;
+            }
+            Ok(e) => return Err(e),
+            Err(_) => return Err(AppError::Internal(text)),
+        }
+    }
+    Err(AppError::TokenExpired)
+}
+
+#[cfg(not(feature = "hydrate"))]
+async fn upload_attachment(_file: leptos::web_sys::File) -> Result<server::Attachment, AppError> {
+    Err(AppError::Network)
+}
+
+#[cfg(feature = "hydrate")]
+fn percent_encode(text: &str) -> String {
+    text.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+            _ => format!("%{b:02X}"),
+        })
+        .collect()
+}
+
+// 添付ファイル (画像はサムネイル、それ以外はファイル名のリンク)
+fn attachments_view(attachments: &[server::Attachment]) -> impl IntoView {
+    let items = attachments
+        .iter()
+        .map(|a| {
+            let href = format!("/attachments/{}", a.id);
+            if a.is_image() {
+                view! {
+                    <a class="attachment-image" href=href.clone() target="_blank" rel="noopener">
+                        <img src=format!("{href}/thumbnail") alt=a.name.clone() title=a.name.clone()/>
+                    </a>
+                }
+                .into_any()
+            } else {
+                view! {
+                    <a class="attachment-file" href=href download=a.name.clone()>
+                        {format!("{} ({})", a.name, format_size(a.size))}
+                    </a>
+                }
+                .into_any()
+            }
+        })
+        .collect_view();
+    (!attachments.is_empty()).then(|| view! {<div class="attachments"> {items} </div>})
+}
+
+fn format_size(size: i64) -> String {
+    if size < 1024 {
+        format!("{size}B")
+    } else if size < 1024 * 1024 {
+        format!("{:.1}KB", size as f64 / 1024.0)
+    } else {
+        format!("{:.1}MB", size as f64 / (1024.0 * 1024.0))
+    }
+}
+
+// アイコンの画像 (設定していなければ仮のアイコン)
+fn avatar_src(avatar: Option<&str>) -> String {
+    match avatar {
+        Some(id) => format!("/attachments/{id}/thumbnail"),
+        None => "/images/kariicon.jpg".to_string(),
+    }
+}
+
+// エラー表示
+#[component]
+fn ErrorMessage(error: ReadSignal<Option<AppError>>) -> impl IntoView {
+    view! {
+        <Show when=move || error.get().is_some()>
+            <p class="error-message">{move || error.get().map(|e| e.to_string())}</p>
+        </Show>
+    }
+}
+
+// 下書きの一覧 (/drafts)
+// 押すと投稿の画面で続きを書ける
+#[component]
+fn DraftsScreen() -> impl IntoView {
+    let (drafts, set_drafts) = signal(None::<Vec<server::Draft>>);
+    let (error, set_error) = signal(None::<AppError>);
+    Effect::new(move |_| {
+        task::spawn_local(async move {
+            match server::get_drafts().await {
+                Ok(drafts) => set_drafts.set(Some(drafts)),
+                Err(e) => set_error.set(Some(e)),
+            }
+        });
+    });
+
+    let discard = move |id: String| {
+        if !window().confirm_with_message("この下書きを破棄しますか？").unwrap_or(false) {
+            return;
+        }
+        task::spawn_local(async move {
+            match server::delete_draft(id.clone()).await {
+                Ok(()) => set_drafts.update(|drafts| {
+                    if let Some(drafts) = drafts {
+                        drafts.retain(|d| d.id != id);
+                    }
+                }),
+                Err(e) => set_error.set(Some(e)),
+            }
+        });
+    };
+
+    view! {
+        <Title text="下書き - Biestar"/>
+        <div class="drafts">
+            <h2>"下書き"</h2>
+            <Show when=move || drafts.with(|d| d.as_ref().is_some_and(Vec::is_empty))>
+                <p>"下書きはありません"</p>
+            </Show>
+            <For
+                each=move || drafts.get().unwrap_or_default()
+                key=|draft| (draft.id.clone(), draft.updated_at)
+                let(draft)
+            >
+                {
+                    let id = draft.id.clone();
+                    let title = if draft.title.trim().is_empty() { "(無題)".to_string() } else { draft.title.clone() };
+                    let body: String = draft.body.chars().take(100).collect();
+                    view! {
+                        <div class="draft">
+                            <div class="draft-header">
+                                <A href=format!("/post?draft={}", draft.id)>
+                                    <span class="draft-title"> {title} </span>
+                                </A>
+                                <span class="draft-time"> {format_time(draft.updated_at)} </span>
+                                <span class="draft-discard" on:click=move |_| discard(id.clone())>"破棄"</span>
+                            </div>
+                            <div class="draft-body"> {body} </div>
+                            <div class="post-actions">
+                                {draft.tag.iter().map(|t| view! {<span class="post-tag"> {t.clone()} </span>}).collect_view()}
+                            </div>
+                        </div>
+                    }
+                }
+            </For>
+            <ErrorMessage error=error/>
+        </div>
+    }
+}
+
+// 設定 (/settings)
+// フォローしているタグの投稿はタイムラインで先に出し、ミュートしているタグの投稿は出さない
+#[component]
+fn SettingsScreen() -> impl IntoView {
+    let feed = use_context::<Feed>().unwrap();
+
+    let (followed, set_followed) = signal(Vec::<String>::new());
+    let (muted, set_muted) = signal(Vec::<String>::new());
+    let (message, set_message) = signal(None::<String>);
+    let (error, set_error) = signal(None::<AppError>);
+    Effect::new(move |_| {
+        task::spawn_local(async move {
+            match server::get_tag_settings().await {
+                Ok(settings) => {
+                    set_followed.set(settings.followed);
+                    set_muted.set(settings.muted);
+                }
+                Err(e) => set_error.set(Some(e)),
+            }
+        });
+    });
+
+    let save = move || {
+        set_message.set(None);
+        set_error.set(None);
+        let (followed, muted) = (followed.get_untracked(), muted.get_untracked());
+        task::spawn_local(async move {
+            match server::save_tag_settings(Some(followed), Some(muted)).await {
+                Ok(settings) => {
+                    set_followed.set(settings.followed);
+                    set_muted.set(settings.muted);
+                    set_message.set(Some("保存しました".to_string()));
+                    // タイムラインにもすぐ反映する
+                    feed.search(feed.query.get_untracked());
+                }
+                Err(e) => set_error.set(Some(e)),
+            }
+        });
+    };
+
+    view! {
+        <Title text="設定 - Biestar"/>
+        <div class="settings">
+            <h2>"設定"</h2>
+            <ProfileSettings/>
+            <h3>"フォローしているタグ"</h3>
+            <p>"「フォロー中のタグ」のタイムラインで、これらのタグ (と子のタグ) の投稿を先に出します。"</p>
+            <TagListEditor tags=followed set_tags=set_followed/>
+            <h3>"ミュートしているタグ"</h3>
+            <p>"これらのタグ (と子のタグ) の投稿は、そのタグで検索したとき以外は出しません。"</p>
+            <TagListEditor tags=muted set_tags=set_muted/>
+            <div class="settings-buttons">
+                <button on:click=move |_| save()>"保存する"</button>
+            </div>
+            <Show when=move || message.get().is_some()>
+                <p class="settings-done">{move || message.get()}</p>
+            </Show>
+            <ErrorMessage error=error/>
+        </div>
+    }
+}
+
+// プロフィールの編集 (設定画面の一番上)
+#[component]
+fn ProfileSettings() -> impl IntoView {
+    let user = use_context::<ReadSignal<Option<User>>>().unwrap();
+    let user_write = use_context::<WriteSignal<Option<User>>>().unwrap();
+
+    let (display_name, set_display_name) = signal(String::new());
+    let (bio, set_bio) = signal(String::new());
+    let (avatar, set_avatar) = signal(None::<String>);
+    let experiences = RwSignal::new(Vec::<server::TagExperience>::new());
+    let (uploading, set_uploading) = signal(false);
+    let (message, set_message) = signal(None::<String>);
+    let (error, set_error) = signal(None::<AppError>);
+    Effect::new(move |_| {
+        let Some(name) = user.with_untracked(|u| u.as_ref().map(|u| u.name.clone())) else {
+            return;
+        };
+        task::spawn_local(async move {
+            match server::get_profile(name).await {
+                Ok(profile) => {
+                    set_display_name.set(profile.display_name);
+                    set_bio.set(profile.bio);
+                    set_avatar.set(profile.avatar);
+                    experiences.set(profile.experiences);
+                }
+                Err(e) => set_error.set(Some(e)),
+            }
+        });
+    });
+
+    let upload = move |files: Option<leptos::web_sys::FileList>| {
+        let Some(file) = files.and_then(|f| f.get(0)) else {
+            return;
+        };
+        set_error.set(None);
+        set_uploading.set(true);
+        task::spawn_local(async move {
+            match upload_attachment(file).await {
+                Ok(a) if a.is_image() => set_avatar.set(Some(a.id)),
+                Ok(_) => set_error.set(Some(AppError::validation("アイコンには画像を選んでください"))),
+                Err(e) => set_error.set(Some(e)),
+            }
+            set_uploading.set(false);
+        });
+    };
+
+    let save = move || {
+        set_message.set(None);
+        set_error.set(None);
+        let (name, text, image) = (display_name.get_untracked(), bio.get_untracked(), avatar.get_untracked());
+        let list = Some(experiences.get_untracked());
+        task::spawn_local(async move {
+            match server::save_profile(name, text, image, list).await {
+                Ok(profile) => {
+                    set_display_name.set(profile.display_name);
+                    set_bio.set(profile.bio);
+                    set_avatar.set(profile.avatar.clone());
+                    experiences.set(profile.experiences);
+                    // ヘッダーのアイコンも替える
+                    user_write.update(|u| {
+                        if let Some(u) = u {
+                            u.avatar = profile.avatar;
+                        }
+                    });
+                    set_message.set(Some("プロフィールを保存しました".to_string()));
+                }
+                Err(e) => set_error.set(Some(e)),
+            }
+        });
+    };
+
+    view! {
+        <h3>"プロフィール"</h3>
+        <div class="profile-settings">
+            <div class="profile-settings-avatar">
+                <img class="profile-avatar" src=move || avatar_src(avatar.get().as_deref()) alt="アイコン"/>
+                <label class="check-btn">
+                    {move || if uploading.get() { "アップロードしています" } else { "画像を選ぶ" }}
+                    <input type="file" accept="image/*" hidden
+                        on:change:target=move |ev| {
+                            upload(ev.target().files());
+                            ev.target().set_value("");
+                        }/>
+                </label>
+                <Show when=move || avatar.get().is_some()>
+                    <span class="check-btn" on:click=move |_| set_avatar.set(None)>"アイコンを外す"</span>
+                </Show>
+            </div>
+            <label>"表示名 (空ならユーザーネームを出します)"</label>
+            <input type="text" maxlength=server::DISPLAY_NAME_MAX_LENGTH
+                prop:value=move || display_name.get()
+                on:input:target=move |ev| set_display_name.set(ev.target().value())/>
+            <label>"自己紹介 (Markdownが使えます)"</label>
+            <textarea maxlength=server::BIO_MAX_LENGTH
+                prop:value=move || bio.get()
+                on:input:target=move |ev| set_bio.set(ev.target().value())/>
+            <label>"タグごとの経験 (投稿するときのレベルの既定になります)"</label>
+            <ExperienceEditor experiences=experiences/>
+            <div class="settings-buttons">
+                <button on:click=move |_| save() disabled=move || uploading.get()>"プロフィールを保存する"</button>
+            </div>
+            <Show when=move || message.get().is_some()>
+                <p class="settings-done">{move || message.get()}</p>
+            </Show>
+            <ErrorMessage error=error/>
+        </div>
+    }
+}
+
+// タグごとの経験を足したり書き換えたりする (保存は呼び出し側)
+#[component]
+fn ExperienceEditor(experiences: RwSignal<Vec<server::TagExperience>>) -> impl IntoView {
+    let (input, set_input) = signal(String::new());
+    let add = move || {
+        let tag = normalize_tag(&input.get_untracked());
+        if tag.is_empty() {
+            return;
+        }
+        experiences.update(|list| {
+            if !list.iter().any(|e| e.tag == tag) && list.len() < server::EXPERIENCE_MAX {
+                list.push(server::TagExperience {
+                    tag,
+                    level: ExperienceLevel::default(),
+                    years: None,
+                });
+            }
+        });
+        set_input.set(String::new());
+    };
+    let edit = move |tag: &str, f: &dyn Fn(&mut server::TagExperience)| {
+        experiences.update(|list| {
+            if let Some(e) = list.iter_mut().find(|e| e.tag == tag) {
+                f(e);
+            }
+        });
+    };
+
+    view! {
+        <div class="experience-editor">
+            <For
+                each=move || experiences.get()
+                key=|e| e.tag.clone()
+                let(e)
+            >
+                {
+                    let tag = StoredValue::new(e.tag.clone());
+                    view! {
+                        <div class="experience-row">
+                            <span class="post-tag"> {e.tag.clone()} </span>
+                            <select prop:value=e.level.as_str()
+                                on:change:target=move |ev| {
+                                    let level = ExperienceLevel::parse(&ev.target().value()).unwrap_or_default();
+                                    tag.with_value(|t| edit(t, &|e| e.level = level));
+                                }>
+                                {ExperienceLevel::ALL.into_iter().map(|l| view! {
+                                    <option value=l.as_str() selected=l == e.level>{l.label()}</option>
+                                }).collect_view()}
+                            </select>
+                            <input type="number" min="0" max=server::EXPERIENCE_YEARS_MAX placeholder="年数"
+                                prop:value=e.years.map(|y| y.to_string()).unwrap_or_default()
+                                on:input:target=move |ev| {
+                                    let years = ev.target().value().trim().parse().ok();
+                                    tag.with_value(|t| edit(t, &|e| e.years = years));
+                                }/>
+                            <span class="attachment-remove"
+                                on:click=move |_| experiences.update(|list| list.retain(|x| tag.with_value(|t| x.tag != *t)))>
+                                "×"
+                            </span>
+                        </div>
+                    }
+                }
+            </For>
+            <input type="text" placeholder="タグを入力してEnter"
+                prop:value=move || input.get()
+                on:input:target=move |ev| set_input.set(ev.target().value())
+                on:keydown=move |ev| if ev.key() == "Enter" { add() }/>
+        </div>
+    }
+}
+
+// ユーザーのプロフィール (/users/:name)
+// プロフィールと、そのユーザーの投稿・返信を出す
+#[component]
+fn UserScreen() -> impl IntoView {
+    let params = use_params_map();
+    let user = use_context::<ReadSignal<Option<User>>>().unwrap();
+    let profile = Resource::new(
+        move || params.read().get("name").unwrap_or_default(),
+        server::get_profile,
+    );
+    let (show_comments, set_show_comments) = signal(false);
+
+    view! {
+        <Suspense fallback=Loading>
+        {move || Suspend::new(async move {
+            match profile.await {
+                Ok(profile) => {
+                    let is_me = user.with_untracked(|u| u.as_ref().is_some_and(|u| u.name == profile.name));
+                    let display_name = if profile.display_name.is_empty() {
+                        profile.name.clone()
+                    } else {
+                        profile.display_name.clone()
+                    };
+                    let experiences = profile
+                        .experiences
+                        .iter()
+                        .map(|e| view! {
+                            <div class="profile-experience">
+                                <span class="post-tag"> {e.tag.clone()} </span>
+                                {level_badge(e.level)}
+                                {e.years.map(|y| view! {<span class="profile-experience-years"> {format!("{y}年")} </span>})}
+                            </div>
+                        })
+                        .collect_view();
+                    let name = StoredValue::new(profile.name.clone());
+                    view! {
+                        <Title text=format!("{display_name} - Biestar")/>
+                        <div class="profile">
+                            <div class="profile-header">
+                                <img class="profile-avatar" src=avatar_src(profile.avatar.as_deref()) alt="アイコン"/>
+                                <div class="profile-names">
+                                    <h2 class="profile-name"> {display_name} </h2>
+                                    <span class="profile-id"> "@"{profile.name.clone()} </span>
+                                </div>
+                                {is_me.then(|| view! {
+                                    <div class="check-btn"><A href="/settings">"プロフィールを編集"</A></div>
+                                })}
+                            </div>
+                            <div class="profile-bio markdown" inner_html=profile.bio_html.clone()/>
+                            {(!profile.experiences.is_empty()).then(|| view! {
+                                <div class="profile-experiences">
+                                    <span class="profile-experiences-label">"タグごとの経験"</span>
+                                    {experiences}
+                                </div>
+                            })}
+                            <div class="feed-tabs">
+                                <span class="feed-tab" class:active=move || !show_comments.get() on:click=move |_| set_show_comments.set(false)>
+                                    "投稿"
+                                </span>
+                                <span class="feed-tab" class:active=move || show_comments.get() on:click=move |_| set_show_comments.set(true)>
+                                    "返信"
+                                </span>
+                            </div>
+                            <Show
+                                when=move || show_comments.get()
+                                fallback=move || view! {<UserPosts name=name.get_value()/>}
+                            >
+                                <UserComments name=name.get_value()/>
+                            </Show>
+                        </div>
+                    }.into_any()
+                }
+                Err(e) => view! {
+                    <div class="cantlook">
+                        <span>{e.to_string()}</span>
+                    </div>
+                }.into_any(),
+            }
+        })}
+        </Suspense>
+    }
+}
+
+// ユーザーの投稿 (新しい順、下の「もっと見る」で続きを読む)
+#[component]
+fn UserPosts(name: String) -> impl IntoView {
+    let navigate = use_navigate();
+    let posts = RwSignal::new(Vec::<server::Post>::new());
+    let next = RwSignal::new(None::<String>);
+    let (loading, set_loading) = signal(false);
+    let (error, set_error) = signal(None::<AppError>);
+    let query = StoredValue::new(PostQuery {
+        author: Some(name),
+        ..PostQuery::default()
+    });
+
+    let load = move |cursor: Option<String>| {
+        set_loading.set(true);
+        task::spawn_local(async move {
+            match server::search(query.get_value(), cursor).await {
+                Ok(page) => {
+                    posts.update(|posts| posts.extend(page.posts));
+                    next.set(page.next_cursor);
+                }
+                Err(e) => set_error.set(Some(e)),
+            }
+            set_loading.set(false);
+        });
+    };
+    Effect::new(move |_| load(None));
+
+    view! {
+        <div class="profile-posts">
+            <For
+                each=move || posts.get()
+                key=|post| post.id.clone()
+                let(post)
+            >
+                {
+                    let (navigate, href) = (navigate.clone(), format!("/posts/{}", post.id));
+                    view! {
+                        <MainScreenPost on:click=move |_| navigate(&href, Default::default())
+                            post=post is_preview=true has_responce=false/>
+                    }
+                }
+            </For>
+            <Show when=move || !loading.get() && posts.with(Vec::is_empty)>
+                <p class="profile-empty">"まだ投稿がありません"</p>
+            </Show>
+            <Show when=move || loading.get()>
+                <Loading/>
+            </Show>
+            <Show when=move || !loading.get() && next.get().is_some()>
+                <div class="timeline-more" on:click=move |_| load(next.get_untracked())>"もっと見る"</div>
+            </Show>
+            <ErrorMessage error=error/>
+        </div>
+    }
+}
+
+// ユーザーの返信 (付いている投稿へのリンクと一緒に出す)
+#[component]
+fn UserComments(name: String) -> impl IntoView {
+    let comments = Resource::new(move || name.clone(), server::get_user_comments);
+
+    view! {
+        <div class="profile-comments">
+            <Suspense fallback=Loading>
+            {move || Suspend::new(async move {
+                match comments.await {
+                    Ok(comments) if comments.is_empty() => view! {
+                        <p class="profile-empty">"まだ返信がありません"</p>
+                    }.into_any(),
+                    Ok(comments) => comments
+                        .into_iter()
+                        .map(|c| view! {
+                            <div class="profile-comment">
+                                <A href=format!("/posts/{}", c.post_id) attr:class="profile-comment-post">
+                                    {c.post_title}
+                                </A>
+                                <span class="post-comment-time"> {format_time(c.comment.created_at)} </span>
+                                <div class="post-comment-body markdown" inner_html=c.comment.body_html/>
+                                {attachments_view(&c.comment.attachments)}
+                            </div>
+                        })
+                        .collect_view()
+                        .into_any(),
+                    Err(e) => view! {<p class="error-message">{e.to_string()}</p>}.into_any(),
+                }
+            })}
+            </Suspense>
+        </div>
+    }
+}
+
+// タグの並びを足したり消したりする (保存は呼び出し側)
+#[component]
+fn TagListEditor(tags: ReadSignal<Vec<String>>, set_tags: WriteSignal<Vec<String>>) -> impl IntoView {
+    let (input, set_input) = signal(String::new());
+    let add = move || {
+        let tag = normalize_tag(&input.get_untracked());
+        if tag.is_empty() {
+            return;
+        }
+        set_tags.update(|tags| {
+            if !tags.contains(&tag) {
+                tags.push(tag);
+            }
+        });
+        set_input.set(String::new());
+    };
+
+    view! {
+        <div class="tag-list-editor">
+            <div class="tag-space">
+                <For
+                    each=move || tags.get()
+                    key=|tag| tag.clone()
+                    let(tag)
+                >
+                    <TagSearch tag=tag set_select_tag=set_tags/>
+                </For>
+            </div>
+            <input type="text" placeholder="タグを入力してEnter"
+                prop:value=move || input.get()
+                on:input:target=move |ev| set_input.set(ev.target().value())
+                on:keydown=move |ev| if ev.key() == "Enter" { add() }/>
+        </div>
+    }
+}
+
+// タグの一覧 (/tags)
+// 親子関係を木にして並べ、タグを押すとそのタグ (と子孫のタグ) の投稿を探す
+#[component]
+fn TagTreeScreen() -> impl IntoView {
+    let feed = use_context::<Feed>().unwrap();
+    let user = use_context::<ReadSignal<Option<User>>>().unwrap();
+    let navigate = use_navigate();
+
+    let (tags, set_tags) = signal(Vec::<server::TagNode>::new());
+    let (error, set_error) = signal(None::<AppError>);
+    let load = move || {
+        task::spawn_local(async move {
+            match server::tag_tree().await {
+                Ok(tags) => set_tags.set(tags),
+                Err(e) => set_error.set(Some(e)),
+            }
+        });
+    };
+    Effect::new(move |_| load());
+
+    let open = Callback::new(move |tag: String| {
+        let input = format!("tag:{tag}");
+        feed.input.set(input.clone());
+        feed.search(PostQuery::parse(&input));
+        navigate("/", Default::default());
+    });
+
+    // モデレーターは親を付け替えられる
+    let (child, set_child) = signal(String::new());
+    let (parent, set_parent) = signal(String::new());
+    let (message, set_message) = signal(None::<String>);
+    let set_tag_parent = move || {
+        set_message.set(None);
+        set_error.set(None);
+        let (child, parent) = (child.get_untracked(), parent.get_untracked());
+        let parent = Some(parent).filter(|p| !p.trim().is_empty());
+        task::spawn_local(async move {
+            match server::set_tag_parent(child.clone(), parent.clone()).await {
+                Ok(()) => {
+                    set_message.set(Some(match parent {
+                        Some(parent) => format!("「{child}」を「{parent}」の下に移しました"),
+                        None => format!("「{child}」を一番上に移しました"),
+                    }));
+                    load();
+                }
+                Err(e) => set_error.set(Some(e)),
+            }
+        });
+    };
+    // 漢字のタグに読みを付けると、読みやローマ字でも入力補完に出る
+    let (reading_tag, set_reading_tag) = signal(String::new());
+    let (reading, set_reading) = signal(String::new());
+    let set_tag_reading = move || {
+        set_message.set(None);
+        set_error.set(None);
+        let (tag, reading) = (reading_tag.get_untracked(), reading.get_untracked());
+        task::spawn_local(async move {
+            match server::set_tag_reading(tag.clone(), reading.clone()).await {
+                Ok(()) => {
+                    set_message.set(Some(match reading.trim() {
+                        "" => format!("「{tag}」の読みを消しました"),
+                        reading => format!("「{tag}」の読みを「{reading}」にしました"),
+                    }));
+                    load();
+                }
+                Err(e) => set_error.set(Some(e)),
+            }
+        });
+    };
+
+    view! {
+        <Title text="タグ - Biestar"/>
+        <div class="tag-tree">
+            <h2>"タグ"</h2>
+            <Show when=move || user.get().is_some_and(|u| u.is_moderator)>
+                <div class="tag-tree-fields">
+                    <input type="text" placeholder="タグ" on:input:target=move |ev| set_child.set(ev.target().value())/>
+                    "の親を"
+                    <input type="text" placeholder="親のタグ (空なら一番上)" on:input:target=move |ev| set_parent.set(ev.target().value())/>
+                    <button on:click=move |_| set_tag_parent()>"にする"</button>
+                </div>
+                <div class="tag-tree-fields">
+                    <input type="text" placeholder="タグ" on:input:target=move |ev| set_reading_tag.set(ev.target().value())/>
+                    "の読みを"
+                    <input type="text" placeholder="ひらがな (空なら消す)" on:input:target=move |ev| set_reading.set(ev.target().value())/>
+                    <button on:click=move |_| set_tag_reading()>"にする"</button>
+                </div>
+                <Show when=move || message.get().is_some()>
+                    <p class="tag-tree-done">{move || message.get()}</p>
+                </Show>
+            </Show>
+            <ErrorMessage error=error/>
+            <ul class="tag-tree-root">
+                {move || tags.with(|tags| tag_tree(tags, None, open))}
+            </ul>
+        </div>
+    }
+}
+
+// parentの子のタグを再帰的に並べる
+// 親が見つからないタグは一番上に出す
+fn tag_tree(tags: &[server::TagNode], parent: Option<&str>, open: Callback<String>) -> AnyView {
+    let is_child = |t: &server::TagNode| match (&t.parent, parent) {
+        (Some(p), Some(parent)) => p == parent,
+        (Some(p), None) => !tags.iter().any(|t| t.tag == *p),
+        (None, parent) => parent.is_none(),
+    };
+    tags.iter()
+        .filter(|t| is_child(t))
+        .map(|t| {
+            let tag = t.tag.clone();
+            view! {
+                <li class="tag-tree-node">
+                    <span class="tag-tree-name" on:click=move |_| open.run(tag.clone())> {t.tag.clone()} </span>
+                    {t.reading.clone().map(|r| view! {<span class="tag-tree-reading"> {r} </span>})}
+                    <span class="tag-count"> {t.count} </span>
+                    <ul class="tag-tree-children">
+                        {tag_tree(tags, Some(&t.tag), open)}
+                    </ul>
+                </li>
+            }
+        })
+        .collect_view()
+        .into_any()
+}
+
+// タグの統合 (/tags/merge、モデレーター用)
+#[component]
+fn TagMergeScreen() -> impl IntoView {
+    let (from, set_from) = signal(String::new());
+    let (into, set_into) = signal(String::new());
+    let (message, set_message) = signal(None::<String>);
+    let (error, set_error) = signal(None::<AppError>);
+
+    let merge = move || {
+        set_message.set(None);
+        set_error.set(None);
+        let (from, into) = (from.get_untracked(), into.get_untracked());
+        task::spawn_local(async move {
+            match server::merge_tags(from.clone(), into.clone()).await {
+                Ok(merged) => set_message.set(Some(format!(
+                    "「{from}」を「{into}」に統合しました ({merged}件の投稿を付け替えました)"
+                ))),
+                Err(e) => set_error.set(Some(e)),
+            }
+        });
+    };
+
+    view! {
+        <Title text="タグの統合 - Biestar"/>
+        <div class="tag-merge">
+            <h2>"タグの統合"</h2>
+            <p>"統合したタグは統合先のタグの別名になり、付いていた投稿は統合先のタグに付け替えます。"</p>
+            <div class="tag-merge-fields">
+                <input type="text" placeholder="統合するタグ" on:input:target=move |ev| set_from.set(ev.target().value())/>
+                "→"
+                <input type="text" placeholder="統合先のタグ" on:input:target=move |ev| set_into.set(ev.target().value())/>
+                <button on:click=move |_| merge()>"統合する"</button>
+            </div>
+            <Show when=move || message.get().is_some()>
+                <p class="tag-merge-done">{move || message.get()}</p>
+            </Show>
+            <ErrorMessage error=error/>
+        </div>
+    }
+}
+
 //ログイン画面
 
 #[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
 pub enum LoginScreenState {
     Ok,
-    InvalidAccount,
-    TooShortPassword,
-    NameExists,
     Logining,
     SigningUp,
+    Failed(AppError),
 }
 
 #[component]
@@ -356,39 +2215,39 @@ fn Login() -> impl IntoView {
     let login = move |(name, password): (String, String)| {
         set_login_state.set(LoginScreenState::Logining);
         task::spawn_local(async move {
-            let api = server::log_in(name.clone(), password).await.unwrap();
+            let api = server::log_in(name, password).await;
             match api {
-                Ok(token) => {
+                Ok(user) => {
                     set_login_state.set(LoginScreenState::Ok);
                     let user_write = use_context::<WriteSignal<Option<User>>>().unwrap();
-                    user_write.set(Some(User { jwt: token, name }))
+                    user_write.set(Some(user))
                 }
-                Err(state) => set_login_state.set(state),
+                Err(e) => set_login_state.set(LoginScreenState::Failed(e)),
             }
         });
     };
     let signup = move |(name, password): (String, String)| {
         set_login_state.set(LoginScreenState::SigningUp);
         task::spawn_local(async move {
-            let api = server::sign_up(name.clone(), password).await.unwrap();
+            let api = server::sign_up(name, password).await;
             match api {
-                Ok(token) => {
+                Ok(user) => {
                     set_login_state.set(LoginScreenState::Ok);
                     let user_write = use_context::<WriteSignal<Option<User>>>().unwrap();
-                    user_write.set(Some(User { jwt: token, name }))
+                    user_write.set(Some(user))
                 }
-                Err(state) => set_login_state.set(state),
+                Err(e) => set_login_state.set(LoginScreenState::Failed(e)),
             }
         });
     };
 
     view! {
-        <img class="backpicture" src="./images/IMG_0257.JPG" alt="Background Image"/>
+        <img class="backpicture" src="/images/IMG_0257.JPG" alt="Background Image"/>
             <div class="login-board">
             <input type="text" class="user-name" autocomplete="username" placeholder="ユーザーネーム" on:input:target=move |ev| set_name.set(ev.target().value())/>
             <div class="password-wrap">
                 <input type={change_visible} class="password" placeholder="パスワード" on:input:target=move |ev| set_password.set(ev.target().value())/>
-                <img src="./images/eye_transparent.png" class="eye-icon"
+                <img src="/images/eye_transparent.png" class="eye-icon"
                     on:click={move |_| *set_visible.write() = !visible.get()}/>
             </div>
             <button class="loginbtn" on:click={move |_| login((name.get(), password.get()))}>"ログイン"</button>
@@ -396,11 +2255,9 @@ fn Login() -> impl IntoView {
                 when=move || login_state.get() != LoginScreenState::Ok> <p class="wrongpassword">{move || {
                     match login_state.get(){
                         LoginScreenState::Ok => unreachable!(),
-                        LoginScreenState::InvalidAccount => "パスワードかユーザーネームが間違っています",
-                        LoginScreenState::Logining => "ログイン中です",
-                        LoginScreenState::SigningUp => "登録中です",
-                        LoginScreenState::NameExists => "その名前は存在しています",
-                        LoginScreenState::TooShortPassword => "パスワードは8文字以上にしてください",
+                        LoginScreenState::Logining => "ログイン中です".to_string(),
+                        LoginScreenState::SigningUp => "登録中です".to_string(),
+                        LoginScreenState::Failed(e) => e.to_string(),
                     }
                 }
             }</p>
@@ -412,28 +2269,108 @@ fn Login() -> impl IntoView {
 
 #[component]
 fn ResponceScreen() -> impl IntoView{
-    let p = use_context::<ReadSignal<Option<server::Post>>>().unwrap();
+    let params = use_params_map();
+    let loaded = Resource::new_blocking(
+        move || params.read().get("id").unwrap_or_default(),
+        server::get_post,
+    );
+    // 返信したらすぐ反映できるように、読み込んだ投稿は手元のsignalに入れておく
+    let (p, set_p) = signal(None::<server::Post>);
+    let set_posts = use_context::<WriteSignal<Vec<server::Post>>>().unwrap();
 
-    let (post, set_post) = signal(String::new());
+    let (body, set_body) = signal(String::new());
+    let (is_sending, set_is_sending) = signal(false);
+    let (error, set_error) = signal(None::<AppError>);
+    let (sent, set_sent) = signal(false);
+    // 返信先の返信 (Noneなら投稿への返信)
+    let (reply_to, set_reply_to) = signal(None::<server::Comment>);
+    let attachments = RwSignal::new(Vec::<server::Attachment>::new());
 
-    let user = use_context::<ReadSignal<Option<User>>>().unwrap();
+    let reply = move || {
+        let Some(post) = p.get_untracked() else {
+            return;
+        };
+        if is_sending.get_untracked() {
+            return;
+        }
+        set_is_sending.set(true);
+        set_error.set(None);
+        set_sent.set(false);
+        let text = body.get_untracked();
+        let parent_id = reply_to.get_untracked().map(|c| c.id);
+        task::spawn_local(async move {
+            let files = Some(attachments.get_untracked());
+            match server::add_comment(post.id.clone(), parent_id, text, files).await {
+                Ok(comment) => {
+                    // 開いている投稿とタイムラインの両方に反映する
+                    set_p.update(|p| {
+                        if let Some(p) = p {
+                            p.comment.push(comment.clone());
+                        }
+                    });
+                    set_posts.update(|posts| {
+                        if let Some(p) = posts.iter_mut().find(|p| p.id == post.id) {
+                            p.comment.push(comment);
+                        }
+                    });
+                    set_body.set(String::new());
+                    attachments.set(vec![]);
+                    set_reply_to.set(None);
+                    set_sent.set(true);
+                }
+                Err(e) => set_error.set(Some(e)),
+            }
+            set_is_sending.set(false);
+        });
+    };
 
     view!{
         <div class="box-responce" id="side-space-left">
             <div class="post-right">
-                <MainScreenPost post=(move || {p.get_untracked().unwrap()})() is_preview=false has_responce=false/>
+                <Suspense fallback=Loading>
+                {move || Suspend::new(async move {
+                    match loaded.await {
+                        Ok(post) => {
+                            set_p.set(Some(post));
+                            view! {
+                                {move || p.get().map(|post| view! {
+                                    <MainScreenPost post=post is_preview=false has_responce=false
+                                        on_reply=Callback::new(move |c| set_reply_to.set(Some(c)))
+                                        on_comment_change=Callback::new(move |c: server::Comment| set_p.update(|p| {
+                                            if let Some(c0) = p.as_mut().and_then(|p| p.comment.iter_mut().find(|c0| c0.id == c.id)) {
+                                                *c0 = c;
+                                            }
+                                        }))/>
+                                })}
+                            }.into_any()
+                        }
+                        Err(e) => view! {<p class="error-message">{e.to_string()}</p>}.into_any(),
+                    }
+                })}
+                </Suspense>
             </div>
         </div>
         <div class="outer-responce">
-                <textarea class="text-space" placeholder="内容を入力" on:input:target=move |ev| set_post.set(ev.target().value())/> <br/>
+                <Show when=move || reply_to.get().is_some()>
+                    <p class="responce-target">
+                        {move || reply_to.get().map(|c| format!("{}さんへの返信", c.name))}
+                        <span class="responce-target-cancel" on:click=move |_| set_reply_to.set(None)>"×"</span>
+                    </p>
+                </Show>
+                <PreviewTabs text=body>
+                    <textarea class="text-space" placeholder="内容を入力 (Markdownが使えます)" prop:value=body on:input:target=move |ev| set_body.set(ev.target().value())/>
+                </PreviewTabs>
+                <AttachmentPicker attachments=attachments/>
+                <Show when=move || sent.get()>
+                    <p class="responce-sent">"返信しました"</p>
+                </Show>
+                <Show when=move || is_sending.get()>
+                    <p class="responce-sent">"送信中です"</p>
+                </Show>
+                <ErrorMessage error=error/>
                 <div class="post-button-responce">
-                    <img src="/images/mailing_fill72.png" on:click=move |_| {
-                        task::spawn_local(async move {
-                            let u = user.get().unwrap();
-                            //server::add_comment(p.get_untracked().unwrap().id, u.name, u.jwt, post.get()).await.unwrap();
-                        })}
-                    />
+                    <img src="/images/mailing_fill72.png" on:click=move |_| reply()/>
                 </div>
         </div>
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,356 @@
+// 添付ファイル
+// アップロードされたファイルは中身のSHA-256をIDにしてローカルのディスクに置く (同じファイルは1つだけ)
+//   {dir}/ab/abcdef...            => ファイルそのもの
+//   {dir}/thumbnails/abcdef....png => 画像なら縮小したもの (アップロードのときに作る)
+// 種類はファイルの先頭のバイト列で決める (ブラウザが送ってくるContent-Typeは信用しない)
+
+use crate::{auth::CurrentUser, config, error::AppError, server::Attachment};
+use axum::{
+    body::Bytes,
+    extract::{Path, Query},
+    http::header,
+    response::{IntoResponse, Response},
+    Json,
+};
+use image::{ImageFormat, ImageReader, Limits};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::{
+    io::Cursor,
+    path::{Path as FilePath, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+};
+use tokio::io::AsyncReadExt;
+
+// サムネイルの長いほうの辺
+const THUMBNAIL_SIZE: u32 = 320;
+// これより大きい画像は読まない (小さいファイルで大量のメモリを使わせないように)
+const IMAGE_MAX_DIMENSION: u32 = 4096;
+// 画像を読むときに使ってよいメモリ
+const IMAGE_MAX_ALLOC: u64 = 64 * 1024 * 1024;
+const FILE_NAME_MAX_LENGTH: usize = 100;
+// 置いてあるファイルの種類を決めるときに読む先頭のバイト数
+const SNIFF_LENGTH: u64 = 512;
+
+// 添付できるファイルの種類
+fn sniff(bytes: &[u8]) -> Option<&'static str> {
+    if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some("image/png")
+    } else if bytes.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        Some("image/jpeg")
+    } else if bytes.starts_with(b"GIF87a") || bytes.starts_with(b"GIF89a") {
+        Some("image/gif")
+    } else if bytes.len() >= 12 && &bytes[..4] == b"RIFF" && &bytes[8..12] == b"WEBP" {
+        Some("image/webp")
+    } else if bytes.starts_with(b"%PDF-") {
+        Some("application/pdf")
+    } else if !bytes.contains(&0) && std::str::from_utf8(bytes).is_ok() {
+        Some("text/plain")
+    } else {
+        None
+    }
+}
+
+// 置いてあるファイルは先頭だけ読んで種類と大きさを返す
+// 中身はアップロードのときに全体を確かめてあるので、先頭の最後で切れた文字は気にしない
+async fn sniff_file(path: &FilePath) -> std::io::Result<Option<(&'static str, u64)>> {
+    let file = tokio::fs::File::open(path).await?;
+    let size = file.metadata().await?.len();
+    let mut head = vec![];
+    file.take(SNIFF_LENGTH).read_to_end(&mut head).await?;
+    let head = match std::str::from_utf8(&head) {
+        Err(e) if (head.len() as u64) < size && e.error_len().is_none() => &head[..e.valid_up_to()],
+        _ => &head[..],
+    };
+    Ok(sniff(head).map(|content_type| (content_type, size)))
+}
+
+fn image_format(content_type: &str) -> Option<ImageFormat> {
+    match content_type {
+        "image/png" => Some(ImageFormat::Png),
+        "image/jpeg" => Some(ImageFormat::Jpeg),
+        "image/gif" => Some(ImageFormat::Gif),
+        "image/webp" => Some(ImageFormat::WebP),
+        _ => None,
+    }
+}
+
+// IDはSHA-256の16進数 (パスに使うので、それ以外は受け付けない)
+fn is_id(id: &str) -> bool {
+    id.len() == 64 && id.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+fn blob_path(id: &str) -> PathBuf {
+    PathBuf::from(&config::get().attachment.dir)
+        .join(&id[..2])
+        .join(id)
+}
+
+fn thumbnail_path(id: &str) -> PathBuf {
+    PathBuf::from(&config::get().attachment.dir)
+        .join("thumbnails")
+        .join(format!("{id}.png"))
+}
+
+// 一時ファイルの名前に付ける番号
+static TMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+// 書きかけのファイルを読まれないように、別の名前で書いてから置き換える
+// 同じファイルが同時にアップロードされても一時ファイルが重ならないように、書くたびに名前を変える
+async fn write_file(path: PathBuf, bytes: Vec<u8>) -> std::io::Result<()> {
+    if let Some(dir) = path.parent() {
+        tokio::fs::create_dir_all(dir).await?;
+    }
+    let n = TMP_COUNTER.fetch_add(1, Ordering::Relaxed);
+    let tmp = path.with_extension(format!("tmp{}-{n}", std::process::id()));
+    tokio::fs::write(&tmp, bytes).await?;
+    tokio::fs::rename(&tmp, &path).await
+}
+
+fn make_thumbnail(bytes: &[u8], format: ImageFormat) -> Result<Vec<u8>, image::ImageError> {
+    let mut reader = ImageReader::with_format(Cursor::new(bytes), format);
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(IMAGE_MAX_DIMENSION);
+    limits.max_image_height = Some(IMAGE_MAX_DIMENSION);
+    limits.max_alloc = Some(IMAGE_MAX_ALLOC);
+    reader.limits(limits);
+    let thumbnail = reader.decode()?.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE);
+    let mut out = Cursor::new(vec![]);
+    thumbnail.write_to(&mut out, ImageFormat::Png)?;
+    Ok(out.into_inner())
+}
+
+// ファイル名はリンクの表示とダウンロードのときの名前にだけ使う
+fn file_name(name: &str) -> String {
+    let name: String = name
+        .rsplit(['/', '\\'])
+        .next()
+        .unwrap_or_default()
+        .chars()
+        .filter(|c| !c.is_control())
+        .take(FILE_NAME_MAX_LENGTH)
+        .collect();
+    match name.trim() {
+        "" => "file".to_string(),
+        name => name.to_string(),
+    }
+}
+
+// アップロード済みのファイル (投稿するときに、送られてきたIDが本当にあるか確かめる)
+// 種類と大きさはファイルから決め直し、名前だけ送られてきたものを使う
+pub async fn find(id: &str, name: &str) -> Result<Option<Attachment>, AppError> {
+    if !is_id(id) {
+        return Ok(None);
+    }
+    let sniffed = match sniff_file(&blob_path(id)).await {
+        Ok(sniffed) => sniffed,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(AppError::internal(e)),
+    };
+    Ok(sniffed.map(|(content_type, size)| Attachment {
+        id: id.to_string(),
+        name: file_name(name),
+        content_type: content_type.to_string(),
+        size: size as i64,
+    }))
+}
+
+// アップロードされたファイルの大きさと種類を確かめる
+fn check_upload(bytes: &[u8], max_size: usize) -> Result<&'static str, AppError> {
+    if bytes.is_empty() {
+        return Err(AppError::validation("空のファイルは添付できません"));
+    }
+    if bytes.len() > max_size {
+        return Err(AppError::validation(format!(
+            "添付できるファイルは{}KBまでです",
+            max_size / 1024
+        )));
+    }
+    sniff(bytes).ok_or_else(|| {
+        AppError::validation(
+            "添付できるのは画像 (PNG・JPEG・GIF・WebP)・PDF・テキストファイルだけです",
+        )
+    })
+}
+
+#[derive(Deserialize)]
+pub struct UploadQuery {
+    #[serde(default)]
+    name: String,
+}
+
+// POST /api/attachments?name=ファイル名 (本文はファイルの中身そのまま)
+// 大きさの上限はmain.rsでルートに付けたDefaultBodyLimitで切る
+// ログインしていないと使えない (アクセストークンが切れていればTokenExpiredを返す)
+pub async fn upload(
+    _user: CurrentUser,
+    Query(query): Query<UploadQuery>,
+    bytes: Bytes,
+) -> Result<Json<Attachment>, AppError> {
+    let content_type = check_upload(&bytes, config::get().attachment.max_size)?;
+    let id = format!("{:x}", Sha256::digest(&bytes));
+
+    // 画像は読めることを確かめてからサムネイルと一緒に置く
+    if let Some(format) = image_format(content_type) {
+        let path = thumbnail_path(&id);
+        if tokio::fs::metadata(&path).await.is_err() {
+            let data = bytes.clone();
+            let thumbnail = tokio::task::spawn_blocking(move || make_thumbnail(&data, format))
+                .await
+                .map_err(AppError::internal)?
+                .map_err(|e| match e {
+                    image::ImageError::Limits(_) => AppError::validation(format!(
+                        "画像は縦横{IMAGE_MAX_DIMENSION}ピクセルまでです"
+                    )),
+                    _ => AppError::validation("画像を読み込めませんでした"),
+                })?;
+            write_file(path, thumbnail)
+                .await
+                .map_err(AppError::internal)?;
+        }
+    }
+    let path = blob_path(&id);
+    if tokio::fs::metadata(&path).await.is_err() {
+        write_file(path, bytes.to_vec())
+            .await
+            .map_err(AppError::internal)?;
+    }
+    Ok(Json(Attachment {
+        id,
+        name: file_name(&query.name),
+        content_type: content_type.to_string(),
+        size: bytes.len() as i64,
+    }))
+}
+
+// 中身が変わらないので、ずっとキャッシュしてよい
+fn blob_response(content_type: &str, bytes: Vec<u8>) -> Response {
+    // 画像以外はページとして開かれないようにダウンロードさせる
+    let disposition = if content_type.starts_with("image/") {
+        "inline"
+    } else {
+        "attachment"
+    };
+    let content_type = match content_type {
+        "text/plain" => "text/plain; charset=utf-8",
+        content_type => content_type,
+    };
+    (
+        [
+            (header::CONTENT_TYPE, content_type),
+            (header::CONTENT_DISPOSITION, disposition),
+            (header::CACHE_CONTROL, "public, max-age=31536000, immutable"),
+            (header::X_CONTENT_TYPE_OPTIONS, "nosniff"),
+            (header::CONTENT_SECURITY_POLICY, "sandbox"),
+        ],
+        bytes,
+    )
+        .into_response()
+}
+
+// GET /attachments/{id}
+pub async fn serve(Path(id): Path<String>) -> Result<Response, AppError> {
+    let not_found = || AppError::not_found("ファイルが見つかりません");
+    if !is_id(&id) {
+        return Err(not_found());
+    }
+    let bytes = tokio::fs::read(blob_path(&id))
+        .await
+        .map_err(|_| not_found())?;
+    let content_type = sniff(&bytes).ok_or_else(not_found)?;
+    Ok(blob_response(content_type, bytes))
+}
+
+// GET /attachments/{id}/thumbnail
+pub async fn thumbnail(Path(id): Path<String>) -> Result<Response, AppError> {
+    let not_found = || AppError::not_found("サムネイルが見つかりません");
+    if !is_id(&id) {
+        return Err(not_found());
+    }
+    let bytes = tokio::fs::read(thumbnail_path(&id))
+        .await
+        .map_err(|_| not_found())?;
+    Ok(blob_response("image/png", bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageError, RgbImage};
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let mut out = Cursor::new(vec![]);
+        RgbImage::new(width, height)
+            .write_to(&mut out, ImageFormat::Png)
+            .unwrap();
+        out.into_inner()
+    }
+
+    #[test]
+    fn sniff_uses_leading_bytes() {
+        assert_eq!(sniff(&png(1, 1)), Some("image/png"));
+        assert_eq!(sniff(&[0xFF, 0xD8, 0xFF, 0xE0]), Some("image/jpeg"));
+        assert_eq!(sniff(b"GIF89a..."), Some("image/gif"));
+        assert_eq!(sniff(b"RIFF\0\0\0\0WEBPVP8 "), Some("image/webp"));
+        assert_eq!(sniff(b"%PDF-1.7"), Some("application/pdf"));
+        assert_eq!(sniff("テキスト".as_bytes()), Some("text/plain"));
+        // NULを含むもの・UTF-8でないものは受け付けない
+        assert_eq!(sniff(b"text\0"), None);
+        assert_eq!(sniff(&[0xFF, 0xFE, 0x41]), None);
+        assert_eq!(sniff(b"RIFF\0\0\0\0WAVE"), None);
+    }
+
+    #[test]
+    fn sniff_file_reads_only_the_head() {
+        let dir = std::env::temp_dir().join(format!("attachment-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        // 先頭の最後で文字が切れていてもテキストとして扱う
+        let path = dir.join("text");
+        std::fs::write(&path, "あ".repeat(200)).unwrap();
+        let sniffed = tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(sniff_file(&path))
+            .unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(sniffed, Some(("text/plain", 600)));
+    }
+
+    #[test]
+    fn is_id_accepts_only_lowercase_sha256() {
+        let id = "0123456789abcdef".repeat(4);
+        assert!(is_id(&id));
+        assert!(!is_id(&id[..63]));
+        assert!(!is_id(&id.to_uppercase()));
+        assert!(!is_id(&format!("../{}", &id[3..])));
+    }
+
+    #[test]
+    fn check_upload_rejects_empty_large_and_unknown_files() {
+        assert_eq!(check_upload(b"hello", 5), Ok("text/plain"));
+        assert!(matches!(check_upload(b"", 5), Err(AppError::Validation(_))));
+        assert!(matches!(
+            check_upload(b"hello!", 5),
+            Err(AppError::Validation(_))
+        ));
+        assert!(matches!(
+            check_upload(&[0x00, 0x01], 5),
+            Err(AppError::Validation(_))
+        ));
+    }
+
+    #[test]
+    fn make_thumbnail_shrinks_images() {
+        let thumbnail = make_thumbnail(&png(640, 320), ImageFormat::Png).unwrap();
+        let thumbnail = image::load_from_memory(&thumbnail).unwrap();
+        assert_eq!((thumbnail.width(), thumbnail.height()), (320, 160));
+    }
+
+    #[test]
+    fn make_thumbnail_rejects_images_over_the_limits() {
+        let wide = png(IMAGE_MAX_DIMENSION + 1, 1);
+        assert!(matches!(
+            make_thumbnail(&wide, ImageFormat::Png),
+            Err(ImageError::Limits(_))
+        ));
+    }
+}
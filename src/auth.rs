@@ -0,0 +1,511 @@
+// ログインまわり
+// トークンはHttpOnlyのcookieに入れて、クライアントのコードからは触らせない
+// biestar_access  => 短命のJWT (アクセストークン)
+// biestar_refresh => サーバー側に保存したセッションのリフレッシュトークン
+
+use crate::{
+    config,
+    error::AppError,
+    storage::{self, Session, Storage, User},
+};
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use axum::{
+    extract::{FromRequestParts, Request},
+    http::{
+        header::{COOKIE, SET_COOKIE},
+        request::Parts,
+        HeaderMap, HeaderValue,
+    },
+    middleware::Next,
+    response::Response,
+    Extension,
+};
+use jsonwebtoken::{
+    decode, encode, errors::ErrorKind, Algorithm::HS256, DecodingKey, EncodingKey, Header,
+    Validation,
+};
+use leptos::prelude::*;
+use leptos_axum::ResponseOptions;
+use mongodb::bson::oid::ObjectId;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::sync::{Arc, LazyLock};
+use tokio::sync::Mutex;
+
+const ACCESS_COOKIE: &str = "biestar_access";
+const REFRESH_COOKIE: &str = "biestar_refresh";
+// リフレッシュトークンを入れ替えてから、古いトークンをまだ受け付ける秒数
+// (同時に送られたリクエストがどれも古いトークンを持っているため)
+const REFRESH_GRACE_PERIOD: i64 = 30;
+
+static JWT_ENCODE_KEY: LazyLock<EncodingKey> =
+    LazyLock::new(|| EncodingKey::from_secret(config::get().auth.jwt_secret.as_bytes()));
+
+static JWT_DECODE_KEY: LazyLock<DecodingKey> =
+    LazyLock::new(|| DecodingKey::from_secret(config::get().auth.jwt_secret.as_bytes()));
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Claims {
+    // ユーザーID
+    sub: String,
+    name: String,
+    // セッションID (ログアウトしたら無効になる)
+    sid: String,
+    exp: i64,
+}
+
+fn now() -> i64 {
+    jsonwebtoken::get_current_timestamp() as i64
+}
+
+fn random_hex(bytes: usize) -> String {
+    let mut buf = vec![0u8; bytes];
+    OsRng.fill_bytes(&mut buf);
+    buf.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn hash_secret(secret: &str) -> String {
+    Sha256::digest(secret.as_bytes())
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+fn logged_out() -> AppError {
+    AppError::auth("ログアウトされています。ログインし直してください")
+}
+
+// 古いトークン・セッションにはユーザーIDが入っていないので、読めなければログアウト扱いにする
+fn parse_user_id(id: &str) -> Result<ObjectId, AppError> {
+    ObjectId::parse_str(id).map_err(|_| logged_out())
+}
+
+fn make_jwt(user_id: ObjectId, name: String, sid: String) -> Result<String, AppError> {
+    let exp = now() + config::get().auth.token_lifetime;
+    encode(
+        &Header::default(),
+        &Claims {
+            sub: user_id.to_hex(),
+            name,
+            sid,
+            exp,
+        },
+        &JWT_ENCODE_KEY,
+    )
+    .map_err(AppError::internal)
+}
+
+// cookie
+
+fn cookie<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .filter_map(|c| c.trim().split_once('='))
+        .find(|(k, _)| *k == name)
+        .map(|(_, v)| v)
+}
+
+fn set_cookie(name: &str, value: &str, max_age: i64) {
+    let secure = if config::get().auth.cookie_secure {
+        "; Secure"
+    } else {
+        ""
+    };
+    let header =
+        format!("{name}={value}; Max-Age={max_age}; Path=/; HttpOnly; SameSite=Lax{secure}");
+    match HeaderValue::from_str(&header) {
+        Ok(value) => expect_context::<ResponseOptions>().append_header(SET_COOKIE, value),
+        Err(e) => leptos::logging::error!("invalid cookie: {e}"),
+    }
+}
+
+fn set_session_cookies(access_token: &str, refresh_token: &str) {
+    let auth = &config::get().auth;
+    set_cookie(ACCESS_COOKIE, access_token, auth.token_lifetime);
+    set_cookie(REFRESH_COOKIE, refresh_token, auth.refresh_token_lifetime);
+}
+
+fn clear_session_cookies() {
+    set_cookie(ACCESS_COOKIE, "", 0);
+    set_cookie(REFRESH_COOKIE, "", 0);
+}
+
+// セッション
+
+// 新しいセッションを作ってcookieを発行する (サーバー関数の中から呼ぶ)
+pub async fn start_session(user: &User) -> Result<(), AppError> {
+    let id = random_hex(16);
+    let secret = random_hex(32);
+    storage::get()
+        .insert_session(Session {
+            id: id.clone(),
+            user_id: user.id.to_hex(),
+            name: user.name.clone(),
+            refresh_hash: hash_secret(&secret),
+            expires_at: now() + config::get().auth.refresh_token_lifetime,
+            previous_hash: String::new(),
+            rotated_at: 0,
+        })
+        .await?;
+    set_session_cookies(
+        &make_jwt(user.id, user.name.clone(), id.clone())?,
+        &format!("{id}.{secret}"),
+    );
+    Ok(())
+}
+
+// リフレッシュトークンのセッションを返す (ひとつ前のトークンでも見つける)
+async fn find_refresh_session(
+    storage: &dyn Storage,
+    refresh_token: &str,
+    now: i64,
+) -> Result<(Session, String), AppError> {
+    let (id, secret) = refresh_token.split_once('.').ok_or_else(logged_out)?;
+    let session = storage.find_session(id).await?.ok_or_else(logged_out)?;
+    let hash = hash_secret(secret);
+    if (session.refresh_hash != hash && session.previous_hash != hash) || session.expires_at < now {
+        return Err(logged_out());
+    }
+    Ok((session, hash))
+}
+
+// リフレッシュトークンを新しいものに入れ替える
+// 入れ替えたら新しいリフレッシュトークンを、入れ替えたばかりの古いトークンならNoneを返す
+async fn rotate_refresh_token(
+    storage: &dyn Storage,
+    refresh_token: &str,
+    now: i64,
+    lifetime: i64,
+) -> Result<(Session, Option<String>), AppError> {
+    let (mut session, hash) = find_refresh_session(storage, refresh_token, now).await?;
+    if session.refresh_hash == hash {
+        let new_secret = random_hex(32);
+        let rotated = storage
+            .rotate_session(
+                &session.id,
+                &hash,
+                &hash_secret(&new_secret),
+                now + lifetime,
+                now,
+            )
+            .await?;
+        if rotated {
+            let token = format!("{}.{new_secret}", session.id);
+            return Ok((session, Some(token)));
+        }
+        // 読んでから入れ替えるまでの間に、同時に来た別のリクエストが入れ替えた
+        session = storage
+            .find_session(&session.id)
+            .await?
+            .ok_or_else(logged_out)?;
+    }
+    if session.previous_hash != hash {
+        return Err(logged_out());
+    }
+    if now - session.rotated_at > REFRESH_GRACE_PERIOD {
+        // 入れ替えてからしばらく経って古いトークンが使われた (盗まれた可能性がある) のでセッションごと無効にする
+        storage.delete_session(&session.id).await?;
+        return Err(logged_out());
+    }
+    Ok((session, None))
+}
+
+// リフレッシュトークンを入れ替えて、アクセストークンを発行し直す
+async fn refresh_session(refresh_token: &str) -> Result<CurrentUser, AppError> {
+    let auth = &config::get().auth;
+    let (session, new_token) = rotate_refresh_token(
+        &*storage::get(),
+        refresh_token,
+        now(),
+        auth.refresh_token_lifetime,
+    )
+    .await?;
+    let user = CurrentUser {
+        id: parse_user_id(&session.user_id)?,
+        name: session.name,
+        session_id: session.id,
+    };
+    let access_token = make_jwt(user.id, user.name.clone(), user.session_id.clone())?;
+    match new_token {
+        Some(refresh_token) => set_session_cookies(&access_token, &refresh_token),
+        // 新しいリフレッシュトークンは入れ替えたほうのレスポンスで届くので、アクセストークンだけ出し直す
+        None => set_cookie(ACCESS_COOKIE, &access_token, auth.token_lifetime),
+    }
+    Ok(user)
+}
+
+// 1つのリクエストの中でリフレッシュした結果
+// SSRでは1回の描画で何度も current_user を呼ぶので、リフレッシュは最初の1回だけにする
+#[derive(Clone, Default)]
+pub struct RefreshedUser(Arc<Mutex<Option<CurrentUser>>>);
+
+// リクエストごとに RefreshedUser を入れておくaxumのミドルウェア
+pub async fn with_refreshed_user(mut request: Request, next: Next) -> Response {
+    request.extensions_mut().insert(RefreshedUser::default());
+    next.run(request).await
+}
+
+// everywhere = true ならそのユーザーのすべてのセッションを無効にする
+pub async fn end_session(everywhere: bool) -> Result<(), AppError> {
+    let headers: HeaderMap = leptos_axum::extract().await.map_err(AppError::internal)?;
+    clear_session_cookies();
+    match cookie(&headers, REFRESH_COOKIE) {
+        Some(token) => revoke_session(&*storage::get(), token, everywhere, now()).await,
+        None => Ok(()),
+    }
+}
+
+async fn revoke_session(
+    storage: &dyn Storage,
+    refresh_token: &str,
+    everywhere: bool,
+    now: i64,
+) -> Result<(), AppError> {
+    let session = match find_refresh_session(storage, refresh_token, now).await {
+        Ok((session, _)) => session,
+        // すでに無効なトークンならログアウト済みとみなす
+        Err(AppError::Auth(_)) => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    if everywhere {
+        storage.delete_sessions_of(&session.user_id).await?;
+    } else {
+        storage.delete_session(&session.id).await?;
+    }
+    Ok(())
+}
+
+// ログイン中のユーザー
+// サーバー関数はリクエストの引数ではなく、必ずここから誰が操作しているかを取る
+
+#[derive(Debug, Clone)]
+pub struct CurrentUser {
+    pub id: ObjectId,
+    // 表示用の名前
+    pub name: String,
+    pub session_id: String,
+}
+
+async fn check_access_token(jwt: &str) -> Result<CurrentUser, AppError> {
+    let claims = match decode::<Claims>(jwt, &JWT_DECODE_KEY, &Validation::new(HS256)) {
+        Ok(token) => token.claims,
+        Err(e) if *e.kind() == ErrorKind::ExpiredSignature => return Err(AppError::TokenExpired),
+        Err(_) => return Err(AppError::auth("ログインし直してください")),
+    };
+    let id = parse_user_id(&claims.sub)?;
+    // ログアウト済みのセッションのトークンは使えない
+    match storage::get().find_session(&claims.sid).await? {
+        Some(session) if session.expires_at >= now() && session.user_id == claims.sub => {
+            Ok(CurrentUser {
+                id,
+                name: claims.name,
+                session_id: claims.sid,
+            })
+        }
+        _ => Err(logged_out()),
+    }
+}
+
+// アクセストークンのcookieを検証するaxumのextractor
+// 期限切れ (cookieが消えている場合も含む) でリフレッシュトークンがあればTokenExpiredを返す
+impl<S: Send + Sync> FromRequestParts<S> for CurrentUser {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        match cookie(&parts.headers, ACCESS_COOKIE) {
+            Some(jwt) if !jwt.is_empty() => check_access_token(jwt).await,
+            _ if cookie(&parts.headers, REFRESH_COOKIE).is_some() => Err(AppError::TokenExpired),
+            _ => Err(AppError::auth("ログインしてください")),
+        }
+    }
+}
+
+impl CurrentUser {
+    pub fn is_moderator(&self) -> bool {
+        is_moderator(&self.name)
+    }
+}
+
+pub fn is_moderator(name: &str) -> bool {
+    config::get()
+        .moderation
+        .moderators
+        .iter()
+        .any(|m| m == name)
+}
+
+// モデレーターだけができる操作に使う
+pub async fn current_moderator() -> Result<CurrentUser, AppError> {
+    let user = current_user().await?;
+    if !user.is_moderator() {
+        return Err(AppError::auth("この操作はモデレーターしかできません"));
+    }
+    Ok(user)
+}
+
+// サーバー関数の中でログイン中のユーザーを取り出す
+// アクセストークンが切れていればリフレッシュしてcookieを更新する
+pub async fn current_user() -> Result<CurrentUser, AppError> {
+    let user: Result<CurrentUser, AppError> =
+        leptos_axum::extract().await.map_err(AppError::internal)?;
+    match user {
+        Err(AppError::TokenExpired) => {
+            let (headers, Extension(refreshed)): (HeaderMap, Extension<RefreshedUser>) =
+                leptos_axum::extract().await.map_err(AppError::internal)?;
+            // 同じリクエストで先にリフレッシュしていればその結果を使う
+            let mut refreshed = refreshed.0.lock().await;
+            if let Some(user) = refreshed.as_ref() {
+                return Ok(user.clone());
+            }
+            let token = cookie(&headers, REFRESH_COOKIE).ok_or_else(logged_out)?;
+            match refresh_session(token).await {
+                Ok(user) => Ok(refreshed.insert(user).clone()),
+                // セッションが無効になっていればcookieも消す (DBのエラーなどでは消さない)
+                Err(e @ AppError::Auth(_)) => {
+                    clear_session_cookies();
+                    Err(e)
+                }
+                Err(e) => Err(e),
+            }
+        }
+        user => user,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    const LIFETIME: i64 = 1000;
+
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        tokio::runtime::Runtime::new().unwrap().block_on(future)
+    }
+
+    // 時刻0に作ったセッションとそのリフレッシュトークン
+    async fn session(storage: &dyn Storage, id: &str, user_id: &str) -> String {
+        let secret = random_hex(32);
+        storage
+            .insert_session(Session {
+                id: id.to_string(),
+                user_id: user_id.to_string(),
+                name: "alice".to_string(),
+                refresh_hash: hash_secret(&secret),
+                expires_at: LIFETIME,
+                previous_hash: String::new(),
+                rotated_at: 0,
+            })
+            .await
+            .unwrap();
+        format!("{id}.{secret}")
+    }
+
+    fn is_logged_out<T>(result: Result<T, AppError>) -> bool {
+        matches!(result, Err(AppError::Auth(_)))
+    }
+
+    #[test]
+    fn refresh_rotates_the_token() {
+        block_on(async {
+            let storage = MemoryStorage::new();
+            let first = session(&storage, "a", "alice").await;
+            let (_, second) = rotate_refresh_token(&storage, &first, 10, LIFETIME)
+                .await
+                .unwrap();
+            let second = second.unwrap();
+            assert_ne!(first, second);
+            let found = storage.find_session("a").await.unwrap().unwrap();
+            assert_eq!(found.expires_at, 10 + LIFETIME);
+
+            // 新しいトークンでまた入れ替えられる
+            let (_, third) = rotate_refresh_token(&storage, &second, 20, LIFETIME)
+                .await
+                .unwrap();
+            assert!(third.is_some());
+            // 期限が切れたトークンは使えない
+            let third = third.unwrap();
+            assert!(is_logged_out(
+                rotate_refresh_token(&storage, &third, 20 + LIFETIME + 1, LIFETIME).await
+            ));
+        });
+    }
+
+    #[test]
+    fn previous_token_is_accepted_within_grace_period() {
+        block_on(async {
+            let storage = MemoryStorage::new();
+            let first = session(&storage, "a", "alice").await;
+            let (_, second) = rotate_refresh_token(&storage, &first, 10, LIFETIME)
+                .await
+                .unwrap();
+            // 同時に送られたリクエストは古いトークンのままでも通るが、入れ替えはしない
+            let (session, token) =
+                rotate_refresh_token(&storage, &first, 10 + REFRESH_GRACE_PERIOD, LIFETIME)
+                    .await
+                    .unwrap();
+            assert_eq!(session.id, "a");
+            assert_eq!(token, None);
+            let found = storage.find_session("a").await.unwrap().unwrap();
+            assert_eq!(
+                found.refresh_hash,
+                hash_secret(second.unwrap().split_once('.').unwrap().1)
+            );
+        });
+    }
+
+    #[test]
+    fn previous_token_after_grace_period_ends_the_session() {
+        block_on(async {
+            let storage = MemoryStorage::new();
+            let first = session(&storage, "a", "alice").await;
+            let (_, second) = rotate_refresh_token(&storage, &first, 10, LIFETIME)
+                .await
+                .unwrap();
+            assert!(is_logged_out(
+                rotate_refresh_token(&storage, &first, 11 + REFRESH_GRACE_PERIOD, LIFETIME).await
+            ));
+            // 盗まれたかもしれないので、新しいほうのトークンも使えなくなる
+            assert!(storage.find_session("a").await.unwrap().is_none());
+            assert!(is_logged_out(
+                rotate_refresh_token(&storage, &second.unwrap(), 12, LIFETIME).await
+            ));
+            // 知らないトークンも使えない
+            let unknown = format!("a.{}", random_hex(32));
+            assert!(is_logged_out(
+                rotate_refresh_token(&storage, &unknown, 12, LIFETIME).await
+            ));
+        });
+    }
+
+    #[test]
+    fn revoke_ends_one_or_every_session() {
+        block_on(async {
+            let storage = MemoryStorage::new();
+            let a = session(&storage, "a", "alice").await;
+            let b = session(&storage, "b", "alice").await;
+            let c = session(&storage, "c", "alice").await;
+            let d = session(&storage, "d", "bob").await;
+
+            revoke_session(&storage, &a, false, 10).await.unwrap();
+            assert!(is_logged_out(
+                rotate_refresh_token(&storage, &a, 10, LIFETIME).await
+            ));
+            assert!(storage.find_session("b").await.unwrap().is_some());
+            // 無効になったトークンでもう一度ログアウトしてもエラーにしない
+            revoke_session(&storage, &a, false, 10).await.unwrap();
+
+            revoke_session(&storage, &b, true, 10).await.unwrap();
+            assert!(is_logged_out(
+                rotate_refresh_token(&storage, &c, 10, LIFETIME).await
+            ));
+            let (session, _) = rotate_refresh_token(&storage, &d, 10, LIFETIME)
+                .await
+                .unwrap();
+            assert_eq!(session.user_id, "bob");
+        });
+    }
+}
@@ -0,0 +1,387 @@
+// タグの入力補完
+// すべてのタグと読みをメモリに持っておき、キーを押すたびに呼ばれても速く返せるようにする
+// 入力はそのまま文字列として比べるだけなので、正規表現などとして解釈されることはない
+// タグの親子もここから引く (検索のたびにDBから全部のタグを読まないように)
+
+use crate::{
+    query::normalize_tag,
+    server::TagSuggestion,
+    storage::{self, StorageError, Tag},
+};
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+use tokio::sync::RwLock;
+
+// ほかのサーバーで増えたタグも拾えるように、この時間ごとに読み直す
+const INDEX_TTL: Duration = Duration::from_secs(60);
+
+struct Entry {
+    tag: String,
+    count: i64,
+    // 別名なら正式なタグ (候補には正式なタグを出す)
+    alias_of: Option<String>,
+    parent: Option<String>,
+    // 比べる文字列 (タグそのもの・ひらがな・ローマ字、読みがあればそのひらがな・ローマ字も)
+    keys: Vec<String>,
+}
+
+struct Index {
+    entries: Vec<Entry>,
+    loaded_at: Instant,
+}
+
+static INDEX: RwLock<Option<Index>> = RwLock::const_new(None);
+
+fn entry(tag: Tag) -> Entry {
+    let mut keys = vec![tag.tag.clone()];
+    for text in [Some(tag.tag.as_str()), tag.reading.as_deref()]
+        .into_iter()
+        .flatten()
+    {
+        let hiragana = to_hiragana(text);
+        for key in [to_romaji(&hiragana), hiragana] {
+            if !keys.contains(&key) {
+                keys.push(key);
+            }
+        }
+    }
+    Entry {
+        tag: tag.tag,
+        count: tag.count,
+        alias_of: tag.alias_of,
+        parent: tag.parent,
+        keys,
+    }
+}
+
+async fn load() -> Result<(), StorageError> {
+    let fresh = INDEX
+        .read()
+        .await
+        .as_ref()
+        .is_some_and(|index| index.loaded_at.elapsed() < INDEX_TTL);
+    if fresh {
+        return Ok(());
+    }
+    let tags = storage::get().all_tags().await?;
+    *INDEX.write().await = Some(Index {
+        entries: tags.into_iter().map(entry).collect(),
+        loaded_at: Instant::now(),
+    });
+    Ok(())
+}
+
+// タグとその子孫のタグ
+pub async fn descendants(tag: &str) -> Result<Vec<String>, StorageError> {
+    load().await?;
+    let index = INDEX.read().await;
+    let entries = index
+        .as_ref()
+        .map(|index| index.entries.as_slice())
+        .unwrap_or_default();
+    let mut tags = vec![tag.to_string()];
+    let mut i = 0;
+    while i < tags.len() {
+        for e in entries {
+            if e.alias_of.is_none()
+                && e.parent.as_deref() == Some(tags[i].as_str())
+                && !tags.contains(&e.tag)
+            {
+                tags.push(e.tag.clone());
+            }
+        }
+        i += 1;
+    }
+    Ok(tags)
+}
+
+// 一番上の親からそのタグまで
+fn path(entries: &[Entry], tag: &str) -> Vec<String> {
+    let mut path = vec![tag.to_string()];
+    let mut current = tag;
+    while let Some(parent) = entries
+        .iter()
+        .find(|e| e.tag == current)
+        .and_then(|e| e.parent.as_deref())
+    {
+        // 親子が輪になっていても止まるように
+        if path.iter().any(|t| t == parent) {
+            break;
+        }
+        path.push(parent.to_string());
+        current = parent;
+    }
+    path.reverse();
+    path
+}
+
+// タグを統合したときなどに、次の入力で読み直させる
+pub async fn invalidate() {
+    *INDEX.write().await = None;
+}
+
+// 投稿に付いたタグを数える (読み直すまで待たずに候補に出す)
+pub async fn record(tags: &[String]) {
+    let mut index = INDEX.write().await;
+    let Some(index) = index.as_mut() else {
+        return;
+    };
+    for tag in tags {
+        match index.entries.iter_mut().find(|e| e.tag == *tag) {
+            Some(e) => e.count += 1,
+            None => index.entries.push(entry(Tag {
+                tag: tag.clone(),
+                count: 1,
+                alias_of: None,
+                parent: None,
+                reading: None,
+            })),
+        }
+    }
+}
+
+// 入力に合うタグを良い順に返す
+// recentはそのユーザーが最近使ったタグ (新しい順)
+pub async fn suggest(
+    input: &str,
+    recent: &[String],
+    limit: usize,
+) -> Result<Vec<TagSuggestion>, StorageError> {
+    let input = normalize_tag(input);
+    if input.is_empty() {
+        return Ok(vec![]);
+    }
+    let inputs = [input.clone(), to_hiragana(&input)];
+    load().await?;
+
+    let recent: HashMap<&str, usize> = recent
+        .iter()
+        .enumerate()
+        .map(|(i, t)| (t.as_str(), i))
+        .collect();
+    let index = INDEX.read().await;
+    let entries = index
+        .as_ref()
+        .map(|index| index.entries.as_slice())
+        .unwrap_or_default();
+    let counts: HashMap<&str, i64> = entries.iter().map(|e| (e.tag.as_str(), e.count)).collect();
+    let mut scored: Vec<(f64, &str, i64)> = entries
+        .iter()
+        .filter_map(|e| {
+            let matched = e
+                .keys
+                .iter()
+                .enumerate()
+                .flat_map(|(i, key)| inputs.iter().map(move |input| match_score(i, key, input)))
+                .fold(0.0, f64::max);
+            if matched == 0.0 {
+                return None;
+            }
+            let tag = e.alias_of.as_deref().unwrap_or(&e.tag);
+            let count = counts.get(tag).copied().unwrap_or(e.count);
+            // よく使われているほど、自分が最近使ったものほど上に出す
+            let popularity = 10.0 * (1.0 + count.max(0) as f64).ln();
+            let mine = recent
+                .get(tag)
+                .map_or(0.0, |i| 40.0 - 2.0 * (*i).min(10) as f64);
+            Some((matched + popularity + mine, tag, count))
+        })
+        .collect();
+    scored.sort_by(|a, b| {
+        b.0.total_cmp(&a.0)
+            .then_with(|| b.2.cmp(&a.2))
+            .then_with(|| a.1.cmp(b.1))
+    });
+    // 別名と正式なタグの両方に当たったら、点の高いほうだけ残す
+    let mut result: Vec<TagSuggestion> = vec![];
+    for (_, tag, count) in scored {
+        if result.len() >= limit {
+            break;
+        }
+        if !result.iter().any(|s| s.tag == tag) {
+            result.push(TagSuggestion {
+                tag: tag.to_string(),
+                count,
+                path: path(entries, tag),
+            });
+        }
+    }
+    Ok(result)
+}
+
+// key_indexが0ならタグそのもの、それ以外は読み
+fn match_score(key_index: usize, key: &str, input: &str) -> f64 {
+    if key == input {
+        100.0
+    } else if key.starts_with(input) {
+        if key_index == 0 {
+            60.0
+        } else {
+            50.0
+        }
+    } else if key_index == 0 && key.contains(input) {
+        15.0
+    } else {
+        0.0
+    }
+}
+
+// 読み
+
+// モデレーターが入力した読みをひらがなにそろえる (かな以外が入っていればNone)
+pub fn normalize_reading(text: &str) -> Option<String> {
+    let reading = to_hiragana(text.trim());
+    let is_kana = |c: char| matches!(c, '\u{3041}'..='\u{3096}' | 'ー');
+    (!reading.is_empty() && reading.chars().all(is_kana)).then_some(reading)
+}
+
+// カタカナをひらがなにする (長音はそのまま)
+fn to_hiragana(text: &str) -> String {
+    text.chars()
+        .map(|c| match c {
+            '\u{30A1}'..='\u{30F6}' => char::from_u32(c as u32 - 0x60).unwrap_or(c),
+            _ => c,
+        })
+        .collect()
+}
+
+// ひらがなをヘボン式に近いローマ字にする (かな以外はそのまま)
+fn to_romaji(text: &str) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    let mut out = String::new();
+    let mut double_next = false;
+    let mut i = 0;
+    while i < chars.len() {
+        // 拗音 (きゃ など) は2文字で1つ
+        let pair: String = chars[i..(i + 2).min(chars.len())].iter().collect();
+        let (romaji, used) = match kana_romaji(&pair) {
+            Some(r) if pair.chars().count() == 2 => (r, 2),
+            _ => match chars[i] {
+                'っ' => {
+                    double_next = true;
+                    i += 1;
+                    continue;
+                }
+                // 長音は前の母音をのばすだけなので読みからは外す
+                'ー' => ("", 1),
+                c => match kana_romaji(&c.to_string()) {
+                    Some(r) => (r, 1),
+                    None => {
+                        out.push(c);
+                        i += 1;
+                        double_next = false;
+                        continue;
+                    }
+                },
+            },
+        };
+        if double_next {
+            out.extend(romaji.chars().next());
+            double_next = false;
+        }
+        out.push_str(romaji);
+        i += used;
+    }
+    out
+}
+
+fn kana_romaji(kana: &str) -> Option<&'static str> {
+    #[rustfmt::skip]
+    const TABLE: &[(&str, &str)] = &[
+        ("きゃ", "kya"), ("きゅ", "kyu"), ("きょ", "kyo"),
+        ("しゃ", "sha"), ("しゅ", "shu"), ("しょ", "sho"), ("しぇ", "she"),
+        ("ちゃ", "cha"), ("ちゅ", "chu"), ("ちょ", "cho"), ("ちぇ", "che"),
+        ("にゃ", "nya"), ("にゅ", "nyu"), ("にょ", "nyo"),
+        ("ひゃ", "hya"), ("ひゅ", "hyu"), ("ひょ", "hyo"),
+        ("みゃ", "mya"), ("みゅ", "myu"), ("みょ", "myo"),
+        ("りゃ", "rya"), ("りゅ", "ryu"), ("りょ", "ryo"),
+        ("ぎゃ", "gya"), ("ぎゅ", "gyu"), ("ぎょ", "gyo"),
+        ("じゃ", "ja"), ("じゅ", "ju"), ("じょ", "jo"), ("じぇ", "je"),
+        ("びゃ", "bya"), ("びゅ", "byu"), ("びょ", "byo"),
+        ("ぴゃ", "pya"), ("ぴゅ", "pyu"), ("ぴょ", "pyo"),
+        ("てぃ", "ti"), ("でぃ", "di"), ("ふぁ", "fa"), ("ふぃ", "fi"), ("ふぇ", "fe"),
+        ("ふぉ", "fo"), ("うぃ", "wi"), ("うぇ", "we"), ("ゔぁ", "va"),
+        ("あ", "a"), ("い", "i"), ("う", "u"), ("え", "e"), ("お", "o"),
+        ("か", "ka"), ("き", "ki"), ("く", "ku"), ("け", "ke"), ("こ", "ko"),
+        ("さ", "sa"), ("し", "shi"), ("す", "su"), ("せ", "se"), ("そ", "so"),
+        ("た", "ta"), ("ち", "chi"), ("つ", "tsu"), ("て", "te"), ("と", "to"),
+        ("な", "na"), ("に", "ni"), ("ぬ", "nu"), ("ね", "ne"), ("の", "no"),
+        ("は", "ha"), ("ひ", "hi"), ("ふ", "fu"), ("へ", "he"), ("ほ", "ho"),
+        ("ま", "ma"), ("み", "mi"), ("む", "mu"), ("め", "me"), ("も", "mo"),
+        ("や", "ya"), ("ゆ", "yu"), ("よ", "yo"),
+        ("ら", "ra"), ("り", "ri"), ("る", "ru"), ("れ", "re"), ("ろ", "ro"),
+        ("わ", "wa"), ("を", "wo"), ("ん", "n"),
+        ("が", "ga"), ("ぎ", "gi"), ("ぐ", "gu"), ("げ", "ge"), ("ご", "go"),
+        ("ざ", "za"), ("じ", "ji"), ("ず", "zu"), ("ぜ", "ze"), ("ぞ", "zo"),
+        ("だ", "da"), ("ぢ", "ji"), ("づ", "zu"), ("で", "de"), ("ど", "do"),
+        ("ば", "ba"), ("び", "bi"), ("ぶ", "bu"), ("べ", "be"), ("ぼ", "bo"),
+        ("ぱ", "pa"), ("ぴ", "pi"), ("ぷ", "pu"), ("ぺ", "pe"), ("ぽ", "po"),
+        ("ぁ", "a"), ("ぃ", "i"), ("ぅ", "u"), ("ぇ", "e"), ("ぉ", "o"),
+        ("ゃ", "ya"), ("ゅ", "yu"), ("ょ", "yo"), ("ゔ", "vu"),
+    ];
+    TABLE.iter().find(|(k, _)| *k == kana).map(|(_, r)| *r)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tag(tag: &str, reading: Option<&str>) -> Tag {
+        Tag {
+            tag: tag.to_string(),
+            count: 0,
+            alias_of: None,
+            parent: None,
+            reading: reading.map(str::to_string),
+        }
+    }
+
+    fn matches(entry: &Entry, input: &str) -> bool {
+        let input = normalize_tag(input);
+        let inputs = [input.clone(), to_hiragana(&input)];
+        entry
+            .keys
+            .iter()
+            .enumerate()
+            .any(|(i, key)| inputs.iter().any(|input| match_score(i, key, input) > 0.0))
+    }
+
+    #[test]
+    fn kana_tags_match_romaji() {
+        let e = entry(tag("プログラミング", None));
+        assert_eq!(e.keys[0], "プログラミング");
+        assert!(matches(&e, "ぷろぐら"));
+        assert!(matches(&e, "puroguramingu"));
+        assert!(matches(&e, "プロ"));
+    }
+
+    #[test]
+    fn kanji_tags_match_their_reading() {
+        let e = entry(tag("料理", Some("リョウリ")));
+        assert!(matches(&e, "料理"));
+        assert!(matches(&e, "りょうり"));
+        assert!(matches(&e, "りょ"));
+        assert!(matches(&e, "ryouri"));
+        assert!(!matches(&e, "すし"));
+        assert!(!matches(&entry(tag("料理", None)), "りょうり"));
+    }
+
+    #[test]
+    fn romaji_handles_small_kana() {
+        assert_eq!(to_romaji("きっと"), "kitto");
+        assert_eq!(to_romaji("しゃしん"), "shashin");
+        assert_eq!(to_romaji("らーめん"), "ramen");
+        assert_eq!(to_romaji("c++"), "c++");
+    }
+
+    #[test]
+    fn normalize_reading_accepts_only_kana() {
+        assert_eq!(normalize_reading(" リョウリ ").as_deref(), Some("りょうり"));
+        assert_eq!(normalize_reading("らーめん").as_deref(), Some("らーめん"));
+        assert_eq!(normalize_reading("ryouri"), None);
+        assert_eq!(normalize_reading("料理"), None);
+        assert_eq!(normalize_reading(""), None);
+    }
+}
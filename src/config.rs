@@ -0,0 +1,438 @@
+// アプリの設定
+// TOMLファイル (既定は ./biestar.toml, BIESTAR_CONFIG で変更可) を読み、環境変数で上書きする
+// 起動時にmain.rsで一度だけ読み込んで検証する
+
+use serde::Deserialize;
+use std::{env, fmt, fs, io, str::FromStr, sync::OnceLock};
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    Mongo,
+    Sqlite,
+    Memory,
+}
+
+impl FromStr for Backend {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Backend, ()> {
+        match s {
+            "mongo" => Ok(Backend::Mongo),
+            "sqlite" => Ok(Backend::Sqlite),
+            "memory" => Ok(Backend::Memory),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    pub backend: Backend,
+    // MongoDBの接続URI (backend = "mongo" のとき必須)
+    pub uri: Option<String>,
+    pub database: String,
+    pub sqlite_path: String,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        StorageConfig {
+            backend: Backend::Mongo,
+            uri: None,
+            database: "biestar".to_string(),
+            sqlite_path: "./biestar.sqlite3".to_string(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    // 空ならDBのconfigに入っている鍵を使う
+    pub jwt_secret: String,
+    // アクセストークンの有効期間 (秒)
+    pub token_lifetime: i64,
+    // リフレッシュトークンの有効期間 (秒)
+    pub refresh_token_lifetime: i64,
+    // cookieにSecure属性を付けるか (httpで動かす開発環境ではfalseにする)
+    pub cookie_secure: bool,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        AuthConfig {
+            jwt_secret: String::new(),
+            token_lifetime: 60 * 15,
+            refresh_token_lifetime: 60 * 60 * 24 * 30,
+            cookie_secure: true,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct PasswordPolicy {
+    pub min_length: usize,
+}
+
+impl Default for PasswordPolicy {
+    fn default() -> Self {
+        PasswordPolicy { min_length: 8 }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct PageConfig {
+    // タイムラインの1ページの投稿の数 (スクロールすると次のページを読む)
+    pub posts: i64,
+    // タグ候補の最大数
+    pub tags: i64,
+}
+
+impl Default for PageConfig {
+    fn default() -> Self {
+        PageConfig {
+            posts: 20,
+            tags: 10,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct AttachmentConfig {
+    // アップロードされたファイルとサムネイルを置くディレクトリ
+    pub dir: String,
+    // 1つのファイルの最大サイズ (バイト)
+    pub max_size: usize,
+}
+
+impl Default for AttachmentConfig {
+    fn default() -> Self {
+        AttachmentConfig {
+            dir: "./attachments".to_string(),
+            max_size: 5 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct ModerationConfig {
+    // タグの統合などができるユーザーの名前
+    pub moderators: Vec<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub storage: StorageConfig,
+    pub auth: AuthConfig,
+    pub password: PasswordPolicy,
+    pub page: PageConfig,
+    pub attachment: AttachmentConfig,
+    pub moderation: ModerationConfig,
+}
+
+// エラー
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read {
+        path: String,
+        source: io::Error,
+    },
+    Parse {
+        path: String,
+        source: toml::de::Error,
+    },
+    Env {
+        var: &'static str,
+        value: String,
+    },
+    Invalid(Vec<String>),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read { path, source } => {
+                write!(f, "couldn't read config file {path}: {source}")
+            }
+            ConfigError::Parse { path, source } => {
+                write!(f, "couldn't parse config file {path}: {source}")
+            }
+            ConfigError::Env { var, value } => {
+                write!(f, "invalid value for environment variable {var}: {value:?}")
+            }
+            ConfigError::Invalid(problems) => {
+                write!(f, "invalid configuration:")?;
+                for p in problems {
+                    write!(f, "\n  - {p}")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+// 読み込み
+
+const DEFAULT_PATH: &str = "./biestar.toml";
+
+impl Config {
+    // ファイル → 環境変数の順に読み込んで検証する
+    pub fn load() -> Result<Config, ConfigError> {
+        let mut config = match env::var("BIESTAR_CONFIG") {
+            Ok(path) => Config::from_file(&path)?,
+            // 既定のパスはファイルがなくてもよい
+            Err(_) => match fs::metadata(DEFAULT_PATH) {
+                Ok(_) => Config::from_file(DEFAULT_PATH)?,
+                Err(_) => Config::default(),
+            },
+        };
+        config.apply_env()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn from_file(path: &str) -> Result<Config, ConfigError> {
+        let text = fs::read_to_string(path).map_err(|source| ConfigError::Read {
+            path: path.to_string(),
+            source,
+        })?;
+        toml::from_str(&text).map_err(|source| ConfigError::Parse {
+            path: path.to_string(),
+            source,
+        })
+    }
+
+    fn apply_env(&mut self) -> Result<(), ConfigError> {
+        override_with(&mut self.storage.backend, "BIESTAR_STORAGE")?;
+        if let Ok(uri) = env::var("BIESTAR_DB_URI") {
+            self.storage.uri = Some(uri);
+        }
+        override_with(&mut self.storage.database, "BIESTAR_DB_NAME")?;
+        override_with(&mut self.storage.sqlite_path, "BIESTAR_SQLITE_PATH")?;
+        override_with(&mut self.auth.jwt_secret, "BIESTAR_JWT_SECRET")?;
+        override_with(&mut self.auth.token_lifetime, "BIESTAR_TOKEN_LIFETIME")?;
+        override_with(
+            &mut self.auth.refresh_token_lifetime,
+            "BIESTAR_REFRESH_TOKEN_LIFETIME",
+        )?;
+        override_with(&mut self.auth.cookie_secure, "BIESTAR_COOKIE_SECURE")?;
+        override_with(&mut self.password.min_length, "BIESTAR_PASSWORD_MIN_LENGTH")?;
+        override_with(&mut self.page.posts, "BIESTAR_PAGE_POSTS")?;
+        override_with(&mut self.page.tags, "BIESTAR_PAGE_TAGS")?;
+        override_with(&mut self.attachment.dir, "BIESTAR_ATTACHMENT_DIR")?;
+        override_with(&mut self.attachment.max_size, "BIESTAR_ATTACHMENT_MAX_SIZE")?;
+        // カンマ区切り
+        if let Ok(names) = env::var("BIESTAR_MODERATORS") {
+            self.moderation.moderators = names
+                .split(',')
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+                .map(str::to_string)
+                .collect();
+        }
+        Ok(())
+    }
+
+    // おかしな値はまとめて報告する
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut problems = vec![];
+
+        match self.storage.backend {
+            Backend::Mongo => {
+                if self.storage.uri.as_deref().is_none_or(str::is_empty) {
+                    problems.push(
+                        "storage.uri (BIESTAR_DB_URI) is required when storage.backend is \"mongo\""
+                            .to_string(),
+                    );
+                }
+                if self.storage.database.is_empty() {
+                    problems
+                        .push("storage.database (BIESTAR_DB_NAME) must not be empty".to_string());
+                }
+            }
+            Backend::Sqlite => {
+                if self.storage.sqlite_path.is_empty() {
+                    problems.push(
+                        "storage.sqlite_path (BIESTAR_SQLITE_PATH) must not be empty".to_string(),
+                    );
+                }
+            }
+            Backend::Memory => {}
+        }
+
+        if self.auth.token_lifetime <= 0 {
+            problems
+                .push("auth.token_lifetime (BIESTAR_TOKEN_LIFETIME) must be positive".to_string());
+        }
+        if self.auth.refresh_token_lifetime < self.auth.token_lifetime {
+            problems.push(
+                "auth.refresh_token_lifetime (BIESTAR_REFRESH_TOKEN_LIFETIME) must not be \
+                 shorter than auth.token_lifetime"
+                    .to_string(),
+            );
+        }
+        if self.password.min_length == 0 {
+            problems.push(
+                "password.min_length (BIESTAR_PASSWORD_MIN_LENGTH) must be at least 1".to_string(),
+            );
+        }
+        if self.page.posts <= 0 {
+            problems.push("page.posts (BIESTAR_PAGE_POSTS) must be positive".to_string());
+        }
+        if self.page.tags <= 0 {
+            problems.push("page.tags (BIESTAR_PAGE_TAGS) must be positive".to_string());
+        }
+        if self.attachment.dir.is_empty() {
+            problems.push("attachment.dir (BIESTAR_ATTACHMENT_DIR) must not be empty".to_string());
+        }
+        if self.attachment.max_size == 0 {
+            problems.push(
+                "attachment.max_size (BIESTAR_ATTACHMENT_MAX_SIZE) must be positive".to_string(),
+            );
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError::Invalid(problems))
+        }
+    }
+}
+
+fn override_with<T: FromStr>(field: &mut T, var: &'static str) -> Result<(), ConfigError> {
+    if let Ok(value) = env::var(var) {
+        *field = value.parse().map_err(|_| ConfigError::Env { var, value })?;
+    }
+    Ok(())
+}
+
+// 起動後はどこからでも読めるようにしておく
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+pub fn init(config: Config) {
+    if CONFIG.set(config).is_err() {
+        panic!("config::init must only be called once");
+    }
+}
+
+pub fn get() -> &'static Config {
+    CONFIG
+        .get()
+        .expect("config::init must be called before serving requests")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn problems(config: &Config) -> Vec<String> {
+        match config.validate() {
+            Ok(()) => vec![],
+            Err(ConfigError::Invalid(problems)) => problems,
+            Err(e) => panic!("unexpected error: {e}"),
+        }
+    }
+
+    #[test]
+    fn default_memory_config_is_valid() {
+        let mut config = Config::default();
+        config.storage.backend = Backend::Memory;
+        assert!(problems(&config).is_empty());
+    }
+
+    #[test]
+    fn validate_reports_every_problem() {
+        // 既定は mongo なので uri がないことも報告される
+        let mut config = Config::default();
+        config.storage.database = String::new();
+        config.auth.token_lifetime = 0;
+        config.password.min_length = 0;
+        config.page.posts = 0;
+        config.page.tags = -1;
+        let problems = problems(&config);
+        assert_eq!(problems.len(), 6, "{problems:?}");
+        assert!(problems[0].starts_with("storage.uri"));
+        assert!(problems[1].starts_with("storage.database"));
+
+        let mut config = Config::default();
+        config.storage.backend = Backend::Sqlite;
+        config.storage.sqlite_path = String::new();
+        let problems = self::problems(&config);
+        assert_eq!(problems.len(), 1, "{problems:?}");
+        assert!(problems[0].starts_with("storage.sqlite_path"));
+    }
+
+    #[test]
+    fn file_rejects_unknown_fields() {
+        assert!(toml::from_str::<Config>("[page]\nposts = 5\ntag = 3").is_err());
+        let config: Config = toml::from_str("[page]\nposts = 5").unwrap();
+        assert_eq!(config.page.posts, 5);
+        assert_eq!(config.page.tags, 10);
+    }
+
+    // 環境変数はプロセス全体で共有されるので、読み書きするテストはこの1つにまとめる
+    #[test]
+    fn env_overrides_file() {
+        let mut config: Config = toml::from_str(
+            r#"
+            [storage]
+            backend = "mongo"
+            uri = "mongodb://file"
+            database = "file"
+
+            [page]
+            posts = 5
+            tags = 3
+            "#,
+        )
+        .unwrap();
+        env::set_var("BIESTAR_STORAGE", "sqlite");
+        env::set_var("BIESTAR_SQLITE_PATH", "/tmp/env.sqlite3");
+        env::set_var("BIESTAR_PAGE_POSTS", "50");
+        env::set_var("BIESTAR_TOKEN_LIFETIME", "60");
+        let result = config.apply_env();
+
+        // 不正な値はどの変数かを挙げてエラーにする
+        env::set_var("BIESTAR_PAGE_POSTS", "many");
+        let mut invalid = Config::default();
+        let error = invalid.apply_env();
+
+        for var in [
+            "BIESTAR_STORAGE",
+            "BIESTAR_SQLITE_PATH",
+            "BIESTAR_PAGE_POSTS",
+            "BIESTAR_TOKEN_LIFETIME",
+        ] {
+            env::remove_var(var);
+        }
+
+        result.unwrap();
+        assert_eq!(config.storage.backend, Backend::Sqlite);
+        assert_eq!(config.storage.sqlite_path, "/tmp/env.sqlite3");
+        assert_eq!(config.storage.uri.as_deref(), Some("mongodb://file"));
+        assert_eq!(config.storage.database, "file");
+        assert_eq!(config.page.posts, 50);
+        assert_eq!(config.page.tags, 3);
+        assert_eq!(config.auth.token_lifetime, 60);
+        assert!(problems(&config).is_empty());
+
+        match error {
+            Err(ConfigError::Env { var, value }) => {
+                assert_eq!(var, "BIESTAR_PAGE_POSTS");
+                assert_eq!(value, "many");
+            }
+            _ => panic!("BIESTAR_PAGE_POSTS=many must be rejected"),
+        }
+    }
+}
@@ -0,0 +1,178 @@
+// 2つの文章の行ごとの差分 (投稿の編集履歴で使う)
+// 最長共通部分列で、両方に残っている行とそれ以外を分ける
+// 表は行数の積の大きさになるので、先頭と末尾の同じ行を除いてから作り、それでも大きければ作らない
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DiffKind {
+    Same,
+    Added,
+    Removed,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DiffLine {
+    pub kind: DiffKind,
+    pub text: String,
+}
+
+// 表のマスの数の上限 (4バイトずつなので4MBくらい)
+// これより大きければ、変わった範囲を丸ごと削除して追加したことにする
+const TABLE_MAX: usize = 1_000_000;
+
+fn line(kind: DiffKind, text: &str) -> DiffLine {
+    DiffLine {
+        kind,
+        text: text.to_string(),
+    }
+}
+
+pub fn diff_lines(old: &str, new: &str) -> Vec<DiffLine> {
+    let old: Vec<&str> = old.lines().collect();
+    let new: Vec<&str> = new.lines().collect();
+    let prefix = old.iter().zip(&new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let (old_changed, new_changed) = (
+        &old[prefix..old.len() - suffix],
+        &new[prefix..new.len() - suffix],
+    );
+
+    let mut result: Vec<DiffLine> = old[..prefix]
+        .iter()
+        .map(|t| line(DiffKind::Same, t))
+        .collect();
+    if (old_changed.len() + 1).saturating_mul(new_changed.len() + 1) > TABLE_MAX {
+        result.extend(old_changed.iter().map(|t| line(DiffKind::Removed, t)));
+        result.extend(new_changed.iter().map(|t| line(DiffKind::Added, t)));
+    } else {
+        common_lines(old_changed, new_changed, &mut result);
+    }
+    result.extend(
+        old[old.len() - suffix..]
+            .iter()
+            .map(|t| line(DiffKind::Same, t)),
+    );
+    result
+}
+
+fn common_lines(old: &[&str], new: &[&str], result: &mut Vec<DiffLine>) {
+    // common[i][j] は old[i..] と new[j..] の共通部分列の長さ
+    let mut common = vec![vec![0u32; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            common[i][j] = if old[i] == new[j] {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() && j < new.len() {
+        if old[i] == new[j] {
+            result.push(line(DiffKind::Same, old[i]));
+            i += 1;
+            j += 1;
+        } else if common[i + 1][j] >= common[i][j + 1] {
+            result.push(line(DiffKind::Removed, old[i]));
+            i += 1;
+        } else {
+            result.push(line(DiffKind::Added, new[j]));
+            j += 1;
+        }
+    }
+    result.extend(old[i..].iter().map(|t| line(DiffKind::Removed, t)));
+    result.extend(new[j..].iter().map(|t| line(DiffKind::Added, t)));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use DiffKind::*;
+
+    fn diff(old: &str, new: &str) -> Vec<(DiffKind, String)> {
+        diff_lines(old, new)
+            .into_iter()
+            .map(|l| (l.kind, l.text))
+            .collect()
+    }
+
+    fn lines(expected: &[(DiffKind, &str)]) -> Vec<(DiffKind, String)> {
+        expected.iter().map(|(k, t)| (*k, t.to_string())).collect()
+    }
+
+    #[test]
+    fn same_text_has_no_changes() {
+        assert_eq!(diff("a\nb", "a\nb"), lines(&[(Same, "a"), (Same, "b")]));
+    }
+
+    #[test]
+    fn empty_input() {
+        assert_eq!(diff("", ""), lines(&[]));
+        assert_eq!(diff("", "a\nb"), lines(&[(Added, "a"), (Added, "b")]));
+        assert_eq!(diff("a\nb", ""), lines(&[(Removed, "a"), (Removed, "b")]));
+    }
+
+    #[test]
+    fn inserted_lines() {
+        assert_eq!(
+            diff("a\nc", "a\nb\nc"),
+            lines(&[(Same, "a"), (Added, "b"), (Same, "c")])
+        );
+        assert_eq!(
+            diff("b", "a\nb\nc"),
+            lines(&[(Added, "a"), (Same, "b"), (Added, "c")])
+        );
+    }
+
+    #[test]
+    fn deleted_lines() {
+        assert_eq!(
+            diff("a\nb\nc", "a\nc"),
+            lines(&[(Same, "a"), (Removed, "b"), (Same, "c")])
+        );
+        assert_eq!(
+            diff("a\nb\nc", "b"),
+            lines(&[(Removed, "a"), (Same, "b"), (Removed, "c")])
+        );
+    }
+
+    #[test]
+    fn replaced_lines() {
+        assert_eq!(
+            diff("a\nb\nc", "a\nx\nc"),
+            lines(&[(Same, "a"), (Removed, "b"), (Added, "x"), (Same, "c")])
+        );
+        assert_eq!(
+            diff("a\nb\nc\nd", "a\nx\nc\ny"),
+            lines(&[
+                (Same, "a"),
+                (Removed, "b"),
+                (Added, "x"),
+                (Same, "c"),
+                (Removed, "d"),
+                (Added, "y"),
+            ])
+        );
+    }
+
+    #[test]
+    fn large_changes_fall_back_to_remove_and_add() {
+        let old: Vec<String> = (0..2000).map(|i| format!("old {i}")).collect();
+        let new: Vec<String> = (0..2000).map(|i| format!("new {i}")).collect();
+        let old = format!("head\n{}\nsame\ntail", old.join("\n"));
+        let new = format!("head\n{}\nsame\ntail", new.join("\n"));
+        let result = diff_lines(&old, &new);
+        assert_eq!(result.len(), 4003);
+        assert_eq!(result[0], line(Same, "head"));
+        assert!(result[1..2001].iter().all(|l| l.kind == Removed));
+        assert!(result[2001..4001].iter().all(|l| l.kind == Added));
+        assert_eq!(result[4001], line(Same, "same"));
+        assert_eq!(result[4002], line(Same, "tail"));
+    }
+}
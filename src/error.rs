@@ -0,0 +1,113 @@
+// アプリ全体で使うエラー
+// すべての#[server]関数はこれを返し、クライアント側ではそのまま日本語のメッセージとして表示する
+
+use leptos::server_fn::{
+    codec::JsonEncoding,
+    error::{FromServerFnError, ServerFnErrorErr},
+};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AppError {
+    // 入力がおかしい (メッセージはそのまま表示する)
+    Validation(String),
+    // ログインしていない・権限がない
+    Auth(String),
+    // アクセストークンの期限切れ (リフレッシュすれば続けられる)
+    TokenExpired,
+    NotFound(String),
+    // 名前の重複など
+    Conflict(String),
+    // DBのエラー (詳細はサーバーのログにだけ出す)
+    Storage,
+    // サーバーに届かなかった
+    Network,
+    // その他のサーバー内部のエラー
+    Internal(String),
+}
+
+impl AppError {
+    pub fn validation(message: impl Into<String>) -> AppError {
+        AppError::Validation(message.into())
+    }
+
+    pub fn auth(message: impl Into<String>) -> AppError {
+        AppError::Auth(message.into())
+    }
+
+    pub fn not_found(message: impl Into<String>) -> AppError {
+        AppError::NotFound(message.into())
+    }
+
+    pub fn conflict(message: impl Into<String>) -> AppError {
+        AppError::Conflict(message.into())
+    }
+
+    // サーバー内部のエラーはログに残して、クライアントには詳細を返さない
+    #[cfg(feature = "ssr")]
+    pub fn internal(e: impl fmt::Display) -> AppError {
+        leptos::logging::error!("internal error: {e}");
+        AppError::Internal(String::new())
+    }
+}
+
+// UIに出すメッセージ
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AppError::Validation(m)
+            | AppError::Auth(m)
+            | AppError::NotFound(m)
+            | AppError::Conflict(m) => write!(f, "{m}"),
+            AppError::TokenExpired => write!(f, "ログインの有効期限が切れました"),
+            AppError::Storage => {
+                write!(
+                    f,
+                    "データベースでエラーが発生しました。時間をおいてもう一度お試しください"
+                )
+            }
+            AppError::Network => write!(f, "サーバーに接続できませんでした"),
+            AppError::Internal(_) => write!(f, "サーバーでエラーが発生しました"),
+        }
+    }
+}
+
+impl std::error::Error for AppError {}
+
+impl FromServerFnError for AppError {
+    type Encoder = JsonEncoding;
+
+    fn from_server_fn_error(value: ServerFnErrorErr) -> Self {
+        match value {
+            ServerFnErrorErr::Request(_) => AppError::Network,
+            other => AppError::Internal(other.to_string()),
+        }
+    }
+}
+
+#[cfg(feature = "ssr")]
+impl From<crate::storage::StorageError> for AppError {
+    fn from(e: crate::storage::StorageError) -> Self {
+        leptos::logging::error!("{e}");
+        AppError::Storage
+    }
+}
+
+// 普通のaxumのハンドラから返すとき用
+#[cfg(feature = "ssr")]
+impl axum::response::IntoResponse for AppError {
+    fn into_response(self) -> axum::response::Response {
+        use axum::http::StatusCode;
+        let status = match self {
+            AppError::Validation(_) => StatusCode::BAD_REQUEST,
+            AppError::Auth(_) | AppError::TokenExpired => StatusCode::UNAUTHORIZED,
+            AppError::NotFound(_) => StatusCode::NOT_FOUND,
+            AppError::Conflict(_) => StatusCode::CONFLICT,
+            AppError::Storage | AppError::Network | AppError::Internal(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        };
+        (status, axum::Json(self)).into_response()
+    }
+}
@@ -1,5 +1,22 @@
 pub mod app;
+#[cfg(feature = "ssr")]
+pub mod attachment;
+#[cfg(feature = "ssr")]
+pub mod auth;
+#[cfg(feature = "ssr")]
+pub mod autocomplete;
+#[cfg(feature = "ssr")]
+pub mod config;
+pub mod diff;
+pub mod error;
+#[cfg(feature = "ssr")]
+pub mod markdown;
+#[cfg(feature = "ssr")]
+pub mod search;
+pub mod query;
 pub mod server;
+#[cfg(feature = "ssr")]
+pub mod storage;
 
 #[cfg(feature = "hydrate")]
 #[wasm_bindgen::prelude::wasm_bindgen]
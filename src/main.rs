@@ -1,12 +1,38 @@
+#![recursion_limit = "256"]
 
 #[cfg(feature = "ssr")]
 #[tokio::main]
 async fn main() {
-    use axum::Router;
+    use axum::{
+        extract::DefaultBodyLimit,
+        routing::{get, post},
+        Router,
+    };
     use leptos::logging::log;
     use leptos::prelude::*;
     use leptos_axum::{generate_route_list, LeptosRoutes};
     use pre_07::app::*;
+    use pre_07::{attachment, auth, config, storage};
+
+    // 設定の読み込み (おかしければ理由を出して終了する)
+    let mut config = config::Config::load().unwrap_or_else(|e| exit_with(e));
+    log!("storage backend: {:?}", config.storage.backend);
+    storage::init(&config.storage)
+        .await
+        .unwrap_or_else(|e| exit_with(e));
+    if config.auth.jwt_secret.is_empty() {
+        match storage::get().setting().await {
+            Ok(Some(setting)) => config.auth.jwt_secret = setting.jwt,
+            Ok(None) => exit_with(
+                "JWT secret is not configured: set auth.jwt_secret (BIESTAR_JWT_SECRET) \
+                 or add a document to the config collection",
+            ),
+            Err(e) => exit_with(e),
+        }
+    }
+    // ファイルの上限ちょうどでもDefaultBodyLimitで切られないように少し余裕を持たせる
+    let upload_limit = config.attachment.max_size + 1024;
+    config::init(config);
 
     let conf = get_configuration(None).unwrap();
     let addr = conf.leptos_options.site_addr;
@@ -15,11 +41,18 @@ async fn main() {
     let routes = generate_route_list(App);
 
     let app = Router::new()
+        .route(
+            "/api/attachments",
+            post(attachment::upload).layer(DefaultBodyLimit::max(upload_limit)),
+        )
+        .route("/attachments/{id}", get(attachment::serve))
+        .route("/attachments/{id}/thumbnail", get(attachment::thumbnail))
         .leptos_routes(&leptos_options, routes, {
             let leptos_options = leptos_options.clone();
             move || shell(leptos_options.clone())
         })
         .fallback(leptos_axum::file_and_error_handler(shell))
+        .layer(axum::middleware::from_fn(auth::with_refreshed_user))
         .with_state(leptos_options);
 
     // run our app with hyper
@@ -31,6 +64,12 @@ async fn main() {
         .unwrap();
 }
 
+#[cfg(feature = "ssr")]
+fn exit_with(e: impl std::fmt::Display) -> ! {
+    eprintln!("biestar: {e}");
+    std::process::exit(1)
+}
+
 #[cfg(not(feature = "ssr"))]
 pub fn main() {
     // no client-side main function
@@ -0,0 +1,213 @@
+// 投稿・返信の本文 (Markdown) をHTMLにする
+// 本文に書かれたHTMLはタグとして扱わずに文字として出し、最後にammoniaで許したタグと属性だけ残す
+// コードブロックは言語が分かればsyntectで色分けする (色はCSSの .hl-* で付ける)
+
+use pulldown_cmark::{html, CodeBlockKind, CowStr, Event, Options, Parser, Tag, TagEnd};
+use std::{collections::HashSet, sync::LazyLock};
+use syntect::{
+    html::{ClassStyle, ClassedHTMLGenerator},
+    parsing::SyntaxSet,
+    util::LinesWithEndings,
+};
+
+static SYNTAXES: LazyLock<SyntaxSet> = LazyLock::new(SyntaxSet::load_defaults_newlines);
+
+static CLEANER: LazyLock<ammonia::Builder<'static>> = LazyLock::new(|| {
+    let mut cleaner = ammonia::Builder::default();
+    cleaner
+        .add_tag_attributes("span", &["class"])
+        .add_tag_attributes("code", &["class"])
+        .url_schemes(HashSet::from(["http", "https", "mailto"]))
+        .link_rel(Some("nofollow noopener noreferrer"));
+    cleaner
+});
+
+pub fn render(text: &str) -> String {
+    let options = Options::ENABLE_TABLES | Options::ENABLE_STRIKETHROUGH;
+    let mut events = vec![];
+    // コードブロックの中身 (言語と書いてあるコード)
+    let mut code: Option<(String, String)> = None;
+    for event in Parser::new_ext(text, options) {
+        match (event, &mut code) {
+            (Event::Start(Tag::CodeBlock(kind)), _) => {
+                let lang = match kind {
+                    CodeBlockKind::Fenced(info) => info
+                        .split_whitespace()
+                        .next()
+                        .unwrap_or_default()
+                        .to_string(),
+                    CodeBlockKind::Indented => String::new(),
+                };
+                code = Some((lang, String::new()));
+            }
+            (Event::End(TagEnd::CodeBlock), Some((lang, text))) => {
+                events.push(Event::Html(CowStr::from(code_block(lang, text))));
+                code = None;
+            }
+            (Event::Text(t), Some((_, text))) => text.push_str(&t),
+            // 書かれたHTMLはそのまま文字として出す
+            (Event::Html(t) | Event::InlineHtml(t), _) => events.push(Event::Text(t)),
+            // これまで本文の改行はそのまま出していたので、Markdownでも改行にする
+            (Event::SoftBreak, _) => events.push(Event::HardBreak),
+            (event, _) => events.push(event),
+        }
+    }
+    let mut out = String::new();
+    html::push_html(&mut out, events.into_iter());
+    CLEANER.clean(&out).to_string()
+}
+
+fn code_block(lang: &str, code: &str) -> String {
+    let syntax = Some(lang)
+        .filter(|l| !l.is_empty())
+        .and_then(|l| SYNTAXES.find_syntax_by_token(l));
+    let Some(syntax) = syntax else {
+        return format!("<pre><code>{}</code></pre>\n", escape(code));
+    };
+    let mut generator = ClassedHTMLGenerator::new_with_class_style(
+        syntax,
+        &SYNTAXES,
+        ClassStyle::SpacedPrefixed { prefix: "hl-" },
+    );
+    for line in LinesWithEndings::from(code) {
+        if generator
+            .parse_html_for_line_which_includes_newline(line)
+            .is_err()
+        {
+            return format!("<pre><code>{}</code></pre>\n", escape(code));
+        }
+    }
+    format!(
+        "<pre><code class=\"language-{}\">{}</code></pre>\n",
+        escape(lang),
+        generator.finalize()
+    )
+}
+
+fn escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 出力のタグに危ないものが残っていないか (中身の文字として出ているものはよい)
+    fn assert_safe(html: &str) {
+        for tag in html.split('<').skip(1) {
+            let tag = tag.split('>').next().unwrap_or_default().to_lowercase();
+            let name = tag.split_whitespace().next().unwrap_or_default();
+            assert!(!["script", "style", "iframe"].contains(&name), "{html}");
+            for attr in tag.split_whitespace().skip(1) {
+                assert!(!attr.starts_with("on"), "{html}");
+                assert!(!attr.starts_with("style"), "{html}");
+                assert!(!attr.contains("javascript:"), "{html}");
+                assert!(!attr.contains("data:"), "{html}");
+            }
+        }
+    }
+
+    #[test]
+    fn script_blocks_are_text() {
+        let html = render("<script>alert(1)</script>");
+        assert_eq!(html, "&lt;script&gt;alert(1)&lt;/script&gt;");
+        assert_safe(&html);
+    }
+
+    #[test]
+    fn inline_html_is_text() {
+        let html = render("a <b onclick=\"x()\">b</b> <img src=x onerror=alert(1)>");
+        assert_eq!(
+            html,
+            "<p>a &lt;b onclick=\"x()\"&gt;b&lt;/b&gt; &lt;img src=x onerror=alert(1)&gt;</p>\n"
+        );
+        assert_safe(&html);
+        let html = render("<div style=\"x\" onmouseover=\"y\">z</div>\n\ntext");
+        assert!(html.starts_with("&lt;div style="), "{html}");
+        assert_safe(&html);
+    }
+
+    #[test]
+    fn dangerous_link_urls_are_dropped() {
+        let html = render(
+            "[x](javascript:alert(1)) [y](JaVaScRiPt:alert(1)) \
+             [z](data:text/html;base64,PHNjcmlwdD4=)",
+        );
+        assert_eq!(
+            html,
+            "<p><a rel=\"nofollow noopener noreferrer\">x</a> \
+             <a rel=\"nofollow noopener noreferrer\">y</a> \
+             <a rel=\"nofollow noopener noreferrer\">z</a></p>\n"
+        );
+        assert_safe(&html);
+    }
+
+    #[test]
+    fn dangerous_image_urls_are_dropped() {
+        let html = render(
+            "![i](javascript:alert(1)) ![j](data:image/png;base64,AAAA) \
+             ![k](https://example.com/a.png)",
+        );
+        assert_eq!(
+            html,
+            "<p><img alt=\"i\"> <img alt=\"j\"> \
+             <img src=\"https://example.com/a.png\" alt=\"k\"></p>\n"
+        );
+        assert_safe(&html);
+    }
+
+    #[test]
+    fn safe_links_are_kept() {
+        let html = render("[ok](https://example.com \"\\\" onclick=\\\"x\")");
+        assert_eq!(
+            html,
+            "<p><a href=\"https://example.com\" title=\"&quot; onclick=&quot;x\" \
+             rel=\"nofollow noopener noreferrer\">ok</a></p>\n"
+        );
+    }
+
+    #[test]
+    fn event_attributes_are_removed_by_the_cleaner() {
+        let html = CLEANER
+            .clean("<a href=\"https://example.com\" onclick=\"x()\">a</a><span onmouseover=\"y\">b</span>")
+            .to_string();
+        assert_eq!(
+            html,
+            "<a href=\"https://example.com\" rel=\"nofollow noopener noreferrer\">a</a><span>b</span>"
+        );
+    }
+
+    #[test]
+    fn code_fence_language_cannot_break_out() {
+        let html = render("```rust\" onmouseover=\"alert(1)\nfn main() {}\n```");
+        assert_eq!(html, "<pre><code>fn main() {}\n</code></pre>\n");
+        let html = render("```js\"><script>alert(1)</script>\nx\n```");
+        assert_eq!(html, "<pre><code>x\n</code></pre>\n");
+        assert_safe(&html);
+        // 言語名は分かるものしか属性に入らないが、入れるときもエスケープする
+        assert!(code_block("rust", "a").starts_with("<pre><code class=\"language-rust\">"));
+        assert_eq!(escape("a\"<b>&"), "a&quot;&lt;b&gt;&amp;");
+    }
+
+    #[test]
+    fn code_is_escaped_and_highlighted() {
+        let html = render("```rust\nlet a = \"<script>\";\n```");
+        assert!(
+            html.starts_with("<pre><code class=\"language-rust\">"),
+            "{html}"
+        );
+        assert!(html.contains("<span class=\"hl-"), "{html}");
+        assert!(html.contains("&lt;script&gt;"), "{html}");
+        assert_safe(&html);
+    }
+}
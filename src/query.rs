@@ -0,0 +1,404 @@
+// 投稿の検索条件
+// 検索欄の文字列 (例: "tag:rust -tag:web author:foo 所有権") とPostQueryを相互に変換する
+//
+//   tag:a        タグaかその子孫のタグが付いている (何個でも書ける、すべて満たす)
+//   tag:a|b      aかbのどちらかが付いている (tag:a|b tag:c|d は「aかb」かつ「cかd」)
+//   #a           tag:a と同じ
+//   -tag:a       タグaが付いていない (-#a でもよい)
+//   author:名前  その人の投稿
+//   level:beginner / level:intermediate / level:advanced (初心者 / 経験あり / 経験者 でもよい)
+//   since:2026-01-01 until:2026-01-31  投稿日 (日本時間、until の日も含む)
+//   is:answered / is:unanswered  返信があるか
+//   is:following  フォローしているタグの投稿を先に出す
+//   sort:new / sort:replies / sort:active
+//   それ以外の言葉は全文検索のキーワード
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ReplyFilter {
+    #[default]
+    Any,
+    Answered,
+    Unanswered,
+}
+
+// 経験の度合い (投稿のバッジと、プロフィールでタグごとに設定するもの)
+// 並びは経験の少ない順
+#[derive(
+    Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum ExperienceLevel {
+    #[default]
+    Beginner,
+    Intermediate,
+    Advanced,
+}
+
+impl ExperienceLevel {
+    pub const ALL: [ExperienceLevel; 3] = [
+        ExperienceLevel::Beginner,
+        ExperienceLevel::Intermediate,
+        ExperienceLevel::Advanced,
+    ];
+
+    // 検索の level: とSQLiteに入れる値
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ExperienceLevel::Beginner => "beginner",
+            ExperienceLevel::Intermediate => "intermediate",
+            ExperienceLevel::Advanced => "advanced",
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            ExperienceLevel::Beginner => "初心者",
+            ExperienceLevel::Intermediate => "経験あり",
+            ExperienceLevel::Advanced => "経験者",
+        }
+    }
+
+    pub fn parse(text: &str) -> Option<ExperienceLevel> {
+        match text {
+            "beginner" | "初心者" => Some(ExperienceLevel::Beginner),
+            "intermediate" | "経験あり" => Some(ExperienceLevel::Intermediate),
+            "advanced" | "経験者" => Some(ExperienceLevel::Advanced),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PostSort {
+    // 新しい順
+    #[default]
+    Newest,
+    // 返信が多い順
+    MostReplied,
+    // 最後に投稿か返信があった時間の新しい順
+    MostActive,
+}
+
+// 空のVecはフォームの引数に出てこないので、ない項目は既定値にする
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct PostQuery {
+    // すべて付いている
+    pub all_tags: Vec<String>,
+    // どのグループからもどれかが付いている (tag:a|b の1つが1グループ)
+    pub any_tags: Vec<Vec<String>>,
+    // どれも付いていない
+    pub not_tags: Vec<String>,
+    // その経験の度合いの投稿だけ
+    pub level: Option<ExperienceLevel>,
+    // 投稿者の名前
+    pub author: Option<String>,
+    // "YYYY-MM-DD"
+    pub since: Option<String>,
+    pub until: Option<String>,
+    pub replies: ReplyFilter,
+    // Noneなら、キーワードがあれば当たりの良い順、なければ新しい順
+    pub sort: Option<PostSort>,
+    // trueならフォローしているタグの投稿を先に出す
+    pub following: bool,
+    // 全文検索のキーワード
+    pub text: String,
+}
+
+impl PostQuery {
+    pub fn parse(input: &str) -> PostQuery {
+        let mut query = PostQuery::default();
+        let mut text = vec![];
+        for word in input.split_whitespace() {
+            let (negate, body) = match word.strip_prefix('-') {
+                Some(body) if body.contains(':') || body.starts_with('#') => (true, body),
+                _ => (false, word),
+            };
+            if let Some(tag) = body.strip_prefix('#').filter(|t| !t.is_empty()) {
+                push_tags(&mut query, negate, tag);
+                continue;
+            }
+            let Some((key, value)) = body.split_once(':').filter(|(_, v)| !v.is_empty()) else {
+                text.push(word);
+                continue;
+            };
+            match (key, value) {
+                ("tag", tags) => push_tags(&mut query, negate, tags),
+                ("author", name) => query.author = Some(name.to_string()),
+                ("level", level) if ExperienceLevel::parse(level).is_some() => {
+                    query.level = ExperienceLevel::parse(level)
+                }
+                ("since", date) => query.since = Some(date.to_string()),
+                ("until", date) => query.until = Some(date.to_string()),
+                ("is", "answered") => query.replies = ReplyFilter::Answered,
+                ("is", "unanswered") => query.replies = ReplyFilter::Unanswered,
+                ("is", "following") => query.following = true,
+                ("sort", "new") => query.sort = Some(PostSort::Newest),
+                ("sort", "replies") => query.sort = Some(PostSort::MostReplied),
+                ("sort", "active") => query.sort = Some(PostSort::MostActive),
+                // 知らない指定はキーワードとして扱う
+                _ => text.push(word),
+            }
+        }
+        query.text = text.join(" ");
+        query
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == PostQuery::default()
+    }
+}
+
+fn push_tags(query: &mut PostQuery, negate: bool, tags: &str) {
+    let tags: Vec<String> = tags
+        .split('|')
+        .filter(|t| !t.is_empty())
+        .map(str::to_string)
+        .collect();
+    if negate {
+        query.not_tags.extend(tags);
+    } else if tags.len() > 1 {
+        query.any_tags.push(tags);
+    } else {
+        query.all_tags.extend(tags);
+    }
+}
+
+// 検索欄に戻すときの文字列
+impl fmt::Display for PostQuery {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut words: Vec<String> = vec![];
+        words.extend(self.all_tags.iter().map(|t| format!("tag:{t}")));
+        words.extend(self.any_tags.iter().map(|g| format!("tag:{}", g.join("|"))));
+        words.extend(self.not_tags.iter().map(|t| format!("-tag:{t}")));
+        if let Some(author) = &self.author {
+            words.push(format!("author:{author}"));
+        }
+        if let Some(level) = self.level {
+            words.push(format!("level:{}", level.as_str()));
+        }
+        if let Some(since) = &self.since {
+            words.push(format!("since:{since}"));
+        }
+        if let Some(until) = &self.until {
+            words.push(format!("until:{until}"));
+        }
+        match self.replies {
+            ReplyFilter::Any => {}
+            ReplyFilter::Answered => words.push("is:answered".to_string()),
+            ReplyFilter::Unanswered => words.push("is:unanswered".to_string()),
+        }
+        match self.sort {
+            Some(PostSort::Newest) => words.push("sort:new".to_string()),
+            Some(PostSort::MostReplied) => words.push("sort:replies".to_string()),
+            Some(PostSort::MostActive) => words.push("sort:active".to_string()),
+            None => {}
+        }
+        if self.following {
+            words.push("is:following".to_string());
+        }
+        if !self.text.is_empty() {
+            words.push(self.text.clone());
+        }
+        write!(f, "{}", words.join(" "))
+    }
+}
+
+// タグ
+
+// 全角英数字を半角に、大文字を小文字にそろえる
+pub fn fold_char(c: char) -> char {
+    let c = match c {
+        '\u{FF01}'..='\u{FF5E}' => char::from_u32(c as u32 - 0xFEE0).unwrap_or(c),
+        '\u{3000}' => ' ',
+        _ => c,
+    };
+    c.to_lowercase().next().unwrap_or(c)
+}
+
+// タグの表記をそろえる ("＃ Rust " も "rust" にする)
+// 検索欄では空白で区切るので、タグの中の空白は "_" にする
+pub fn normalize_tag(tag: &str) -> String {
+    let tag: String = tag.chars().map(fold_char).collect();
+    let tag = tag.trim().trim_start_matches('#').trim();
+    tag.split_whitespace().collect::<Vec<_>>().join("_")
+}
+
+// 日付
+
+const JST: i64 = 9 * 60 * 60;
+
+// "YYYY-MM-DD" を日本時間のその日の0時のUNIX時間 (秒) にする
+pub fn parse_date(date: &str) -> Option<i64> {
+    let mut parts = date.splitn(3, '-');
+    let year: i64 = parts.next()?.parse().ok()?;
+    let month: i64 = parts.next()?.parse().ok()?;
+    let day: i64 = parts.next()?.parse().ok()?;
+    // 大きすぎる年は下の計算があふれるので、4桁までにする
+    if !(1..=9999).contains(&year)
+        || !(1..=12).contains(&month)
+        || !(1..=days_in_month(year, month)).contains(&day)
+    {
+        return None;
+    }
+    // 年月日を1970-01-01からの日数に直す
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+    Some(days * 86400 - JST)
+}
+
+fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+// UNIX時間 (秒) を日本時間の "YYYY/MM/DD HH:MM" にする
+pub fn format_time(secs: i64) -> String {
+    if secs <= 0 {
+        return String::new();
+    }
+    let secs = secs + JST;
+    let (days, rest) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+    // 1970-01-01からの日数を年月日に直す
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year}/{month:02}/{day:02} {:02}:{:02}",
+        rest / 3600,
+        rest % 3600 / 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tags(tags: &[&str]) -> Vec<String> {
+        tags.iter().map(|t| t.to_string()).collect()
+    }
+
+    #[test]
+    fn parse_reads_every_filter() {
+        let query = PostQuery::parse(
+            "tag:rust #web tag:go|zig -tag:java -#php author:bob level:経験者 \
+             since:2026-01-01 until:2026-01-31 is:unanswered is:following sort:active 所有権",
+        );
+        assert_eq!(query.all_tags, tags(&["rust", "web"]));
+        assert_eq!(query.any_tags, vec![tags(&["go", "zig"])]);
+        assert_eq!(query.not_tags, tags(&["java", "php"]));
+        assert_eq!(query.author.as_deref(), Some("bob"));
+        assert_eq!(query.level, Some(ExperienceLevel::Advanced));
+        assert_eq!(query.since.as_deref(), Some("2026-01-01"));
+        assert_eq!(query.until.as_deref(), Some("2026-01-31"));
+        assert_eq!(query.replies, ReplyFilter::Unanswered);
+        assert!(query.following);
+        assert_eq!(query.sort, Some(PostSort::MostActive));
+        assert_eq!(query.text, "所有権");
+    }
+
+    #[test]
+    fn parse_keeps_each_or_group() {
+        let query = PostQuery::parse("tag:a|b tag:c|d #e tag:f|");
+        assert_eq!(query.any_tags, vec![tags(&["a", "b"]), tags(&["c", "d"])]);
+        assert_eq!(query.all_tags, tags(&["e", "f"]));
+        assert_eq!(query.to_string(), "tag:e tag:f tag:a|b tag:c|d");
+    }
+
+    #[test]
+    fn parse_keeps_unknown_words_as_text() {
+        let query = PostQuery::parse("level:expert sort:old tag: -x foo:bar - # c++");
+        assert_eq!(query.level, None);
+        assert_eq!(query.sort, None);
+        assert!(query.all_tags.is_empty() && query.not_tags.is_empty());
+        assert_eq!(query.text, "level:expert sort:old tag: -x foo:bar - # c++");
+        assert!(PostQuery::parse("  ").is_empty());
+    }
+
+    #[test]
+    fn display_round_trips() {
+        let input = "tag:rust tag:go|zig tag:vim|emacs -tag:java author:bob level:intermediate \
+                     since:2026-01-01 until:2026-01-31 is:answered sort:replies is:following 所有権";
+        let query = PostQuery::parse(input);
+        assert_eq!(PostQuery::parse(&query.to_string()), query);
+        let negated = PostQuery::parse("-#php");
+        assert_eq!(negated.to_string(), "-tag:php");
+    }
+
+    #[test]
+    fn normalize_tag_folds_width_and_case() {
+        assert_eq!(normalize_tag("＃ Ｒｕｓｔ "), "rust");
+        assert_eq!(normalize_tag("#Rust"), "rust");
+        assert_eq!(normalize_tag("machine　learning"), "machine_learning");
+        assert_eq!(normalize_tag("  "), "");
+    }
+
+    #[test]
+    fn parse_date_is_jst_midnight() {
+        assert_eq!(parse_date("1970-01-01"), Some(-JST));
+        assert_eq!(parse_date("1970-01-02"), Some(86400 - JST));
+        assert_eq!(parse_date("2000-03-01"), Some(951868800 - JST));
+        assert_eq!(parse_date("2026-01-01"), Some(1767225600 - JST));
+    }
+
+    #[test]
+    fn parse_date_rejects_impossible_dates() {
+        assert!(parse_date("2026-02-28").is_some());
+        assert_eq!(parse_date("2026-02-29"), None);
+        assert_eq!(parse_date("2026-02-31"), None);
+        assert_eq!(parse_date("2026-04-31"), None);
+        assert!(parse_date("2024-02-29").is_some());
+        assert!(parse_date("2000-02-29").is_some());
+        assert_eq!(parse_date("1900-02-29"), None);
+        assert_eq!(parse_date("2026-13-01"), None);
+        assert_eq!(parse_date("2026-00-10"), None);
+        assert_eq!(parse_date("2026-01-00"), None);
+        assert_eq!(parse_date("2026-01"), None);
+        assert_eq!(parse_date("99999999999999-01-01"), None);
+        assert_eq!(parse_date("-1-01-01"), None);
+        assert_eq!(parse_date("0-01-01"), None);
+        assert_eq!(parse_date("10000-01-01"), None);
+        assert!(parse_date("9999-12-31").is_some());
+        assert!(parse_date("0001-01-01").is_some());
+        assert_eq!(parse_date("yesterday"), None);
+    }
+
+    #[test]
+    fn format_time_is_jst() {
+        assert_eq!(format_time(0), "");
+        assert_eq!(format_time(1), "1970/01/01 09:00");
+        assert_eq!(format_time(1767225600 - JST), "2026/01/01 00:00");
+        // 日本時間ではもう次の日
+        assert_eq!(format_time(1709218800), "2024/03/01 00:00");
+        assert_eq!(format_time(1709218740), "2024/02/29 23:59");
+    }
+
+    #[test]
+    fn format_time_reads_back_parse_date() {
+        for date in ["1999-12-31", "2000-02-29", "2024-12-31", "2100-03-01"] {
+            let secs = parse_date(date).unwrap();
+            assert_eq!(
+                format_time(secs),
+                format!("{} 00:00", date.replace('-', "/"))
+            );
+        }
+    }
+}
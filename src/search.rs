@@ -0,0 +1,333 @@
+// 全文検索
+// 日本語は単語の間に空白がないので、英数字は単語ごと、それ以外の文字は1文字と2文字 (bigram) ずつに分けて索引にする
+// 検索語もbigramに分け、すべてを含む投稿を候補にしてからここで順位を付ける
+
+use crate::{query::fold_char as normalize, server::Segment, storage::ServerPost};
+use std::collections::HashMap;
+
+// 文字の並びを語に分ける (with_unigram = trueなら日本語の1文字も入れる)
+fn tokens(text: &str, with_unigram: bool) -> Vec<String> {
+    let mut out = vec![];
+    let mut word = String::new();
+    let mut run: Vec<char> = vec![];
+
+    let flush_run = |run: &mut Vec<char>, out: &mut Vec<String>| {
+        if with_unigram || run.len() == 1 {
+            out.extend(run.iter().map(|c| c.to_string()));
+        }
+        out.extend(run.windows(2).map(|w| w.iter().collect::<String>()));
+        run.clear();
+    };
+    let flush_word = |word: &mut String, out: &mut Vec<String>| {
+        if !word.is_empty() {
+            out.push(std::mem::take(word));
+        }
+    };
+
+    for c in text.chars().map(normalize) {
+        if c.is_ascii_alphanumeric() {
+            flush_run(&mut run, &mut out);
+            word.push(c);
+        } else if c.is_alphanumeric() {
+            flush_word(&mut word, &mut out);
+            run.push(c);
+        } else {
+            flush_word(&mut word, &mut out);
+            flush_run(&mut run, &mut out);
+        }
+    }
+    flush_word(&mut word, &mut out);
+    flush_run(&mut run, &mut out);
+    out
+}
+
+// 投稿の索引に入れる語 (重複なし)
+pub fn index_terms(text: &str) -> Vec<String> {
+    let mut terms = tokens(text, true);
+    terms.sort();
+    terms.dedup();
+    terms
+}
+
+// 検索語を索引の語に分ける (重複なし)
+pub fn query_terms(query: &str) -> Vec<String> {
+    let mut terms = tokens(query, false);
+    terms.sort();
+    terms.dedup();
+    terms
+}
+
+// 投稿の中で検索の対象になる文章
+pub fn post_text(post: &ServerPost) -> String {
+    let mut text = format!("{}\n{}", post.title, post.body);
+    for comment in post.comment.iter().filter(|c| c.is_visible()) {
+        text.push('\n');
+        text.push_str(&comment.body);
+    }
+    text
+}
+
+// 順位付け
+// 検索語の出てくる回数を、タイトルは重く、返信は軽く数える
+// 空白で区切った言葉がそのまま含まれていれば加点する
+pub fn score(query: &str, post: &ServerPost) -> f64 {
+    let terms = query_terms(query);
+    let count = |text: &str| {
+        let mut counts: HashMap<String, usize> = HashMap::new();
+        for token in tokens(text, true) {
+            *counts.entry(token).or_default() += 1;
+        }
+        terms
+            .iter()
+            .map(|t| counts.get(t).copied().unwrap_or(0) as f64)
+            .sum::<f64>()
+    };
+    let comments: String = post
+        .comment
+        .iter()
+        .filter(|c| c.is_visible())
+        .map(|c| c.body.as_str())
+        .collect();
+    let mut score = count(&post.title) * 3.0 + count(&post.body) + count(&comments) * 0.5;
+
+    let title = normalized(&post.title);
+    let body = normalized(&post.body);
+    for word in words(query) {
+        if title.contains(&word) {
+            score += 5.0;
+        }
+        if body.contains(&word) {
+            score += 2.0;
+        }
+    }
+    score
+}
+
+fn normalized(text: &str) -> String {
+    text.chars().map(normalize).collect()
+}
+
+// 検索語を空白で区切った言葉
+fn words(query: &str) -> Vec<String> {
+    normalized(query)
+        .split_whitespace()
+        .map(str::to_string)
+        .collect()
+}
+
+// 検索語に当たる文字に印を付ける
+fn marks(query: &str, chars: &[char]) -> Vec<bool> {
+    let lower: Vec<char> = chars.iter().copied().map(normalize).collect();
+    let mut marked = vec![false; chars.len()];
+    for word in words(query) {
+        let word: Vec<char> = word.chars().collect();
+        if word.is_empty() || word.len() > lower.len() {
+            continue;
+        }
+        for start in 0..=lower.len() - word.len() {
+            if lower[start..start + word.len()] == word[..] {
+                marked[start..start + word.len()].fill(true);
+            }
+        }
+    }
+    marked
+}
+
+fn segments(chars: &[char], marked: &[bool]) -> Vec<Segment> {
+    let mut out: Vec<Segment> = vec![];
+    for (c, &highlight) in chars.iter().zip(marked) {
+        match out.last_mut() {
+            Some(last) if last.highlight == highlight => last.text.push(*c),
+            _ => out.push(Segment {
+                text: c.to_string(),
+                highlight,
+            }),
+        }
+    }
+    out
+}
+
+// 文章全体に強調を付ける (タイトル用)
+pub fn highlight(query: &str, text: &str) -> Vec<Segment> {
+    let chars: Vec<char> = text.chars().collect();
+    segments(&chars, &marks(query, &chars))
+}
+
+const SNIPPET_BEFORE: usize = 20;
+const SNIPPET_LENGTH: usize = 80;
+
+// 最初に当たったところの前後を切り出して強調を付ける
+// 本文に当たらなければ返信から探し、どこにもなければ本文の先頭を返す
+pub fn snippet(query: &str, texts: &[&str]) -> Vec<Segment> {
+    for text in texts {
+        let chars: Vec<char> = text.chars().collect();
+        let marked = marks(query, &chars);
+        if let Some(first) = marked.iter().position(|m| *m) {
+            let start = first.saturating_sub(SNIPPET_BEFORE);
+            let end = (start + SNIPPET_LENGTH).min(chars.len());
+            let mut out = segments(&chars[start..end], &marked[start..end]);
+            if start > 0 {
+                out.insert(0, plain("…"));
+            }
+            if end < chars.len() {
+                out.push(plain("…"));
+            }
+            return out;
+        }
+    }
+    let chars: Vec<char> = texts.first().copied().unwrap_or_default().chars().collect();
+    let end = SNIPPET_LENGTH.min(chars.len());
+    let mut out = vec![plain(&chars[..end].iter().collect::<String>())];
+    if end < chars.len() {
+        out.push(plain("…"));
+    }
+    out
+}
+
+fn plain(text: &str) -> Segment {
+    Segment {
+        text: text.to_string(),
+        highlight: false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{ExperienceLevel, ServerComment};
+    use mongodb::bson::oid::ObjectId;
+
+    fn strings(terms: &[&str]) -> Vec<String> {
+        terms.iter().map(|t| t.to_string()).collect()
+    }
+
+    fn post(title: &str, body: &str, comments: &[(&str, bool)]) -> ServerPost {
+        ServerPost {
+            author: ObjectId::new(),
+            body: body.to_string(),
+            attachments: vec![],
+            tag: vec![],
+            title: title.to_string(),
+            comment: comments
+                .iter()
+                .map(|(body, hidden)| ServerComment {
+                    id: ObjectId::new(),
+                    author: ObjectId::new(),
+                    body: body.to_string(),
+                    created_at: 0,
+                    parent: None,
+                    updated_at: None,
+                    deleted: false,
+                    hidden: *hidden,
+                    body_html: String::new(),
+                    attachments: vec![],
+                })
+                .collect(),
+            level: ExperienceLevel::Beginner,
+            created_at: 0,
+            updated_at: None,
+            deleted: false,
+            body_html: String::new(),
+            id: None,
+        }
+    }
+
+    fn marked(segments: &[Segment]) -> Vec<(&str, bool)> {
+        segments
+            .iter()
+            .map(|s| (s.text.as_str(), s.highlight))
+            .collect()
+    }
+
+    #[test]
+    fn index_splits_words_and_cjk() {
+        // 英数字は単語ごと、日本語は1文字と2文字ずつ
+        assert_eq!(
+            index_terms("Rust入門"),
+            strings(&["rust", "入", "入門", "門"])
+        );
+        assert_eq!(
+            index_terms("東京都 RUST"),
+            strings(&["rust", "京", "京都", "東", "東京", "都"])
+        );
+        // 全角英数字も半角の小文字にそろえる
+        assert_eq!(
+            index_terms("Ｌｅｐｔｏｓ　０．８"),
+            strings(&["0", "8", "leptos"])
+        );
+    }
+
+    #[test]
+    fn query_uses_bigrams_unless_single_char() {
+        assert_eq!(query_terms("東京"), strings(&["東京"]));
+        assert_eq!(query_terms("東京都"), strings(&["京都", "東京"]));
+        assert_eq!(query_terms("東"), strings(&["東"]));
+        assert_eq!(query_terms("rust rust 入門"), strings(&["rust", "入門"]));
+        assert!(query_terms("  、。!").is_empty());
+        // 検索語はどれも索引に入っている
+        let index = index_terms("東京都でRustの入門");
+        for term in query_terms("東京 rust 入門") {
+            assert!(index.contains(&term), "{term}");
+        }
+    }
+
+    #[test]
+    fn post_text_skips_hidden_comments() {
+        let post = post("title", "body", &[("shown", false), ("secret", true)]);
+        assert_eq!(post_text(&post), "title\nbody\nshown");
+    }
+
+    #[test]
+    fn score_prefers_title_over_body_over_comments() {
+        let title = post("rust", "", &[]);
+        let body = post("", "rust", &[]);
+        let comment = post("", "", &[("rust", false)]);
+        let hidden = post("", "", &[("rust", true)]);
+        let none = post("go", "go", &[]);
+        let scores: Vec<f64> = [&title, &body, &comment, &hidden, &none]
+            .iter()
+            .map(|p| score("Rust", p))
+            .collect();
+        assert!(scores[0] > scores[1], "{scores:?}");
+        assert!(scores[1] > scores[2], "{scores:?}");
+        assert!(scores[2] > 0.0, "{scores:?}");
+        assert_eq!(scores[3], 0.0);
+        assert_eq!(scores[4], 0.0);
+        // 何度も出てくるほうが上
+        assert!(score("rust", &post("", "rust rust", &[])) > scores[1]);
+    }
+
+    #[test]
+    fn highlight_marks_each_word() {
+        assert_eq!(
+            marked(&highlight("rust 入門", "Rustの入門とRUST")),
+            vec![
+                ("Rust", true),
+                ("の", false),
+                ("入門", true),
+                ("と", false),
+                ("RUST", true)
+            ]
+        );
+        assert_eq!(marked(&highlight("go", "Rust")), vec![("Rust", false)]);
+    }
+
+    #[test]
+    fn snippet_cuts_around_first_match() {
+        let body = format!("{}rust{}", "a".repeat(30), "b".repeat(100));
+        let snippet = snippet("rust", &[&body]);
+        assert_eq!(snippet[0], plain("…"));
+        assert_eq!(snippet[1].text, "a".repeat(SNIPPET_BEFORE));
+        assert_eq!(marked(&snippet[2..3]), vec![("rust", true)]);
+        assert_eq!(snippet.last(), Some(&plain("…")));
+
+        // 本文になければ返信から探し、どこにもなければ本文の先頭
+        let snippet = self::snippet("rust", &["body", "a rust reply"]);
+        assert_eq!(
+            marked(&snippet),
+            vec![("a ", false), ("rust", true), (" reply", false)]
+        );
+        let snippet = self::snippet("go", &["body", "reply"]);
+        assert_eq!(marked(&snippet), vec![("body", false)]);
+    }
+}
@@ -1,316 +1,1594 @@
-use crate::app::LoginScreenState;
-use leptos::{logging::log, prelude::*};
+use crate::{
+    error::AppError,
+    query::{ExperienceLevel, PostQuery},
+};
+use leptos::prelude::*;
 use serde::{Deserialize, Serialize};
 
 #[cfg(feature = "ssr")]
 use {
+    crate::{
+        attachment, auth, autocomplete, config, markdown,
+        query::{normalize_tag, parse_date, PostSort, ReplyFilter},
+        search,
+        storage::{
+            self, PostCursor, PostFilter, PostRevision, ServerComment, ServerDraft, ServerPost,
+            Tag, TagPreference, User,
+        },
+    },
     argon2::{
         password_hash::{
             rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString,
         },
         Argon2,
     },
-    futures::StreamExt,
-    jsonwebtoken::{
-        decode, encode, Algorithm::HS256, DecodingKey, EncodingKey, Header, Validation,
-    },
-    mongodb::{
-        bson::{doc, oid::ObjectId, Document, to_bson},
-        Client, Database,
-    },
-    std::{fs, sync::LazyLock},
-    tokio::sync::OnceCell,
+    mongodb::bson::oid::ObjectId,
+    std::{collections::HashMap, sync::LazyLock},
 };
 
-// DBの設定
-#[cfg(feature = "ssr")]
-static DB: OnceCell<Database> = OnceCell::const_new();
+// ログイン中のユーザー
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct UserInfo {
+    pub id: String,
+    pub name: String,
+    // タグの統合などができる
+    pub is_moderator: bool,
+    // アイコンにした画像 (添付ファイルのID)
+    pub avatar: Option<String>,
+}
 
-#[cfg(feature = "ssr")]
-async fn get_db() -> Database {
-    DB.get_or_init(|| async {
-        let path = fs::read_to_string("./passwords/mongoDB_login_url.txt")
-            .expect("Couldn't read mongoDB path file");
-
-        Client::with_uri_str(path)
-            .await
-            .unwrap()
-            .database("biestar")
-    })
-    .await
-    .clone()
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct Post {
+    pub author_id: String,
+    // 投稿者の名前 (プロフィールのページのURLに使う)
+    pub name: String,
+    // 投稿者の表示名 (表示用)
+    pub display_name: String,
+    pub avatar: Option<String>,
+    // 書かれたままのMarkdown (編集用)
+    pub body: String,
+    // 表示用にサーバーでHTMLにしたもの (サニタイズ済み)
+    pub body_html: String,
+    pub attachments: Vec<Attachment>,
+    pub tag: Vec<String>,
+    pub title: String,
+    pub comment: Vec<Comment>,
+    pub level: ExperienceLevel,
+    // UNIX時間 (秒)
+    pub created_at: i64,
+    // 編集していればその時間 (「編集済み」と出す)
+    pub updated_at: Option<i64>,
+    pub id: String,
 }
 
-#[cfg(feature = "ssr")]
-static DB_SETTING: OnceCell<DbSetting> = OnceCell::const_new();
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct Comment {
+    pub id: String,
+    // 返信先の返信のID (投稿への直接の返信ならNone)
+    pub parent_id: Option<String>,
+    pub author_id: String,
+    pub name: String,
+    // 削除・非表示にした返信は空
+    pub body: String,
+    pub body_html: String,
+    pub attachments: Vec<Attachment>,
+    // UNIX時間 (秒)
+    pub created_at: i64,
+    pub updated_at: Option<i64>,
+    pub deleted: bool,
+    // 投稿者が非表示にした
+    pub hidden: bool,
+}
 
-#[cfg(feature = "ssr")]
-#[derive(Serialize, Deserialize, Clone)]
-struct DbSetting {
-    password_salt: String,
-    jwt: String,
+// 投稿・返信に添付したファイル
+// idはファイルの中身のSHA-256 (/attachments/{id} で読める。画像なら /attachments/{id}/thumbnail も)
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct Attachment {
+    pub id: String,
+    // アップロードしたときのファイル名
+    pub name: String,
+    pub content_type: String,
+    // バイト
+    pub size: i64,
 }
 
-#[cfg(feature = "ssr")]
-async fn get_db_setting() -> DbSetting {
-    DB_SETTING
-        .get_or_init(|| async {
-            let collection = get_db().await.collection::<DbSetting>("config");
-            collection.find_one(doc! {}).await.unwrap().unwrap()
-        })
-        .await
-        .clone()
+impl Attachment {
+    pub fn is_image(&self) -> bool {
+        self.content_type.starts_with("image/")
+    }
 }
 
-// DBに乗せるレコードを表すstruct
+// 全文検索の結果
+// 検索語に当たったところはhighlight = trueの断片になっている
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct Segment {
+    pub text: String,
+    pub highlight: bool,
+}
 
-#[cfg(feature = "ssr")]
-#[derive(Deserialize, Serialize)]
-struct Tag {
-    tag: String,
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct SearchHit {
+    pub post: Post,
+    pub title: Vec<Segment>,
+    // 本文 (なければ返信) の当たったところの前後
+    pub snippet: Vec<Segment>,
 }
 
-#[cfg(feature = "ssr")]
-#[derive(Deserialize, Serialize)]
-struct User {
-    name: String,
-    password_hash: String,
+// フォロー・ミュートしているタグ (設定画面用)
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct TagSettings {
+    pub followed: Vec<String>,
+    pub muted: Vec<String>,
 }
 
-#[derive(Deserialize, Serialize, Clone, Debug)]
-pub struct Post {
+// ユーザーのプロフィール (/users/:name)
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct Profile {
     pub name: String,
-    pub body: String,
-    pub tag: Vec<String>,
-    pub title: String,
-    pub comment: Vec<Comment>,
-    pub is_advanced: bool,
-    pub id: String,
+    // 空なら名前を出す
+    pub display_name: String,
+    // 書かれたままのMarkdownと表示用のHTML
+    pub bio: String,
+    pub bio_html: String,
+    pub avatar: Option<String>,
+    // タグごとの経験 (設定した順)
+    pub experiences: Vec<TagExperience>,
 }
 
-#[derive(Deserialize, Serialize, Clone, Debug)]
-pub struct Comment{
-    name: String,
-    body: String,
+// タグごとの経験 (プロフィールで設定する)
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct TagExperience {
+    pub tag: String,
+    pub level: ExperienceLevel,
+    // 経験した年数 (書かなくてもよい)
+    pub years: Option<i64>,
 }
 
+// 投稿の経験の度合いの既定値 (選んだタグのうち、いちばん経験のあるもの)
+// どのタグも設定していなければ初心者
+pub fn default_level(experiences: &[TagExperience], tags: &[String]) -> ExperienceLevel {
+    experiences
+        .iter()
+        .filter(|e| tags.contains(&e.tag))
+        .map(|e| e.level)
+        .max()
+        .unwrap_or_default()
+}
 
-#[cfg(feature = "ssr")]
-#[derive(Deserialize, Serialize)]
-pub struct ServerPost {
-    pub name: String,
+// ユーザーの返信と、それが付いている投稿
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct UserComment {
+    pub post_id: String,
+    pub post_title: String,
+    pub comment: Comment,
+}
+
+// 投稿する前の下書き
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct Draft {
+    pub id: String,
+    pub title: String,
     pub body: String,
     pub tag: Vec<String>,
-    pub title: String,
-    pub comment: Vec<Comment>,
-    pub is_advanced: bool,
-    #[serde(rename = "_id", skip_serializing)]
-    pub id: Option<ObjectId>,
+    pub level: ExperienceLevel,
+    // 最後に保存した時間 (UNIX時間 (秒))
+    pub updated_at: i64,
+}
+
+// 関数
+
+// 投稿のタイトルと本文の最大文字数 (編集するたびに版が残るので大きくしすぎない)
+pub const TITLE_MAX_LENGTH: usize = 100;
+pub const BODY_MAX_LENGTH: usize = 20000;
+// 返信の最大文字数
+pub const COMMENT_MAX_LENGTH: usize = 2000;
+// タグの最大文字数
+pub const TAG_MAX_LENGTH: usize = 30;
+// 1つの投稿に付けられるタグの数
+pub const TAG_MAX_COUNT: usize = 10;
+// フォロー・ミュートできるタグの数 (それぞれ)
+pub const TAG_PREFERENCE_MAX: usize = 100;
+// 1つの投稿・返信に添付できるファイルの数
+pub const ATTACHMENT_MAX: usize = 10;
+// 1人が持てる下書きの数
+pub const DRAFT_MAX: usize = 50;
+// 表示名と自己紹介の最大文字数
+pub const DISPLAY_NAME_MAX_LENGTH: usize = 30;
+pub const BIO_MAX_LENGTH: usize = 1000;
+// プロフィールに書けるタグごとの経験の数と年数
+pub const EXPERIENCE_MAX: usize = 50;
+pub const EXPERIENCE_YEARS_MAX: i64 = 100;
+
+#[cfg(feature = "ssr")]
+static ARGON2: LazyLock<Argon2<'static>> = LazyLock::new(Argon2::default);
+
+#[cfg(feature = "ssr")]
+fn now() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64)
 }
 
 #[cfg(feature = "ssr")]
-impl From<ServerPost> for Post {
-    fn from(from: ServerPost) -> Post {
-        Post {
-            name: from.name,
-            body: from.body,
-            tag: from.tag,
-            title: from.title,
-            comment: from.comment,
-            id: from.id.unwrap().to_string(),
-            is_advanced: from.is_advanced,
+impl From<&User> for UserInfo {
+    fn from(user: &User) -> UserInfo {
+        UserInfo {
+            id: user.id.to_hex(),
+            name: user.name.clone(),
+            is_moderator: auth::is_moderator(&user.name),
+            avatar: user.avatar.clone(),
         }
     }
 }
 
-// 関数
+#[cfg(feature = "ssr")]
+fn display_name_of(user: &User) -> String {
+    if user.display_name.is_empty() {
+        user.name.clone()
+    } else {
+        user.display_name.clone()
+    }
+}
 
+// DBの投稿はユーザーIDしか持っていないので、まとめて名前を引いてクライアント用にする
 #[cfg(feature = "ssr")]
-static JWT_ENCODE_KEY: OnceCell<EncodingKey> = OnceCell::const_new();
+async fn resolve_posts(posts: Vec<ServerPost>) -> Result<Vec<Post>, AppError> {
+    let mut ids: Vec<ObjectId> = posts
+        .iter()
+        .flat_map(|p| std::iter::once(p.author).chain(p.comment.iter().map(|c| c.author)))
+        .collect();
+    ids.sort();
+    ids.dedup();
+    let users: HashMap<ObjectId, User> = storage::get()
+        .find_users_by_id(&ids)
+        .await?
+        .into_iter()
+        .map(|u| (u.id, u))
+        .collect();
+    let name_of = |id: &ObjectId| {
+        users
+            .get(id)
+            .map(|u| u.name.clone())
+            .unwrap_or_else(|| "不明なユーザー".to_string())
+    };
+    Ok(posts
+        .into_iter()
+        .map(|p| Post {
+            author_id: p.author.to_hex(),
+            name: name_of(&p.author),
+            display_name: users
+                .get(&p.author)
+                .map(display_name_of)
+                .unwrap_or_else(|| name_of(&p.author)),
+            avatar: users.get(&p.author).and_then(|u| u.avatar.clone()),
+            body_html: body_html(&p.body, p.body_html),
+            body: p.body,
+            attachments: p.attachments,
+            tag: p.tag,
+            title: p.title,
+            comment: p
+                .comment
+                .into_iter()
+                .map(|c| {
+                    let name = name_of(&c.author);
+                    comment_view(c, name)
+                })
+                .collect(),
+            id: p.id.map(|id| id.to_hex()).unwrap_or_default(),
+            level: p.level,
+            created_at: p.created_at,
+            updated_at: p.updated_at,
+        })
+        .collect())
+}
 
+// 保存したときに作ったHTMLを使う (HTMLを持っていない古い投稿・返信はここで作る)
 #[cfg(feature = "ssr")]
-static JWT_DECODE_KEY: OnceCell<DecodingKey> = OnceCell::const_new();
+fn body_html(body: &str, html: String) -> String {
+    if html.is_empty() && !body.is_empty() {
+        markdown::render(body)
+    } else {
+        html
+    }
+}
 
+// 削除・非表示にした返信は本文と添付ファイルを渡さない
 #[cfg(feature = "ssr")]
-#[derive(Serialize, Deserialize, Debug)]
-struct Claims {
-    sub: String,
-    exp: i64,
+fn comment_view(comment: ServerComment, name: String) -> Comment {
+    let (body, body_html, attachments) = if comment.is_visible() {
+        let html = body_html(&comment.body, comment.body_html);
+        (comment.body, html, comment.attachments)
+    } else {
+        (String::new(), String::new(), vec![])
+    };
+    Comment {
+        id: comment.id.to_hex(),
+        parent_id: comment.parent.map(|id| id.to_hex()),
+        author_id: comment.author.to_hex(),
+        name,
+        body_html,
+        body,
+        attachments,
+        created_at: comment.created_at,
+        updated_at: comment.updated_at,
+        deleted: comment.deleted,
+        hidden: comment.hidden,
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub enum PostResult {
-    Ok,
-    Refuse,
+// タグの表記をそろえ、別名は正式なタグにする (重複と空のタグは除く)
+#[cfg(feature = "ssr")]
+async fn canonical_tags(tags: &[String]) -> Result<Vec<String>, AppError> {
+    let mut result: Vec<String> = vec![];
+    for tag in tags {
+        let tag = normalize_tag(tag);
+        if tag.is_empty() {
+            continue;
+        }
+        let tag = match storage::get().find_tag(&tag).await? {
+            Some(Tag {
+                alias_of: Some(canonical),
+                ..
+            }) => canonical,
+            _ => tag,
+        };
+        if !result.contains(&tag) {
+            result.push(tag);
+        }
+    }
+    Ok(result)
 }
 
+// 親のタグで探したときは子孫のタグの投稿も入れる
 #[cfg(feature = "ssr")]
-async fn make_jwt(name: String) -> String {
-    let key = JWT_ENCODE_KEY
-        .get_or_init(|| async {
-            let setting = get_db_setting().await;
-            EncodingKey::from_secret(setting.jwt.as_bytes())
-        })
-        .await;
-    encode(
-        &Header::default(),
-        &Claims {
-            sub: name,
-            exp: 1893423600,
-        },
-        key,
-    )
-    .unwrap() //2030年まで セキュリティ的にはあまりよろしくない
+async fn with_descendants(tags: Vec<String>) -> Result<Vec<String>, AppError> {
+    let mut result: Vec<String> = vec![];
+    for tag in tags {
+        for tag in autocomplete::descendants(&tag).await? {
+            if !result.contains(&tag) {
+                result.push(tag);
+            }
+        }
+    }
+    Ok(result)
 }
 
+// ユーザーのフォロー・ミュートしているタグ (統合されたタグは統合先にする)
 #[cfg(feature = "ssr")]
-async fn check_jwt(name: String, jwt: String) -> bool {
-    let key = JWT_DECODE_KEY
-        .get_or_init(|| async {
-            let setting = get_db_setting().await;
-            DecodingKey::from_secret(setting.jwt.as_bytes())
-        })
-        .await;
+async fn tag_preference_of(user_id: ObjectId) -> Result<TagPreference, AppError> {
+    let preference = storage::get().find_tag_preference(user_id).await?;
+    Ok(TagPreference {
+        user_id,
+        followed: canonical_tags(&preference.followed).await?,
+        muted: canonical_tags(&preference.muted).await?,
+    })
+}
 
-    let d: Result<jsonwebtoken::TokenData<Claims>, _> = decode(jwt, key, &Validation::new(HS256));
-    match d {
-        Ok(token) => token.claims.sub == name,
-        Err(_) => false,
+// ログインしていなければNone
+#[cfg(feature = "ssr")]
+async fn current_tag_preference() -> Result<Option<TagPreference>, AppError> {
+    match auth::current_user().await {
+        Ok(user) => Ok(Some(tag_preference_of(user.id).await?)),
+        Err(AppError::Auth(_)) => Ok(None),
+        Err(e) => Err(e),
     }
 }
 
+// 検索条件をDBで使える形にする
+// 投稿者が存在しなければ、当たる投稿はないのでNone
+// ミュートしているタグの投稿は、そのタグを検索したとき以外は出さない
 #[cfg(feature = "ssr")]
-static ARGON2: LazyLock<Argon2<'static>> = LazyLock::new(Argon2::default);
+async fn post_filter(
+    query: &PostQuery,
+    preference: Option<&TagPreference>,
+) -> Result<Option<PostFilter>, AppError> {
+    let date = |date: &Option<String>| match date {
+        Some(date) => parse_date(date)
+            .map(Some)
+            .ok_or_else(|| AppError::validation("日付は YYYY-MM-DD の形で入力してください")),
+        None => Ok(None),
+    };
+    let author = match &query.author {
+        Some(name) => match storage::get().find_user(name).await? {
+            Some(user) => Some(user.id),
+            None => return Ok(None),
+        },
+        None => None,
+    };
+    let all_tags = canonical_tags(&query.all_tags).await?;
+    let mut any_tags = vec![];
+    for group in &query.any_tags {
+        any_tags.push(canonical_tags(group).await?);
+    }
+    let mut not_tags = with_descendants(canonical_tags(&query.not_tags).await?).await?;
+    if let Some(preference) = preference {
+        for tag in with_descendants(preference.muted.clone()).await? {
+            if !all_tags.contains(&tag)
+                && !any_tags.iter().any(|g| g.contains(&tag))
+                && !not_tags.contains(&tag)
+            {
+                not_tags.push(tag);
+            }
+        }
+    }
+    Ok(Some(PostFilter {
+        all_tags: {
+            let mut groups = vec![];
+            for tag in all_tags {
+                groups.push(autocomplete::descendants(&tag).await?);
+            }
+            for group in any_tags {
+                groups.push(with_descendants(group).await?);
+            }
+            groups
+        },
+        not_tags,
+        level: query.level,
+        author,
+        commenter: None,
+        since: date(&query.since)?,
+        // untilの日も含める
+        until: date(&query.until)?.map(|until| until + 24 * 60 * 60),
+        replied: match query.replies {
+            ReplyFilter::Any => None,
+            ReplyFilter::Answered => Some(true),
+            ReplyFilter::Unanswered => Some(false),
+        },
+        terms: vec![],
+        sort: query.sort.unwrap_or_default(),
+    }))
+}
 
 // API関数
 
+// ログインに成功するとセッションのcookieが付き、ユーザーが返る
 #[server]
-pub async fn sign_up(
-    name: String,
-    password: String,
-) -> Result<Result<String, LoginScreenState>, ServerFnError> {
-    /*
-    戻り値の意図
-    1つ目のResult => サーバーの処理エラー
-    2つ目のResult => Signinの処理がうまくいったかどうか
-    */
-    if password.chars().count() < 8 {
-        return Ok(Err(LoginScreenState::TooShortPassword));
+pub async fn sign_up(name: String, password: String) -> Result<UserInfo, AppError> {
+    if name.trim().is_empty() {
+        return Err(AppError::validation("ユーザーネームを入力してください"));
+    }
+    let min_length = config::get().password.min_length;
+    if password.chars().count() < min_length {
+        return Err(AppError::validation(format!(
+            "パスワードは{min_length}文字以上にしてください"
+        )));
     }
 
-    let db_user = get_db().await.collection::<User>("users");
-    if let Err(_) = db_user.find_one(doc! {"name": &name}).await {
-        return Ok(Err(LoginScreenState::NameExists));
+    let storage = storage::get();
+    if storage.find_user(&name).await?.is_some() {
+        return Err(AppError::conflict("その名前は存在しています"));
     }
 
     let salt = SaltString::generate(&mut OsRng);
     let argon2 = ARGON2.clone();
     let account = User {
-        name: name.clone(),
+        id: ObjectId::new(),
+        name,
         password_hash: argon2
             .hash_password(password.as_bytes(), &salt)
-            .unwrap()
+            .map_err(AppError::internal)?
             .to_string(),
+        display_name: String::new(),
+        bio: String::new(),
+        avatar: None,
     };
-    let _ = db_user.insert_one(account).await.unwrap();
-    Ok(Ok(make_jwt(name).await))
+    // 確かめてから入れるまでの間に同じ名前で登録されることもある
+    if !storage.insert_user(account.clone()).await? {
+        return Err(AppError::conflict("その名前は存在しています"));
+    }
+    auth::start_session(&account).await?;
+    Ok(UserInfo::from(&account))
 }
 
 #[server]
-pub async fn log_in(
-    name: String,
-    password: String,
-) -> Result<Result<String, LoginScreenState>, ServerFnError> {
-    let db_user = get_db().await.collection::<User>("users");
-    if let Some(user) = db_user.find_one(doc! {"name": &name}).await.unwrap() {
+pub async fn log_in(name: String, password: String) -> Result<UserInfo, AppError> {
+    if let Some(user) = storage::get().find_user(&name).await? {
         let argon2 = ARGON2.clone();
-        if let Ok(_) = argon2.verify_password(
-            password.as_bytes(),
-            &PasswordHash::new(&user.password_hash).unwrap(),
-        ) {
-            return Ok(Ok(make_jwt(name).await));
+        let hash = PasswordHash::new(&user.password_hash).map_err(AppError::internal)?;
+        if argon2.verify_password(password.as_bytes(), &hash).is_ok() {
+            auth::start_session(&user).await?;
+            return Ok(UserInfo::from(&user));
         }
     }
-    Ok(Err(LoginScreenState::InvalidAccount))
+    Err(AppError::auth("パスワードかユーザーネームが間違っています"))
 }
 
+// cookieからログイン中のユーザーを返す (ページを開いたときにログイン状態を戻すのに使う)
 #[server]
-pub async fn search_tag_with_exact(tag: String) -> Result<Option<String>, ServerFnError> {
-    let db_tag = get_db().await.collection::<Tag>("tags");
-    let result = db_tag.find_one(doc! {"tag": tag}).await?.map(|t| t.tag);
+pub async fn current_user() -> Result<Option<UserInfo>, AppError> {
+    match auth::current_user().await {
+        // アイコンはトークンに入っていないのでDBから引く
+        Ok(user) => match storage::get().find_users_by_id(&[user.id]).await?.pop() {
+            Some(account) => Ok(Some(UserInfo::from(&account))),
+            None => Ok(Some(UserInfo {
+                id: user.id.to_hex(),
+                is_moderator: user.is_moderator(),
+                name: user.name,
+                avatar: None,
+            })),
+        },
+        Err(AppError::Auth(_)) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+// everywhere = true ならそのユーザーのすべての端末からログアウトする
+#[server]
+pub async fn log_out(everywhere: bool) -> Result<(), AppError> {
+    auth::end_session(everywhere).await
+}
+
+#[server]
+pub async fn search_tag_with_exact(tag: String) -> Result<Option<String>, AppError> {
+    let result = storage::get()
+        .find_tag(&normalize_tag(&tag))
+        .await?
+        .map(|t| t.alias_of.unwrap_or(t.tag));
     Ok(result)
 }
 
 #[server]
-pub async fn search_tag_with_prefix(
-    tag: String,
-    amount: i64,
-) -> Result<Vec<String>, ServerFnError> {
-    let db_tag = get_db().await.collection::<Tag>("tags");
-    let mut result = db_tag
-        .find(doc! {"tag": {"$regex": format!("^{}", tag), "$options": "i"}})
-        .limit(amount)
+pub async fn search_tag_with_prefix(tag: String, amount: i64) -> Result<Vec<String>, AppError> {
+    let amount = amount.clamp(0, config::get().page.tags);
+    let result = storage::get()
+        .find_tags_with_prefix(&normalize_tag(&tag), amount)
+        .await?;
+    let mut tags: Vec<String> = vec![];
+    for tag in result {
+        let tag = tag.alias_of.unwrap_or(tag.tag);
+        if !tags.contains(&tag) {
+            tags.push(tag);
+        }
+    }
+    Ok(tags)
+}
+
+// fromをintoに統合する (モデレーターだけ)
+// fromの付いた投稿はintoに付け替え、以後fromと書いてもintoとして扱う
+// 付け替えた投稿の数を返す
+#[server]
+pub async fn merge_tags(from: String, into: String) -> Result<u64, AppError> {
+    auth::current_moderator().await?;
+    let from = normalize_tag(&from);
+    if from.is_empty() {
+        return Err(AppError::validation("統合するタグを入力してください"));
+    }
+    let Some(into) = canonical_tags(&[into]).await?.pop() else {
+        return Err(AppError::validation("統合先のタグを入力してください"));
+    };
+    if from == into {
+        return Err(AppError::validation("同じタグには統合できません"));
+    }
+    let storage = storage::get();
+    // fromの子はintoの子になるので、intoがfromの子孫なら先にfromの親の下へ移して輪にならないようにする
+    if autocomplete::descendants(&from).await?.contains(&into) {
+        let parent = storage.find_tag(&from).await?.and_then(|tag| tag.parent);
+        storage.set_tag_parent(&into, parent.as_deref()).await?;
+    }
+    let merged = storage.merge_tag(&from, &into).await?;
+    autocomplete::invalidate().await;
+    Ok(merged)
+}
+
+// タグの木の1つ分
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct TagNode {
+    pub tag: String,
+    pub parent: Option<String>,
+    pub count: i64,
+    pub reading: Option<String>,
+}
+
+// 別名を除いたすべてのタグ (親子は画面側で組み立てる)
+#[server]
+pub async fn tag_tree() -> Result<Vec<TagNode>, AppError> {
+    let mut tags: Vec<TagNode> = storage::get()
+        .all_tags()
+        .await?
+        .into_iter()
+        .filter(|tag| tag.alias_of.is_none())
+        .map(|tag| TagNode {
+            tag: tag.tag,
+            parent: tag.parent,
+            count: tag.count,
+            reading: tag.reading,
+        })
+        .collect();
+    tags.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.tag.cmp(&b.tag)));
+    Ok(tags)
+}
+
+// tagの親をparentにする (モデレーターだけ)
+// parentが空なら一番上に戻す
+#[server]
+pub async fn set_tag_parent(tag: String, parent: Option<String>) -> Result<(), AppError> {
+    auth::current_moderator().await?;
+    let Some(tag) = canonical_tags(&[tag]).await?.pop() else {
+        return Err(AppError::validation("タグを入力してください"));
+    };
+    let storage = storage::get();
+    if storage.find_tag(&tag).await?.is_none() {
+        return Err(AppError::not_found("タグが見つかりません"));
+    }
+    let parent = canonical_tags(&parent.into_iter().collect::<Vec<_>>())
+        .await?
+        .pop();
+    if let Some(parent) = &parent {
+        if storage.find_tag(parent).await?.is_none() {
+            return Err(AppError::not_found("親のタグが見つかりません"));
+        }
+        // 自分の子孫を親にすると輪になる
+        let tags = storage.all_tags().await?;
+        let mut current = Some(parent.clone());
+        for _ in 0..=tags.len() {
+            let Some(t) = current else {
+                break;
+            };
+            if t == tag {
+                return Err(AppError::validation("自分自身や子孫のタグは親にできません"));
+            }
+            current = tags
+                .iter()
+                .find(|x| x.tag == t)
+                .and_then(|x| x.parent.clone());
+        }
+    }
+    storage.set_tag_parent(&tag, parent.as_deref()).await?;
+    autocomplete::invalidate().await;
+    Ok(())
+}
+
+// tagの読みを付ける (モデレーターだけ)
+// 読みはひらがなかカタカナで、空なら消す
+#[server]
+pub async fn set_tag_reading(tag: String, reading: String) -> Result<(), AppError> {
+    auth::current_moderator().await?;
+    let Some(tag) = canonical_tags(&[tag]).await?.pop() else {
+        return Err(AppError::validation("タグを入力してください"));
+    };
+    let storage = storage::get();
+    if storage.find_tag(&tag).await?.is_none() {
+        return Err(AppError::not_found("タグが見つかりません"));
+    }
+    let reading = match reading.trim() {
+        "" => None,
+        reading => Some(
+            autocomplete::normalize_reading(reading)
+                .filter(|r| r.chars().count() <= TAG_MAX_LENGTH)
+                .ok_or_else(|| {
+                    AppError::validation(format!(
+                        "読みはひらがなかカタカナの{TAG_MAX_LENGTH}文字以内で入力してください"
+                    ))
+                })?,
+        ),
+    };
+    storage.set_tag_reading(&tag, reading.as_deref()).await?;
+    autocomplete::invalidate().await;
+    Ok(())
+}
+
+#[server]
+pub async fn get_tag_settings() -> Result<TagSettings, AppError> {
+    let user = auth::current_user().await?;
+    let preference = tag_preference_of(user.id).await?;
+    Ok(TagSettings {
+        followed: preference.followed,
+        muted: preference.muted,
+    })
+}
+
+// フォロー・ミュートするタグを丸ごと置き換える (保存したものを返す)
+#[server]
+pub async fn save_tag_settings(
+    followed: Option<Vec<String>>,
+    muted: Option<Vec<String>>,
+) -> Result<TagSettings, AppError> {
+    let user = auth::current_user().await?;
+    let (followed, muted) = (followed.unwrap_or_default(), muted.unwrap_or_default());
+    // タグごとにDBを引くので、数は引く前に確かめる
+    if followed.len() > TAG_PREFERENCE_MAX || muted.len() > TAG_PREFERENCE_MAX {
+        return Err(AppError::validation(format!(
+            "フォロー・ミュートできるタグはそれぞれ{TAG_PREFERENCE_MAX}個までです"
+        )));
+    }
+    let followed = canonical_tags(&followed).await?;
+    let muted = canonical_tags(&muted).await?;
+    let storage = storage::get();
+    for tag in followed.iter().chain(&muted) {
+        if storage.find_tag(tag).await?.is_none() {
+            return Err(AppError::not_found(format!(
+                "タグ「{tag}」が見つかりません"
+            )));
+        }
+    }
+    if let Some(tag) = followed.iter().find(|t| muted.contains(t)) {
+        return Err(AppError::validation(format!(
+            "「{tag}」をフォローとミュートの両方にはできません"
+        )));
+    }
+    storage
+        .save_tag_preference(TagPreference {
+            user_id: user.id,
+            followed: followed.clone(),
+            muted: muted.clone(),
+        })
         .await?;
-    let mut out = vec![];
-    while let Some(result) = result.next().await {
-        out.push(result.unwrap().tag);
+    Ok(TagSettings { followed, muted })
+}
+
+// タグの入力補完の候補
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct TagSuggestion {
+    pub tag: String,
+    // このタグが付いた投稿の数
+    pub count: i64,
+    // 一番上の親からこのタグまで (親がなければタグだけ)
+    pub path: Vec<String>,
+}
+
+// 入力に合うタグをよく使われている順に返す (読みやローマ字でも探せる)
+// ログインしていれば、自分が最近使ったタグを上に出す
+#[server]
+pub async fn suggest_tags(input: String) -> Result<Vec<TagSuggestion>, AppError> {
+    // 最近使ったタグを調べる投稿の数
+    const RECENT_POSTS: i64 = 20;
+    let mut recent: Vec<String> = vec![];
+    if let Ok(user) = auth::current_user().await {
+        let filter = PostFilter {
+            author: Some(user.id),
+            ..PostFilter::default()
+        };
+        for post in storage::get()
+            .find_posts(&filter, None, RECENT_POSTS)
+            .await?
+        {
+            for tag in post.tag {
+                if !recent.contains(&tag) {
+                    recent.push(tag);
+                }
+            }
+        }
     }
-    Ok(out)
+    let limit = config::get().page.tags as usize;
+    Ok(autocomplete::suggest(&input, &recent, limit).await?)
+}
+
+#[cfg(feature = "ssr")]
+fn check_post_length(title: &str, body: &str) -> Result<(), AppError> {
+    if title.chars().count() > TITLE_MAX_LENGTH {
+        return Err(AppError::validation(format!(
+            "タイトルは{TITLE_MAX_LENGTH}文字以内にしてください"
+        )));
+    }
+    if body.chars().count() > BODY_MAX_LENGTH {
+        return Err(AppError::validation(format!(
+            "内容は{BODY_MAX_LENGTH}文字以内にしてください"
+        )));
+    }
+    Ok(())
+}
+
+// 投稿・編集の入力を確かめて、付けるタグを返す
+// タグは表記をそろえ、別名は正式なタグにする
+#[cfg(feature = "ssr")]
+async fn post_tags(
+    title: &str,
+    body: &str,
+    tag: Option<Vec<String>>,
+) -> Result<Vec<String>, AppError> {
+    if title.trim().is_empty() {
+        return Err(AppError::validation("タイトルを入力してください"));
+    }
+    if body.trim().is_empty() {
+        return Err(AppError::validation("内容を入力してください"));
+    }
+    check_post_length(title, body)?;
+    canonical_tags(&checked_tags(tag.unwrap_or_default())?).await
+}
+
+// 投稿に付けるタグの表記をそろえて確かめる (重複と空のタグは除く)
+// 数はDBを引く前に確かめる
+#[cfg(feature = "ssr")]
+fn checked_tags(tag: Vec<String>) -> Result<Vec<String>, AppError> {
+    let mut tags: Vec<String> = vec![];
+    for tag in tag {
+        let tag = normalize_tag(&tag);
+        if tag.is_empty() || tags.contains(&tag) {
+            continue;
+        }
+        check_tag(&tag)?;
+        if tags.len() == TAG_MAX_COUNT {
+            return Err(AppError::validation(format!(
+                "タグは{TAG_MAX_COUNT}個までにしてください"
+            )));
+        }
+        tags.push(tag);
+    }
+    Ok(tags)
+}
+
+// 表記をそろえたタグが付けられるものか確かめる (投稿とプロフィールの経験で同じにする)
+#[cfg(feature = "ssr")]
+fn check_tag(tag: &str) -> Result<(), AppError> {
+    if tag.chars().count() > TAG_MAX_LENGTH {
+        return Err(AppError::validation(format!(
+            "タグは{TAG_MAX_LENGTH}文字以内にしてください"
+        )));
+    }
+    // 検索の tag:a|b と区別できなくなる
+    if tag.contains(['|', ':']) {
+        return Err(AppError::validation("タグに | と : は使えません"));
+    }
+    Ok(())
 }
 
+// 投稿者はリクエストの引数ではなく、cookieのトークンから決める
+// タグは表記をそろえて、まだないものは新しく作る
 #[server]
 pub async fn do_post(
-    name: String,
-    jwt: String,
     title: String,
     body: String,
     tag: Option<Vec<String>>,
-    is_advanced: bool,
-) -> Result<PostResult, ServerFnError> {
-    if !check_jwt(name.clone(), jwt).await {
-        return Ok(PostResult::Refuse);
-    }
-    let db_post = get_db().await.collection::<ServerPost>("posts");
+    // Noneならプロフィールのタグごとの経験から決める
+    level: Option<ExperienceLevel>,
+    // 下書きから投稿したら、その下書きは消す
+    draft_id: Option<String>,
+    attachments: Option<Vec<Attachment>>,
+) -> Result<(), AppError> {
+    let user = auth::current_user().await?;
+    let tags = post_tags(&title, &body, tag).await?;
+    let attachments = uploaded_attachments(attachments).await?;
+    let level = match level {
+        Some(level) => level,
+        None => default_level(&experiences_of(user.id).await?, &tags),
+    };
     let post = ServerPost {
-        name,
+        author: user.id,
+        body_html: markdown::render(&body),
         body,
-        tag: tag.unwrap(),
-        is_advanced,
+        attachments,
+        tag: tags,
+        level,
         title,
         comment: vec![],
+        created_at: now(),
+        updated_at: None,
+        deleted: false,
         id: Some(ObjectId::new()),
     };
-    db_post.insert_one(post).await.unwrap();
+    let tags = post.tag.clone();
+    storage::get().insert_post(post).await?;
+    autocomplete::record(&tags).await;
+    if let Some(draft_id) = draft_id {
+        if let Ok(draft) = own_draft(&draft_id, user.id).await {
+            storage::get().delete_draft(&draft.id.to_hex()).await?;
+        }
+    }
     leptos_axum::redirect("/");
-    Ok(PostResult::Ok)
+    Ok(())
+}
+
+// 送られてきた添付ファイルが本当にアップロードされているか確かめる
+#[cfg(feature = "ssr")]
+async fn uploaded_attachments(
+    attachments: Option<Vec<Attachment>>,
+) -> Result<Vec<Attachment>, AppError> {
+    let attachments = attachments.unwrap_or_default();
+    if attachments.len() > ATTACHMENT_MAX {
+        return Err(AppError::validation(format!(
+            "添付できるファイルは{ATTACHMENT_MAX}個までです"
+        )));
+    }
+    let mut result: Vec<Attachment> = vec![];
+    for a in attachments {
+        let found = attachment::find(&a.id, &a.name)
+            .await?
+            .ok_or_else(|| AppError::validation("添付ファイルが見つかりません"))?;
+        if !result.iter().any(|r| r.id == found.id) {
+            result.push(found);
+        }
+    }
+    Ok(result)
+}
+
+#[cfg(feature = "ssr")]
+fn draft_view(draft: ServerDraft) -> Draft {
+    Draft {
+        id: draft.id.to_hex(),
+        title: draft.title,
+        body: draft.body,
+        tag: draft.tag,
+        level: draft.level,
+        updated_at: draft.updated_at,
+    }
+}
+
+// 自分の下書きを探す (他人のものは見つからない扱い)
+#[cfg(feature = "ssr")]
+async fn own_draft(id: &str, user_id: ObjectId) -> Result<ServerDraft, AppError> {
+    let not_found = || AppError::not_found("下書きが見つかりません");
+    if ObjectId::parse_str(id).is_err() {
+        return Err(not_found());
+    }
+    storage::get()
+        .find_draft(id)
+        .await?
+        .filter(|d| d.author == user_id)
+        .ok_or_else(not_found)
+}
+
+// 下書きを保存する (idがなければ新しく作る)
+// 書きかけでも保存できるように、投稿のような入力の確認は長さだけにする
+#[server]
+pub async fn save_draft(
+    id: Option<String>,
+    title: String,
+    body: String,
+    tag: Option<Vec<String>>,
+    level: ExperienceLevel,
+) -> Result<Draft, AppError> {
+    let user = auth::current_user().await?;
+    // 投稿と同じ長さ・タグの決まりにしておく (下書きから投稿したときに通らないと困る)
+    check_post_length(&title, &body)?;
+    let tag = checked_tags(tag.unwrap_or_default())?;
+    let storage = storage::get();
+    let id = match id {
+        Some(id) => own_draft(&id, user.id).await?.id,
+        None => {
+            if storage.find_drafts(user.id).await?.len() >= DRAFT_MAX {
+                return Err(AppError::validation(format!(
+                    "下書きは{DRAFT_MAX}件までです。いらない下書きを破棄してください"
+                )));
+            }
+            ObjectId::new()
+        }
+    };
+    let draft = ServerDraft {
+        id,
+        author: user.id,
+        title,
+        body,
+        tag,
+        level,
+        updated_at: now(),
+    };
+    storage.save_draft(draft.clone()).await?;
+    Ok(draft_view(draft))
+}
+
+// 投稿・返信のプレビュー (表示と同じようにHTMLにする)
+#[server]
+pub async fn render_markdown(body: String) -> Result<String, AppError> {
+    auth::current_user().await?;
+    Ok(markdown::render(&body))
+}
+
+// 自分の下書き (最後に保存した時間の新しい順)
+#[server]
+pub async fn get_drafts() -> Result<Vec<Draft>, AppError> {
+    let user = auth::current_user().await?;
+    let drafts = storage::get().find_drafts(user.id).await?;
+    Ok(drafts.into_iter().map(draft_view).collect())
+}
+
+#[server]
+pub async fn get_draft(id: String) -> Result<Draft, AppError> {
+    let user = auth::current_user().await?;
+    Ok(draft_view(own_draft(&id, user.id).await?))
+}
+
+#[server]
+pub async fn delete_draft(id: String) -> Result<(), AppError> {
+    let user = auth::current_user().await?;
+    let draft = own_draft(&id, user.id).await?;
+    storage::get().delete_draft(&draft.id.to_hex()).await?;
+    Ok(())
+}
+
+// 自分の投稿を探す (削除したものと他人のものは見つからない扱い)
+#[cfg(feature = "ssr")]
+async fn own_post(id: &str, user_id: ObjectId) -> Result<ServerPost, AppError> {
+    let not_found = || AppError::not_found("投稿が見つかりません");
+    if ObjectId::parse_str(id).is_err() {
+        return Err(not_found());
+    }
+    let post = storage::get()
+        .find_post(id)
+        .await?
+        .filter(|p| !p.deleted)
+        .ok_or_else(not_found)?;
+    if post.author != user_id {
+        return Err(AppError::auth("自分の投稿しか変更できません"));
+    }
+    Ok(post)
+}
+
+// 自分の投稿を書き換える (前の内容は履歴に残す)
+#[server]
+pub async fn edit_post(
+    id: String,
+    title: String,
+    body: String,
+    tag: Option<Vec<String>>,
+    level: ExperienceLevel,
+) -> Result<(), AppError> {
+    let user = auth::current_user().await?;
+    let old = own_post(&id, user.id).await?;
+    let tags = post_tags(&title, &body, tag).await?;
+    if old.title == title && old.body == body && old.tag == tags && old.level == level {
+        return Err(AppError::validation("変更がありません"));
+    }
+    let revision = PostRevision {
+        id: ObjectId::new(),
+        post_id: old.id.unwrap_or_default(),
+        title: old.title.clone(),
+        body: old.body.clone(),
+        tag: old.tag.clone(),
+        level: old.level,
+        created_at: old.updated_at.unwrap_or(old.created_at),
+    };
+    let post = ServerPost {
+        title,
+        body_html: markdown::render(&body),
+        body,
+        tag: tags,
+        level,
+        updated_at: Some(now()),
+        ..old
+    };
+    storage::get().update_post(post, revision).await?;
+    // タグの使われた数が変わったので入力補完を読み直させる
+    autocomplete::invalidate().await;
+    leptos_axum::redirect(&format!("/posts/{id}"));
+    Ok(())
 }
 
+// 自分の投稿を削除する (DBには残すが、どこにも出さない)
 #[server]
-pub async fn search(tag: Option<String>) -> Result<Vec<Post>, ServerFnError> {
-    let db_post = get_db().await.collection::<ServerPost>("posts");
-    let filter: Document;
-    if let Some(t) = tag {
-        filter = doc! {"tag": { "$in": [t] }};
+pub async fn delete_post(id: String) -> Result<(), AppError> {
+    let user = auth::current_user().await?;
+    own_post(&id, user.id).await?;
+    storage::get().delete_post(&id).await?;
+    autocomplete::invalidate().await;
+    Ok(())
+}
+
+// 投稿の版 (編集履歴の1つ分)
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct PostVersion {
+    pub title: String,
+    pub body: String,
+    pub tag: Vec<String>,
+    pub level: ExperienceLevel,
+    // この内容になった時間
+    pub created_at: i64,
+}
+
+// 投稿の最初の内容から今の内容までを古い順に返す
+#[server]
+pub async fn get_post_history(id: String) -> Result<Vec<PostVersion>, AppError> {
+    let post = get_post(id.clone()).await?;
+    let mut versions: Vec<PostVersion> = storage::get()
+        .find_revisions(&id)
+        .await?
+        .into_iter()
+        .map(|r| PostVersion {
+            title: r.title,
+            body: r.body,
+            tag: r.tag,
+            level: r.level,
+            created_at: r.created_at,
+        })
+        .collect();
+    versions.push(PostVersion {
+        created_at: post.updated_at.unwrap_or(post.created_at),
+        title: post.title,
+        body: post.body,
+        tag: post.tag,
+        level: post.level,
+    });
+    Ok(versions)
+}
+
+// タイムラインの1ページ分
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct PostPage {
+    pub posts: Vec<Post>,
+    // 次のページを読むときにsearchに渡す (最後のページならNone)
+    pub next_cursor: Option<String>,
+}
+
+// filterに合う投稿をafterの後ろからlimit件読み、次のページのカーソルも返す
+// カーソルの先頭にはprefixを付ける
+#[cfg(feature = "ssr")]
+async fn find_page(
+    filter: &PostFilter,
+    after: Option<PostCursor>,
+    limit: i64,
+    prefix: &str,
+) -> Result<(Vec<ServerPost>, Option<String>), AppError> {
+    // 1件多く読んで、次のページがあるかを調べる
+    let mut result = storage::get().find_posts(filter, after, limit + 1).await?;
+    let next_cursor = if result.len() as i64 > limit {
+        result.truncate(limit as usize);
+        result.last().map(|p| {
+            format!(
+                "{prefix}{}.{}",
+                filter.sort_key(p),
+                p.id.unwrap_or_default().to_hex()
+            )
+        })
     } else {
-        filter = doc! {}
+        None
+    };
+    Ok((result, next_cursor))
+}
+
+// 検索条件に合う投稿を1ページ分返す (キーワードは見ない、それはsearch_text)
+// カーソルは "{並べ替えの値}.{前のページの最後の投稿のID}"
+// query.followingなら、フォローしているタグの投稿を出し切ってからほかの投稿を出す
+// ほかの投稿に移ったあとのカーソルには先頭に "+" を付ける
+#[server]
+pub async fn search(
+    #[server(default)] query: PostQuery,
+    cursor: Option<String>,
+) -> Result<PostPage, AppError> {
+    let (is_rest, cursor) = match cursor.as_deref().map(|c| c.strip_prefix('+')) {
+        Some(Some(rest)) => (true, Some(rest).filter(|c| !c.is_empty())),
+        Some(None) => (false, cursor.as_deref()),
+        None => (false, None),
+    };
+    let after = match cursor {
+        Some(cursor) => Some(
+            cursor
+                .split_once('.')
+                .and_then(|(key, id)| {
+                    Some(PostCursor {
+                        key: key.parse().ok()?,
+                        id: ObjectId::parse_str(id).ok()?,
+                    })
+                })
+                .ok_or_else(|| AppError::validation("ページの指定が正しくありません"))?,
+        ),
+        None => None,
+    };
+    let preference = current_tag_preference().await?;
+    let Some(filter) = post_filter(&query, preference.as_ref()).await? else {
+        return Ok(PostPage {
+            posts: vec![],
+            next_cursor: None,
+        });
+    };
+    let limit = config::get().page.posts;
+    let followed = match preference {
+        Some(preference) if query.following => with_descendants(preference.followed).await?,
+        _ => vec![],
+    };
+    let (posts, next_cursor) = if followed.is_empty() {
+        find_page(&filter, after, limit, "").await?
+    } else {
+        let mut first = filter.clone();
+        first.all_tags.push(followed.clone());
+        let mut rest = filter;
+        rest.not_tags.extend(followed);
+        if is_rest {
+            find_page(&rest, after, limit, "+").await?
+        } else {
+            let (mut posts, next_cursor) = find_page(&first, after, limit, "").await?;
+            match next_cursor {
+                Some(next_cursor) => (posts, Some(next_cursor)),
+                // 残りをほかの投稿で埋める
+                None if (posts.len() as i64) < limit => {
+                    let (more, next_cursor) =
+                        find_page(&rest, None, limit - posts.len() as i64, "+").await?;
+                    posts.extend(more);
+                    (posts, next_cursor)
+                }
+                None => (posts, Some("+".to_string())),
+            }
+        }
+    };
+    Ok(PostPage {
+        posts: resolve_posts(posts).await?,
+        next_cursor,
+    })
+}
+
+// 返信を付けて、表示用の返信を返す
+// parent_idを指定すると、その返信への返信になる
+#[server]
+pub async fn add_comment(
+    id: String,
+    parent_id: Option<String>,
+    body: String,
+    attachments: Option<Vec<Attachment>>,
+) -> Result<Comment, AppError> {
+    let user = auth::current_user().await?;
+    let body = comment_body(body)?;
+    let attachments = uploaded_attachments(attachments).await?;
+    let not_found = || AppError::not_found("返信先の投稿が見つかりません");
+    if ObjectId::parse_str(&id).is_err() {
+        return Err(not_found());
+    }
+    // 削除した投稿には返信できない
+    let post = storage::get()
+        .find_post(&id)
+        .await?
+        .filter(|p| !p.deleted)
+        .ok_or_else(not_found)?;
+    // 返信先の返信は同じ投稿のものでなければならない
+    let parent = match parent_id {
+        Some(parent_id) => {
+            let parent = ObjectId::parse_str(&parent_id).ok();
+            match post.comment.iter().find(|c| Some(c.id) == parent) {
+                Some(c) => Some(c.id),
+                None => return Err(AppError::not_found("返信先の返信が見つかりません")),
+            }
+        }
+        None => None,
+    };
+    let comment = ServerComment {
+        id: ObjectId::new(),
+        author: user.id,
+        body_html: markdown::render(&body),
+        body,
+        created_at: now(),
+        parent,
+        updated_at: None,
+        deleted: false,
+        hidden: false,
+        attachments,
+    };
+    if !storage::get().push_comment(&id, comment.clone()).await? {
+        return Err(not_found());
     }
+    Ok(comment_view(comment, user.name))
+}
 
-    let mut out: Vec<Post> = vec![];
-    let mut result = db_post.find(filter).limit(100).await?;
+#[cfg(feature = "ssr")]
+fn comment_body(body: String) -> Result<String, AppError> {
+    let body = body.trim().to_string();
+    if body.is_empty() {
+        return Err(AppError::validation("返信の内容を入力してください"));
+    }
+    if body.chars().count() > COMMENT_MAX_LENGTH {
+        return Err(AppError::validation(format!(
+            "返信は{COMMENT_MAX_LENGTH}文字以内にしてください"
+        )));
+    }
+    Ok(body)
+}
 
-    while let Some(result) = result.next().await {
-        out.push(result.unwrap().into())
+// 投稿とその返信を探す (削除した投稿の返信は見つからない扱い)
+#[cfg(feature = "ssr")]
+async fn find_comment(id: &str, comment_id: &str) -> Result<(ServerPost, ServerComment), AppError> {
+    let not_found = || AppError::not_found("返信が見つかりません");
+    if ObjectId::parse_str(id).is_err() {
+        return Err(not_found());
     }
+    let comment_id = ObjectId::parse_str(comment_id).map_err(|_| not_found())?;
+    let post = storage::get()
+        .find_post(id)
+        .await?
+        .filter(|p| !p.deleted)
+        .ok_or_else(not_found)?;
+    let comment = post
+        .comment
+        .iter()
+        .find(|c| c.id == comment_id)
+        .cloned()
+        .ok_or_else(not_found)?;
+    Ok((post, comment))
+}
 
-    Ok(out)
+// 書き換えた返信を保存して、表示用の返信を返す
+#[cfg(feature = "ssr")]
+async fn save_comment(id: &str, comment: ServerComment) -> Result<Comment, AppError> {
+    let storage = storage::get();
+    if !storage.update_comment(id, comment.clone()).await? {
+        return Err(AppError::not_found("返信が見つかりません"));
+    }
+    let name = storage
+        .find_users_by_id(&[comment.author])
+        .await?
+        .pop()
+        .map_or_else(|| "不明なユーザー".to_string(), |u| u.name);
+    Ok(comment_view(comment, name))
 }
 
+// 自分の返信を書き換える
 #[server]
-pub async fn add_comment(id: String, name: String, jwt: String, body: String) -> Result<(), ServerFnError> {
-    check_jwt(name.clone(), jwt).await;
-    let id = ObjectId::parse_str(id)?;
-    let db_post = get_db().await.collection::<ServerPost>("post");
-    db_post.update_one(doc! {"_id": id}, doc!{"$push": {"comment": to_bson(&Comment{body, name}).unwrap()}}).await.unwrap();
-    leptos_axum::redirect("/");
-    Ok(())
-}
\ No newline at end of file
+pub async fn edit_comment(
+    id: String,
+    comment_id: String,
+    body: String,
+) -> Result<Comment, AppError> {
+    let user = auth::current_user().await?;
+    let body = comment_body(body)?;
+    let (_, comment) = find_comment(&id, &comment_id).await?;
+    if comment.author != user.id {
+        return Err(AppError::auth("自分の返信しか変更できません"));
+    }
+    if comment.deleted {
+        return Err(AppError::validation("削除した返信は編集できません"));
+    }
+    if comment.body == body {
+        return Err(AppError::validation("変更がありません"));
+    }
+    let comment = ServerComment {
+        body_html: markdown::render(&body),
+        body,
+        updated_at: Some(now()),
+        ..comment
+    };
+    save_comment(&id, comment).await
+}
+
+// 自分の返信を削除する
+// スレッドの形が崩れないように、返信自体は「削除しました」として残す
+#[server]
+pub async fn delete_comment(id: String, comment_id: String) -> Result<Comment, AppError> {
+    let user = auth::current_user().await?;
+    let (_, comment) = find_comment(&id, &comment_id).await?;
+    if comment.author != user.id {
+        return Err(AppError::auth("自分の返信しか変更できません"));
+    }
+    let comment = ServerComment {
+        body: String::new(),
+        body_html: String::new(),
+        deleted: true,
+        ..comment
+    };
+    save_comment(&id, comment).await
+}
+
+// 自分の投稿についた返信を非表示にする (hidden = false で戻す)
+#[server]
+pub async fn hide_comment(
+    id: String,
+    comment_id: String,
+    hidden: bool,
+) -> Result<Comment, AppError> {
+    let user = auth::current_user().await?;
+    let (post, comment) = find_comment(&id, &comment_id).await?;
+    if post.author != user.id {
+        return Err(AppError::auth("自分の投稿の返信しか非表示にできません"));
+    }
+    save_comment(&id, ServerComment { hidden, ..comment }).await
+}
+
+// 全文検索の候補として読む投稿の最大数 (ほかの条件にも合うものを並べ替えの順に読み、この中で順位を付ける)
+#[cfg(feature = "ssr")]
+const SEARCH_CANDIDATES: i64 = 500;
+
+// タイトル・本文・返信から query.text の言葉で探して、よく当たる順に返す
+// 並べ替えを指定したときはその順、ほかの条件は絞り込みに使う
+#[server]
+pub async fn search_text(query: PostQuery) -> Result<Vec<SearchHit>, AppError> {
+    let terms = search::query_terms(&query.text);
+    if terms.is_empty() {
+        return Err(AppError::validation("検索する言葉を入力してください"));
+    }
+    let preference = current_tag_preference().await?;
+    let Some(mut filter) = post_filter(&query, preference.as_ref()).await? else {
+        return Ok(vec![]);
+    };
+    filter.terms = terms;
+    let followed = match preference {
+        Some(preference) if query.following => with_descendants(preference.followed).await?,
+        _ => vec![],
+    };
+    let candidates = storage::get()
+        .find_posts(&filter, None, SEARCH_CANDIDATES)
+        .await?;
+    let query_sort = query.sort;
+    let query = query.text;
+    // 同じ点数なら新しい順のまま
+    let mut scored: Vec<(f64, ServerPost)> = candidates
+        .into_iter()
+        .map(|p| match query_sort {
+            Some(_) => (filter.sort_key(&p) as f64, p),
+            None => (search::score(&query, &p), p),
+        })
+        .collect();
+    scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+    // フォローしているタグの投稿を先に出す
+    scored.sort_by_key(|(_, p)| !p.tag.iter().any(|t| followed.contains(t)));
+    scored.truncate(config::get().page.posts as usize);
+    let posts = resolve_posts(scored.into_iter().map(|(_, p)| p).collect()).await?;
+    Ok(posts
+        .into_iter()
+        .map(|post| {
+            let mut texts = vec![post.body.as_str()];
+            texts.extend(post.comment.iter().map(|c| c.body.as_str()));
+            SearchHit {
+                title: search::highlight(&query, &post.title),
+                snippet: search::snippet(&query, &texts),
+                post,
+            }
+        })
+        .collect())
+}
+
+// 投稿を1件返す (投稿のページ用)
+#[server]
+pub async fn get_post(id: String) -> Result<Post, AppError> {
+    let not_found = || AppError::not_found("投稿が見つかりません");
+    if ObjectId::parse_str(&id).is_err() {
+        return Err(not_found());
+    }
+    let post = storage::get()
+        .find_post(&id)
+        .await?
+        .filter(|p| !p.deleted)
+        .ok_or_else(not_found)?;
+    let mut post = resolve_posts(vec![post]).await?;
+    post.pop().ok_or_else(not_found)
+}
+
+// 投稿についた返信をすべて古い順に返す (parent_idをたどるとスレッドになる)
+#[server]
+pub async fn get_thread(id: String) -> Result<Vec<Comment>, AppError> {
+    let mut comments = get_post(id).await?.comment;
+    comments.sort_by_key(|c| c.created_at);
+    Ok(comments)
+}
+
+#[cfg(feature = "ssr")]
+async fn find_user_by_name(name: &str) -> Result<User, AppError> {
+    storage::get()
+        .find_user(name)
+        .await?
+        .ok_or_else(|| AppError::not_found("ユーザーが見つかりません"))
+}
+
+// ユーザーのタグごとの経験 (統合されたタグは統合先にし、同じタグは最初のものだけ残す)
+#[cfg(feature = "ssr")]
+async fn experiences_of(user_id: ObjectId) -> Result<Vec<TagExperience>, AppError> {
+    let mut result: Vec<TagExperience> = vec![];
+    for e in storage::get().find_experiences(user_id).await? {
+        let Some(tag) = canonical_tags(&[e.tag]).await?.pop() else {
+            continue;
+        };
+        if !result.iter().any(|r| r.tag == tag) {
+            result.push(TagExperience { tag, ..e });
+        }
+    }
+    Ok(result)
+}
+
+#[cfg(feature = "ssr")]
+async fn profile_of(user: User) -> Result<Profile, AppError> {
+    let experiences = experiences_of(user.id).await?;
+    Ok(Profile {
+        bio_html: markdown::render(&user.bio),
+        name: user.name,
+        display_name: user.display_name,
+        bio: user.bio,
+        avatar: user.avatar,
+        experiences,
+    })
+}
+
+// ユーザーのプロフィール (投稿の一覧は search で author を指定して読む)
+#[server]
+pub async fn get_profile(name: String) -> Result<Profile, AppError> {
+    profile_of(find_user_by_name(&name).await?).await
+}
+
+// ユーザーの返信を新しい順に返す (削除・非表示にしたものは出さない)
+#[server]
+pub async fn get_user_comments(name: String) -> Result<Vec<UserComment>, AppError> {
+    let user = find_user_by_name(&name).await?;
+    let limit = config::get().page.posts;
+    let filter = PostFilter {
+        commenter: Some(user.id),
+        sort: PostSort::MostActive,
+        ..PostFilter::default()
+    };
+    let posts = storage::get().find_posts(&filter, None, limit).await?;
+    let mut result: Vec<UserComment> = posts
+        .into_iter()
+        .flat_map(|post| {
+            let post_id = post.id.map(|id| id.to_hex()).unwrap_or_default();
+            let (title, user) = (post.title, &user);
+            post.comment
+                .into_iter()
+                .filter(|c| c.author == user.id && c.is_visible())
+                .map(move |c| UserComment {
+                    post_id: post_id.clone(),
+                    post_title: title.clone(),
+                    comment: comment_view(c, user.name.clone()),
+                })
+        })
+        .collect();
+    result.sort_by_key(|c| std::cmp::Reverse(c.comment.created_at));
+    result.truncate(limit as usize);
+    Ok(result)
+}
+
+// タグごとの経験を確かめる (タグは表記をそろえ、同じタグは最初のものだけ残す)
+#[cfg(feature = "ssr")]
+async fn valid_experiences(
+    experiences: Option<Vec<TagExperience>>,
+) -> Result<Vec<TagExperience>, AppError> {
+    let experiences = experiences.unwrap_or_default();
+    if experiences.len() > EXPERIENCE_MAX {
+        return Err(AppError::validation(format!(
+            "経験を書けるタグは{EXPERIENCE_MAX}個までです"
+        )));
+    }
+    let mut result: Vec<TagExperience> = vec![];
+    for e in experiences {
+        if e.years
+            .is_some_and(|y| !(0..=EXPERIENCE_YEARS_MAX).contains(&y))
+        {
+            return Err(AppError::validation(format!(
+                "年数は0から{EXPERIENCE_YEARS_MAX}までで入力してください"
+            )));
+        }
+        let tag = normalize_tag(&e.tag);
+        if tag.is_empty() {
+            continue;
+        }
+        check_tag(&tag)?;
+        let Some(tag) = canonical_tags(&[tag]).await?.pop() else {
+            continue;
+        };
+        if !result.iter().any(|r| r.tag == tag) {
+            result.push(TagExperience { tag, ..e });
+        }
+    }
+    Ok(result)
+}
+
+// 自分のプロフィールを書き換える
+// avatarはアップロード済みの画像のID (Noneならアイコンを外す)
+#[server]
+pub async fn save_profile(
+    display_name: String,
+    bio: String,
+    avatar: Option<String>,
+    experiences: Option<Vec<TagExperience>>,
+) -> Result<Profile, AppError> {
+    let current = auth::current_user().await?;
+    let display_name = display_name.trim().to_string();
+    if display_name.chars().count() > DISPLAY_NAME_MAX_LENGTH {
+        return Err(AppError::validation(format!(
+            "表示名は{DISPLAY_NAME_MAX_LENGTH}文字以内にしてください"
+        )));
+    }
+    if display_name.chars().any(char::is_control) {
+        return Err(AppError::validation("表示名に使えない文字が入っています"));
+    }
+    let bio = bio.trim().to_string();
+    if bio.chars().count() > BIO_MAX_LENGTH {
+        return Err(AppError::validation(format!(
+            "自己紹介は{BIO_MAX_LENGTH}文字以内にしてください"
+        )));
+    }
+    let avatar = match avatar.filter(|id| !id.is_empty()) {
+        Some(id) => match attachment::find(&id, "").await? {
+            Some(a) if a.is_image() => Some(a.id),
+            Some(_) => return Err(AppError::validation("アイコンには画像を選んでください")),
+            None => return Err(AppError::validation("アイコンの画像が見つかりません")),
+        },
+        None => None,
+    };
+    let experiences = valid_experiences(experiences).await?;
+    let mut user = storage::get()
+        .find_users_by_id(&[current.id])
+        .await?
+        .pop()
+        .ok_or_else(|| AppError::not_found("ユーザーが見つかりません"))?;
+    user.display_name = display_name;
+    user.bio = bio;
+    user.avatar = avatar;
+    if !storage::get().update_profile(user.clone()).await? {
+        return Err(AppError::not_found("ユーザーが見つかりません"));
+    }
+    storage::get()
+        .save_experiences(user.id, experiences)
+        .await?;
+    profile_of(user).await
+}
@@ -0,0 +1,451 @@
+// インメモリ実装
+// DBなしでアプリを動かしたりテストしたりするためのもの (再起動で消える)
+
+use super::{
+    DbSetting, PostCursor, PostFilter, PostRevision, ServerComment, ServerDraft, ServerPost,
+    Session, Storage, StorageError, Tag, TagExperience, TagPreference, User,
+};
+use argon2::password_hash::{rand_core::OsRng, SaltString};
+use async_trait::async_trait;
+use mongodb::bson::oid::ObjectId;
+use std::collections::HashMap;
+use tokio::sync::RwLock;
+
+#[derive(Default)]
+struct Data {
+    users: Vec<User>,
+    posts: Vec<ServerPost>,
+    tags: Vec<Tag>,
+    revisions: Vec<PostRevision>,
+    tag_preferences: Vec<TagPreference>,
+    experiences: HashMap<ObjectId, Vec<TagExperience>>,
+    drafts: Vec<ServerDraft>,
+    sessions: Vec<Session>,
+}
+
+impl Data {
+    // タグの使われた数を増やす (なければ作る)
+    fn count_tags(&mut self, tags: &[String], delta: i64) {
+        for tag in tags {
+            match self.tags.iter_mut().find(|t| t.tag == *tag) {
+                Some(t) => t.count += delta,
+                None => self.tags.push(Tag {
+                    tag: tag.clone(),
+                    count: delta.max(0),
+                    alias_of: None,
+                    parent: None,
+                    reading: None,
+                }),
+            }
+        }
+    }
+}
+
+pub struct MemoryStorage {
+    data: RwLock<Data>,
+    setting: DbSetting,
+}
+
+impl MemoryStorage {
+    pub fn new() -> MemoryStorage {
+        // JWTの鍵は起動ごとにランダムに作る
+        MemoryStorage {
+            data: RwLock::new(Data::default()),
+            setting: DbSetting {
+                password_salt: SaltString::generate(&mut OsRng).to_string(),
+                jwt: SaltString::generate(&mut OsRng).to_string(),
+            },
+        }
+    }
+}
+
+impl Default for MemoryStorage {
+    fn default() -> Self {
+        MemoryStorage::new()
+    }
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    async fn find_user(&self, name: &str) -> Result<Option<User>, StorageError> {
+        let data = self.data.read().await;
+        Ok(data.users.iter().find(|u| u.name == name).cloned())
+    }
+
+    async fn find_users_by_id(&self, ids: &[ObjectId]) -> Result<Vec<User>, StorageError> {
+        let data = self.data.read().await;
+        Ok(data
+            .users
+            .iter()
+            .filter(|u| ids.contains(&u.id))
+            .cloned()
+            .collect())
+    }
+
+    async fn insert_user(&self, user: User) -> Result<bool, StorageError> {
+        let mut data = self.data.write().await;
+        if data.users.iter().any(|u| u.name == user.name) {
+            return Ok(false);
+        }
+        data.users.push(user);
+        Ok(true)
+    }
+
+    async fn update_profile(&self, user: User) -> Result<bool, StorageError> {
+        let mut data = self.data.write().await;
+        let Some(old) = data.users.iter_mut().find(|u| u.id == user.id) else {
+            return Ok(false);
+        };
+        old.display_name = user.display_name;
+        old.bio = user.bio;
+        old.avatar = user.avatar;
+        Ok(true)
+    }
+
+    async fn find_tag(&self, tag: &str) -> Result<Option<Tag>, StorageError> {
+        let data = self.data.read().await;
+        Ok(data.tags.iter().find(|t| t.tag == tag).cloned())
+    }
+
+    async fn all_tags(&self) -> Result<Vec<Tag>, StorageError> {
+        Ok(self.data.read().await.tags.clone())
+    }
+
+    async fn find_tags_with_prefix(
+        &self,
+        prefix: &str,
+        amount: i64,
+    ) -> Result<Vec<Tag>, StorageError> {
+        let prefix = prefix.to_lowercase();
+        let data = self.data.read().await;
+        let mut tags: Vec<Tag> = data
+            .tags
+            .iter()
+            .filter(|t| t.tag.to_lowercase().starts_with(&prefix))
+            .cloned()
+            .collect();
+        tags.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.tag.cmp(&b.tag)));
+        tags.truncate(amount.max(0) as usize);
+        Ok(tags)
+    }
+
+    async fn merge_tag(&self, from: &str, into: &str) -> Result<u64, StorageError> {
+        let mut data = self.data.write().await;
+        let mut merged = 0;
+        for post in data
+            .posts
+            .iter_mut()
+            .filter(|p| p.tag.iter().any(|t| t == from))
+        {
+            post.tag.retain(|t| t != from);
+            if !post.tag.iter().any(|t| t == into) {
+                post.tag.push(into.to_string());
+            }
+            merged += 1;
+        }
+        let count = data
+            .posts
+            .iter()
+            .filter(|p| !p.deleted && p.tag.iter().any(|t| t == into))
+            .count() as i64;
+        for name in [from, into] {
+            if !data.tags.iter().any(|t| t.tag == name) {
+                data.tags.push(Tag {
+                    tag: name.to_string(),
+                    count: 0,
+                    alias_of: None,
+                    parent: None,
+                    reading: None,
+                });
+            }
+        }
+        for tag in data.tags.iter_mut() {
+            if tag.tag == into {
+                tag.count = count;
+                tag.alias_of = None;
+            }
+            if tag.tag == from || tag.alias_of.as_deref() == Some(from) {
+                tag.count = 0;
+                tag.alias_of = Some(into.to_string());
+                tag.parent = None;
+            }
+            if tag.parent.as_deref() == Some(from) {
+                tag.parent = Some(into.to_string());
+            }
+        }
+        Ok(merged)
+    }
+
+    async fn set_tag_parent(&self, tag: &str, parent: Option<&str>) -> Result<(), StorageError> {
+        let mut data = self.data.write().await;
+        if let Some(t) = data.tags.iter_mut().find(|t| t.tag == tag) {
+            t.parent = parent.map(str::to_string);
+        }
+        Ok(())
+    }
+
+    async fn set_tag_reading(&self, tag: &str, reading: Option<&str>) -> Result<(), StorageError> {
+        let mut data = self.data.write().await;
+        if let Some(t) = data.tags.iter_mut().find(|t| t.tag == tag) {
+            t.reading = reading.map(str::to_string);
+        }
+        Ok(())
+    }
+
+    async fn find_tag_preference(&self, user_id: ObjectId) -> Result<TagPreference, StorageError> {
+        let data = self.data.read().await;
+        Ok(data
+            .tag_preferences
+            .iter()
+            .find(|p| p.user_id == user_id)
+            .cloned()
+            .unwrap_or_else(|| TagPreference::empty(user_id)))
+    }
+
+    async fn save_tag_preference(&self, preference: TagPreference) -> Result<(), StorageError> {
+        let mut data = self.data.write().await;
+        data.tag_preferences
+            .retain(|p| p.user_id != preference.user_id);
+        data.tag_preferences.push(preference);
+        Ok(())
+    }
+
+    async fn find_experiences(
+        &self,
+        user_id: ObjectId,
+    ) -> Result<Vec<TagExperience>, StorageError> {
+        let data = self.data.read().await;
+        Ok(data.experiences.get(&user_id).cloned().unwrap_or_default())
+    }
+
+    async fn save_experiences(
+        &self,
+        user_id: ObjectId,
+        experiences: Vec<TagExperience>,
+    ) -> Result<(), StorageError> {
+        self.data
+            .write()
+            .await
+            .experiences
+            .insert(user_id, experiences);
+        Ok(())
+    }
+
+    async fn insert_post(&self, mut post: ServerPost) -> Result<(), StorageError> {
+        post.id.get_or_insert_with(ObjectId::new);
+        let mut data = self.data.write().await;
+        data.count_tags(&post.tag, 1);
+        data.posts.push(post);
+        Ok(())
+    }
+
+    async fn find_post(&self, id: &str) -> Result<Option<ServerPost>, StorageError> {
+        let id = ObjectId::parse_str(id)?;
+        let data = self.data.read().await;
+        Ok(data.posts.iter().find(|p| p.id == Some(id)).cloned())
+    }
+
+    async fn update_post(
+        &self,
+        post: ServerPost,
+        revision: PostRevision,
+    ) -> Result<(), StorageError> {
+        let mut data = self.data.write().await;
+        let Some(old) = data.posts.iter_mut().find(|p| p.id == post.id) else {
+            return Ok(());
+        };
+        let old_tags = std::mem::replace(&mut old.tag, post.tag.clone());
+        old.title = post.title;
+        old.body = post.body;
+        old.body_html = post.body_html;
+        old.level = post.level;
+        old.updated_at = post.updated_at;
+        let removed: Vec<String> = old_tags
+            .iter()
+            .filter(|t| !post.tag.contains(t))
+            .cloned()
+            .collect();
+        let added: Vec<String> = post
+            .tag
+            .iter()
+            .filter(|t| !old_tags.contains(t))
+            .cloned()
+            .collect();
+        data.count_tags(&removed, -1);
+        data.count_tags(&added, 1);
+        data.revisions.push(revision);
+        Ok(())
+    }
+
+    async fn delete_post(&self, id: &str) -> Result<bool, StorageError> {
+        let id = ObjectId::parse_str(id)?;
+        let mut data = self.data.write().await;
+        let Some(post) = data
+            .posts
+            .iter_mut()
+            .find(|p| p.id == Some(id) && !p.deleted)
+        else {
+            return Ok(false);
+        };
+        post.deleted = true;
+        let tags = post.tag.clone();
+        data.count_tags(&tags, -1);
+        Ok(true)
+    }
+
+    async fn find_revisions(&self, post_id: &str) -> Result<Vec<PostRevision>, StorageError> {
+        let post_id = ObjectId::parse_str(post_id)?;
+        let data = self.data.read().await;
+        Ok(data
+            .revisions
+            .iter()
+            .filter(|r| r.post_id == post_id)
+            .cloned()
+            .collect())
+    }
+
+    // 索引は持たずに毎回全部の投稿を調べる
+    async fn find_posts(
+        &self,
+        filter: &PostFilter,
+        after: Option<PostCursor>,
+        limit: i64,
+    ) -> Result<Vec<ServerPost>, StorageError> {
+        let data = self.data.read().await;
+        let position = |p: &ServerPost| (filter.sort_key(p), p.id.unwrap_or_default());
+        let mut posts: Vec<ServerPost> = data
+            .posts
+            .iter()
+            .filter(|p| filter.matches(p))
+            .filter(|p| after.is_none_or(|after| position(p) < (after.key, after.id)))
+            .cloned()
+            .collect();
+        posts.sort_by_key(|p| std::cmp::Reverse(position(p)));
+        posts.truncate(limit.max(0) as usize);
+        Ok(posts)
+    }
+
+    async fn push_comment(
+        &self,
+        post_id: &str,
+        comment: ServerComment,
+    ) -> Result<bool, StorageError> {
+        let id = ObjectId::parse_str(post_id)?;
+        let mut data = self.data.write().await;
+        match data.posts.iter_mut().find(|p| p.id == Some(id)) {
+            Some(post) => {
+                post.comment.push(comment);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    async fn update_comment(
+        &self,
+        post_id: &str,
+        comment: ServerComment,
+    ) -> Result<bool, StorageError> {
+        let id = ObjectId::parse_str(post_id)?;
+        let mut data = self.data.write().await;
+        let Some(old) = data
+            .posts
+            .iter_mut()
+            .filter(|p| p.id == Some(id))
+            .flat_map(|p| p.comment.iter_mut())
+            .find(|c| c.id == comment.id)
+        else {
+            return Ok(false);
+        };
+        old.body = comment.body;
+        old.body_html = comment.body_html;
+        old.updated_at = comment.updated_at;
+        old.deleted = comment.deleted;
+        old.hidden = comment.hidden;
+        Ok(true)
+    }
+
+    async fn save_draft(&self, draft: ServerDraft) -> Result<(), StorageError> {
+        let mut data = self.data.write().await;
+        data.drafts.retain(|d| d.id != draft.id);
+        data.drafts.push(draft);
+        Ok(())
+    }
+
+    async fn find_draft(&self, id: &str) -> Result<Option<ServerDraft>, StorageError> {
+        let id = ObjectId::parse_str(id)?;
+        let data = self.data.read().await;
+        Ok(data.drafts.iter().find(|d| d.id == id).cloned())
+    }
+
+    async fn find_drafts(&self, author: ObjectId) -> Result<Vec<ServerDraft>, StorageError> {
+        let data = self.data.read().await;
+        let mut drafts: Vec<ServerDraft> = data
+            .drafts
+            .iter()
+            .filter(|d| d.author == author)
+            .cloned()
+            .collect();
+        drafts.sort_by(|a, b| b.updated_at.cmp(&a.updated_at).then(b.id.cmp(&a.id)));
+        Ok(drafts)
+    }
+
+    async fn delete_draft(&self, id: &str) -> Result<(), StorageError> {
+        let id = ObjectId::parse_str(id)?;
+        self.data.write().await.drafts.retain(|d| d.id != id);
+        Ok(())
+    }
+
+    async fn insert_session(&self, session: Session) -> Result<(), StorageError> {
+        self.data.write().await.sessions.push(session);
+        Ok(())
+    }
+
+    async fn find_session(&self, id: &str) -> Result<Option<Session>, StorageError> {
+        let data = self.data.read().await;
+        Ok(data.sessions.iter().find(|s| s.id == id).cloned())
+    }
+
+    async fn rotate_session(
+        &self,
+        id: &str,
+        old_hash: &str,
+        new_hash: &str,
+        expires_at: i64,
+        rotated_at: i64,
+    ) -> Result<bool, StorageError> {
+        let mut data = self.data.write().await;
+        match data
+            .sessions
+            .iter_mut()
+            .find(|s| s.id == id && s.refresh_hash == old_hash)
+        {
+            Some(session) => {
+                session.previous_hash =
+                    std::mem::replace(&mut session.refresh_hash, new_hash.to_string());
+                session.expires_at = expires_at;
+                session.rotated_at = rotated_at;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    async fn delete_session(&self, id: &str) -> Result<(), StorageError> {
+        self.data.write().await.sessions.retain(|s| s.id != id);
+        Ok(())
+    }
+
+    async fn delete_sessions_of(&self, user_id: &str) -> Result<(), StorageError> {
+        self.data
+            .write()
+            .await
+            .sessions
+            .retain(|s| s.user_id != user_id);
+        Ok(())
+    }
+
+    async fn setting(&self) -> Result<Option<DbSetting>, StorageError> {
+        Ok(Some(self.setting.clone()))
+    }
+}
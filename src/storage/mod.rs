@@ -0,0 +1,430 @@
+// 永続化層
+// server.rsの各API関数はこのトレイトを通してDBにアクセスする
+// 起動時にMongoDB・SQLite・インメモリのどれかを選ぶ
+
+mod memory;
+mod mongo;
+mod sqlite;
+#[cfg(test)]
+mod tests;
+
+pub use crate::query::{ExperienceLevel, PostSort};
+pub use crate::server::{Attachment, TagExperience};
+use crate::{
+    config::{Backend, StorageConfig},
+    search,
+};
+use async_trait::async_trait;
+use mongodb::bson::oid::ObjectId;
+use serde::{Deserialize, Serialize};
+use std::{fmt, sync::Arc};
+use tokio::sync::OnceCell;
+
+pub use memory::MemoryStorage;
+pub use mongo::MongoStorage;
+pub use sqlite::SqliteStorage;
+
+// DBに乗せるレコードを表すstruct
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct DbSetting {
+    pub password_salt: String,
+    pub jwt: String,
+}
+
+// 投稿に付けたタグは insert_post で自動的に増える
+#[derive(Deserialize, Serialize, Clone)]
+pub struct Tag {
+    pub tag: String,
+    // このタグが付いた投稿の数
+    #[serde(default)]
+    pub count: i64,
+    // 別名なら正式なタグ (統合されたタグ)
+    #[serde(default)]
+    pub alias_of: Option<String>,
+    // 親のタグ (例: "leptos" の親は "web")
+    #[serde(default)]
+    pub parent: Option<String>,
+    // 読み (ひらがな、モデレーターが付ける)
+    // 漢字のタグを読みやローマ字でも入力補完できるようにする
+    #[serde(default)]
+    pub reading: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct User {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    // ログインに使う名前 (変わらない)
+    pub name: String,
+    pub password_hash: String,
+    // プロフィール (表示名が空なら名前をそのまま出す)
+    #[serde(default)]
+    pub display_name: String,
+    #[serde(default)]
+    pub bio: String,
+    // アイコンにした画像 (添付ファイルのID)
+    #[serde(default)]
+    pub avatar: Option<String>,
+}
+
+// ユーザーがフォロー・ミュートしているタグ
+#[derive(Deserialize, Serialize, Clone)]
+pub struct TagPreference {
+    #[serde(rename = "_id")]
+    pub user_id: ObjectId,
+    pub followed: Vec<String>,
+    pub muted: Vec<String>,
+}
+
+impl TagPreference {
+    pub fn empty(user_id: ObjectId) -> TagPreference {
+        TagPreference {
+            user_id,
+            followed: vec![],
+            muted: vec![],
+        }
+    }
+}
+
+// 投稿者が見つからない古いデータに入れるID
+pub const UNKNOWN_USER: ObjectId = ObjectId::from_bytes([0; 12]);
+
+// 投稿者・返信者はユーザーIDで持ち、名前は読み出すときに引く
+#[derive(Deserialize, Serialize, Clone)]
+pub struct ServerComment {
+    pub id: ObjectId,
+    pub author: ObjectId,
+    pub body: String,
+    // UNIX時間 (秒)
+    pub created_at: i64,
+    // 返信先の返信 (投稿への直接の返信ならNone)
+    pub parent: Option<ObjectId>,
+    // 最後に編集した時間 (編集していなければNone)
+    #[serde(default)]
+    pub updated_at: Option<i64>,
+    // 削除・非表示にした返信もスレッドの形を保つために残しておく
+    #[serde(default)]
+    pub deleted: bool,
+    // 投稿者が非表示にした
+    #[serde(default)]
+    pub hidden: bool,
+    // 本文をHTMLにしたもの (保存するときに作る、古い返信では空)
+    #[serde(default)]
+    pub body_html: String,
+    #[serde(default)]
+    pub attachments: Vec<Attachment>,
+}
+
+impl ServerComment {
+    // 削除・非表示にした返信の本文は検索にも表示にも使わない
+    pub fn is_visible(&self) -> bool {
+        !self.deleted && !self.hidden
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct ServerPost {
+    pub author: ObjectId,
+    pub body: String,
+    #[serde(default)]
+    pub attachments: Vec<Attachment>,
+    pub tag: Vec<String>,
+    pub title: String,
+    pub comment: Vec<ServerComment>,
+    // 以前は is_advanced (経験者かどうか) だった (MongoStorage::connect で直す)
+    #[serde(default)]
+    pub level: ExperienceLevel,
+    // UNIX時間 (秒)
+    #[serde(default)]
+    pub created_at: i64,
+    // 最後に編集した時間 (編集していなければNone)
+    #[serde(default)]
+    pub updated_at: Option<i64>,
+    // 削除した投稿は消さずに残し、一覧や検索には出さない
+    #[serde(default)]
+    pub deleted: bool,
+    // 本文をHTMLにしたもの (保存するときに作る、古い投稿では空)
+    #[serde(default)]
+    pub body_html: String,
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+}
+
+impl ServerPost {
+    // 削除・非表示にした返信は数えない
+    pub fn reply_count(&self) -> usize {
+        self.comment.iter().filter(|c| c.is_visible()).count()
+    }
+}
+
+// 編集する前の投稿の内容
+#[derive(Deserialize, Serialize, Clone)]
+pub struct PostRevision {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    pub post_id: ObjectId,
+    pub title: String,
+    pub body: String,
+    pub tag: Vec<String>,
+    #[serde(default)]
+    pub level: ExperienceLevel,
+    // この内容になった時間
+    pub created_at: i64,
+}
+
+// 投稿する前の下書き (ユーザーごとに複数持てる)
+#[derive(Deserialize, Serialize, Clone)]
+pub struct ServerDraft {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    pub author: ObjectId,
+    pub title: String,
+    pub body: String,
+    pub tag: Vec<String>,
+    #[serde(default)]
+    pub level: ExperienceLevel,
+    // 最後に保存した時間
+    pub updated_at: i64,
+}
+
+// 投稿の絞り込み (query::PostQuery をサーバーで解決したもの)
+#[derive(Clone, Default)]
+pub struct PostFilter {
+    // どのグループからも1つ以上付いている
+    // (tag:a|b は1つのグループになり、親のタグで探すと子孫のタグもグループに入る)
+    pub all_tags: Vec<Vec<String>>,
+    // どれも付いていない
+    pub not_tags: Vec<String>,
+    pub level: Option<ExperienceLevel>,
+    pub author: Option<ObjectId>,
+    // このユーザーの (削除・非表示にしていない) 返信が付いている
+    pub commenter: Option<ObjectId>,
+    // 投稿日時の範囲 (UNIX時間、untilは含まない)
+    pub since: Option<i64>,
+    pub until: Option<i64>,
+    // Some(true)なら (削除・非表示にしていない) 返信があるもの、Some(false)ならないもの
+    pub replied: Option<bool>,
+    // 全文検索の語 (search::query_terms) をすべて含む
+    pub terms: Vec<String>,
+    pub sort: PostSort,
+}
+
+impl PostFilter {
+    pub fn matches(&self, post: &ServerPost) -> bool {
+        !post.deleted
+            && self
+                .all_tags
+                .iter()
+                .all(|group| group.iter().any(|t| post.tag.contains(t)))
+            && !self.not_tags.iter().any(|t| post.tag.contains(t))
+            && self.level.is_none_or(|l| post.level == l)
+            && self.author.is_none_or(|a| post.author == a)
+            && self
+                .commenter
+                .is_none_or(|a| post.comment.iter().any(|c| c.author == a && c.is_visible()))
+            && self.since.is_none_or(|s| post.created_at >= s)
+            && self.until.is_none_or(|u| post.created_at < u)
+            && self.replied.is_none_or(|r| (post.reply_count() > 0) == r)
+            && (self.terms.is_empty() || {
+                let index = search::index_terms(&search::post_text(post));
+                self.terms.iter().all(|t| index.binary_search(t).is_ok())
+            })
+    }
+
+    // 並べ替えに使う値 (大きい順に並べる)
+    pub fn sort_key(&self, post: &ServerPost) -> i64 {
+        match self.sort {
+            PostSort::Newest => post.created_at,
+            PostSort::MostReplied => post.reply_count() as i64,
+            PostSort::MostActive => post
+                .comment
+                .iter()
+                .map(|c| c.created_at)
+                .fold(post.created_at, i64::max),
+        }
+    }
+}
+
+// ページ送りの位置 (前のページの最後の投稿の並べ替えの値とID)
+#[derive(Clone, Copy)]
+pub struct PostCursor {
+    pub key: i64,
+    pub id: ObjectId,
+}
+
+// ログインのセッション
+// リフレッシュトークンは "{id}.{secret}" の形で、secretのハッシュだけを保存する
+#[derive(Deserialize, Serialize, Clone)]
+pub struct Session {
+    #[serde(rename = "_id")]
+    pub id: String,
+    // 古いセッションには入っていないので、空ならログアウト扱いにする
+    #[serde(default)]
+    pub user_id: String,
+    pub name: String,
+    pub refresh_hash: String,
+    pub expires_at: i64,
+    // ひとつ前のリフレッシュトークンのハッシュと、入れ替えた時間
+    // (同時に送られたリクエストが古いトークンで来ても、少しの間は受け付けるため)
+    #[serde(default)]
+    pub previous_hash: String,
+    #[serde(default)]
+    pub rotated_at: i64,
+}
+
+// エラー
+
+#[derive(Debug)]
+pub struct StorageError(pub String);
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "storage error: {}", self.0)
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+impl From<mongodb::error::Error> for StorageError {
+    fn from(e: mongodb::error::Error) -> Self {
+        StorageError(e.to_string())
+    }
+}
+
+impl From<mongodb::bson::oid::Error> for StorageError {
+    fn from(e: mongodb::bson::oid::Error) -> Self {
+        StorageError(e.to_string())
+    }
+}
+
+// トレイト
+
+#[async_trait]
+pub trait Storage: Send + Sync {
+    // users
+    async fn find_user(&self, name: &str) -> Result<Option<User>, StorageError>;
+    async fn find_users_by_id(&self, ids: &[ObjectId]) -> Result<Vec<User>, StorageError>;
+    // 同じ名前のユーザーがいれば入れずにfalse
+    async fn insert_user(&self, user: User) -> Result<bool, StorageError>;
+    // 同じIDのユーザーの表示名・自己紹介・アイコンを書き換える (見つからなければfalse)
+    async fn update_profile(&self, user: User) -> Result<bool, StorageError>;
+
+    // tags
+    async fn find_tag(&self, tag: &str) -> Result<Option<Tag>, StorageError>;
+    // 入力補完の索引を作るのに使う
+    async fn all_tags(&self) -> Result<Vec<Tag>, StorageError>;
+    // よく使われている順にamount個まで返す (0以下なら何も返さない)
+    async fn find_tags_with_prefix(
+        &self,
+        prefix: &str,
+        amount: i64,
+    ) -> Result<Vec<Tag>, StorageError>;
+    // fromの付いた投稿をintoに付け替え、fromをintoの別名にする (付け替えた投稿の数を返す)
+    // fromの別名だったタグもintoの別名になり、fromの子はintoの子になる
+    async fn merge_tag(&self, from: &str, into: &str) -> Result<u64, StorageError>;
+    async fn set_tag_parent(&self, tag: &str, parent: Option<&str>) -> Result<(), StorageError>;
+    async fn set_tag_reading(&self, tag: &str, reading: Option<&str>) -> Result<(), StorageError>;
+    // まだ何も設定していなければ空のものを返す
+    async fn find_tag_preference(&self, user_id: ObjectId) -> Result<TagPreference, StorageError>;
+    async fn save_tag_preference(&self, preference: TagPreference) -> Result<(), StorageError>;
+    // ユーザーがタグごとに設定した経験 (設定した順)
+    async fn find_experiences(&self, user_id: ObjectId)
+        -> Result<Vec<TagExperience>, StorageError>;
+    // すべて置き換える
+    async fn save_experiences(
+        &self,
+        user_id: ObjectId,
+        experiences: Vec<TagExperience>,
+    ) -> Result<(), StorageError>;
+
+    // posts
+    // 投稿のタグがまだなければ作り、使われた数を1増やす
+    async fn insert_post(&self, post: ServerPost) -> Result<(), StorageError>;
+    // 削除した投稿も返す (ほかの読み出しは削除した投稿を返さない)
+    async fn find_post(&self, id: &str) -> Result<Option<ServerPost>, StorageError>;
+    // タイトル・本文 (HTMLも)・タグ・経験の度合いと編集した時間を書き換え、前の内容をrevisionとして残す
+    // タグの使われた数も付け直す
+    async fn update_post(
+        &self,
+        post: ServerPost,
+        revision: PostRevision,
+    ) -> Result<(), StorageError>;
+    // 削除済みにしてタグの使われた数を減らす (見つからないか削除済みならfalse)
+    async fn delete_post(&self, id: &str) -> Result<bool, StorageError>;
+    // 古い順
+    async fn find_revisions(&self, post_id: &str) -> Result<Vec<PostRevision>, StorageError>;
+    // filterに合う投稿を filter.sort_key の降順 (同じならIDの降順) に返す
+    // afterを指定するとその位置より後ろのものだけ返す
+    async fn find_posts(
+        &self,
+        filter: &PostFilter,
+        after: Option<PostCursor>,
+        limit: i64,
+    ) -> Result<Vec<ServerPost>, StorageError>;
+
+    // comments
+    // 投稿が見つからなければfalse
+    async fn push_comment(
+        &self,
+        post_id: &str,
+        comment: ServerComment,
+    ) -> Result<bool, StorageError>;
+    // 同じIDの返信の本文 (HTMLも)・編集した時間・削除・非表示を書き換える (見つからなければfalse)
+    async fn update_comment(
+        &self,
+        post_id: &str,
+        comment: ServerComment,
+    ) -> Result<bool, StorageError>;
+
+    // drafts
+    // 同じIDの下書きがあれば書き換える
+    async fn save_draft(&self, draft: ServerDraft) -> Result<(), StorageError>;
+    async fn find_draft(&self, id: &str) -> Result<Option<ServerDraft>, StorageError>;
+    // 最後に保存した時間の新しい順
+    async fn find_drafts(&self, author: ObjectId) -> Result<Vec<ServerDraft>, StorageError>;
+    async fn delete_draft(&self, id: &str) -> Result<(), StorageError>;
+
+    // sessions
+    async fn insert_session(&self, session: Session) -> Result<(), StorageError>;
+    async fn find_session(&self, id: &str) -> Result<Option<Session>, StorageError>;
+    // refresh_hashがold_hashのときだけ差し替え、old_hashをprevious_hashに残す (差し替えたらtrue)
+    async fn rotate_session(
+        &self,
+        id: &str,
+        old_hash: &str,
+        new_hash: &str,
+        expires_at: i64,
+        rotated_at: i64,
+    ) -> Result<bool, StorageError>;
+    async fn delete_session(&self, id: &str) -> Result<(), StorageError>;
+    async fn delete_sessions_of(&self, user_id: &str) -> Result<(), StorageError>;
+
+    // config
+    async fn setting(&self) -> Result<Option<DbSetting>, StorageError>;
+}
+
+// 起動時の選択
+
+static STORAGE: OnceCell<Arc<dyn Storage>> = OnceCell::const_new();
+
+pub async fn init(config: &StorageConfig) -> Result<(), StorageError> {
+    let storage: Arc<dyn Storage> = match config.backend {
+        Backend::Mongo => {
+            let uri = config.uri.as_deref().unwrap_or_default();
+            Arc::new(MongoStorage::connect(uri, &config.database).await?)
+        }
+        Backend::Sqlite => Arc::new(SqliteStorage::open(&config.sqlite_path)?),
+        Backend::Memory => Arc::new(MemoryStorage::new()),
+    };
+    STORAGE
+        .set(storage)
+        .map_err(|_| StorageError("storage is already initialized".to_string()))
+}
+
+pub fn get() -> Arc<dyn Storage> {
+    STORAGE
+        .get()
+        .expect("storage::init must be called before serving requests")
+        .clone()
+}
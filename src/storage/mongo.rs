@@ -0,0 +1,710 @@
+use super::{
+    DbSetting, PostCursor, PostFilter, PostRevision, PostSort, ServerComment, ServerDraft,
+    ServerPost, Session, Storage, StorageError, Tag, TagExperience, TagPreference, User,
+    UNKNOWN_USER,
+};
+use crate::search;
+use async_trait::async_trait;
+use futures::TryStreamExt;
+use mongodb::{
+    bson::{doc, from_document, oid::ObjectId, to_bson, to_document, Bson, Document},
+    error::{Error, ErrorKind, WriteFailure},
+    options::IndexOptions,
+    Client, Database, IndexModel,
+};
+use std::collections::HashMap;
+
+pub struct MongoStorage {
+    db: Database,
+}
+
+impl MongoStorage {
+    pub async fn connect(uri: &str, database: &str) -> Result<MongoStorage, StorageError> {
+        let db = Client::with_uri_str(uri).await?.database(database);
+        let storage = MongoStorage { db };
+        storage.index_users().await?;
+        storage.migrate_posts().await?;
+        storage.migrate_levels().await?;
+        storage.index_posts().await?;
+        storage.count_tags().await?;
+        Ok(storage)
+    }
+
+    // 同時に登録しても同じ名前のユーザーができないようにする
+    // 以前は同じ名前でも登録できたので、すでに重なっていれば索引を作らずに名前を挙げて止める
+    // (どちらを残すかは決められないので、手で名前を変えてから起動し直す)
+    async fn index_users(&self) -> Result<(), StorageError> {
+        let db_user = self.db.collection::<Document>("users");
+        let duplicates: Vec<Document> = db_user
+            .aggregate(vec![
+                doc! {"$group": {"_id": "$name", "ids": {"$push": "$_id"}, "count": {"$sum": 1}}},
+                doc! {"$match": {"count": {"$gt": 1}}},
+            ])
+            .await?
+            .try_collect()
+            .await?;
+        if !duplicates.is_empty() {
+            let names: Vec<String> = duplicates
+                .iter()
+                .map(|d| {
+                    let ids: Vec<String> = d
+                        .get_array("ids")
+                        .map(|ids| {
+                            ids.iter()
+                                .filter_map(Bson::as_object_id)
+                                .map(|id| id.to_hex())
+                                .collect()
+                        })
+                        .unwrap_or_default();
+                    let name = d.get_str("_id").unwrap_or_default();
+                    format!("{name} ({})", ids.join(", "))
+                })
+                .collect();
+            return Err(StorageError(format!(
+                "users with the same name must be renamed before starting: {}",
+                names.join("; ")
+            )));
+        }
+        db_user
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! {"name": 1})
+                    .options(IndexOptions::builder().unique(true).build())
+                    .build(),
+            )
+            .await?;
+        Ok(())
+    }
+
+    // タグの使われた数がまだ入っていなければ、投稿から数えて入れる
+    async fn count_tags(&self) -> Result<(), StorageError> {
+        let db_tag = self.db.collection::<Document>("tags");
+        db_tag
+            .create_index(IndexModel::builder().keys(doc! {"tag": 1}).build())
+            .await?;
+        if db_tag
+            .find_one(doc! {"count": {"$exists": true}})
+            .await?
+            .is_some()
+        {
+            return Ok(());
+        }
+        let db_post = self.db.collection::<Document>("posts");
+        let counts: Vec<Document> = db_post
+            .aggregate(vec![
+                doc! {"$match": {"deleted": {"$ne": true}}},
+                doc! {"$unwind": "$tag"},
+                doc! {"$group": {"_id": "$tag", "count": {"$sum": 1}}},
+            ])
+            .await?
+            .try_collect()
+            .await?;
+        for count in counts {
+            let (Ok(tag), Some(count)) = (count.get_str("_id"), count.get("count")) else {
+                continue;
+            };
+            db_tag
+                .update_one(doc! {"tag": tag}, doc! {"$set": {"count": count}})
+                .upsert(true)
+                .await?;
+        }
+        Ok(())
+    }
+
+    // 全文検索の語を投稿の terms に入れておく (まだ入っていない投稿に付ける)
+    async fn index_posts(&self) -> Result<(), StorageError> {
+        let db_post = self.db.collection::<ServerPost>("posts");
+        db_post
+            .create_index(IndexModel::builder().keys(doc! {"terms": 1}).build())
+            .await?;
+        let posts: Vec<ServerPost> = db_post
+            .find(doc! {"terms": {"$exists": false}})
+            .await?
+            .try_collect()
+            .await?;
+        for post in posts {
+            let terms = search::index_terms(&search::post_text(&post));
+            db_post
+                .update_one(doc! {"_id": post.id}, doc! {"$set": {"terms": terms}})
+                .await?;
+        }
+        Ok(())
+    }
+
+    // 古い形式の投稿を今の形式に直す
+    // - 投稿者・返信者を名前 (name) で持っていたので、ユーザーID (author) に置き換える
+    // - 返信にIDと日時がなかったので付ける
+    // - 投稿に日時がなかったので、IDの時刻を入れる
+    async fn migrate_posts(&self) -> Result<(), StorageError> {
+        let db_post = self.db.collection::<Document>("posts");
+        let legacy = doc! {"$or": [
+            {"author": {"$exists": false}},
+            {"created_at": {"$exists": false}},
+            {"comment": {"$elemMatch": {"author": {"$exists": false}}}},
+            {"comment": {"$elemMatch": {"id": {"$exists": false}}}},
+        ]};
+        let posts: Vec<Document> = db_post.find(legacy).await?.try_collect().await?;
+        let mut ids = HashMap::new();
+        for mut post in posts {
+            let Ok(id) = post.get_object_id("_id") else {
+                continue;
+            };
+            if !post.contains_key("author") {
+                let name = post.get_str("name").unwrap_or_default().to_string();
+                let author = self.user_id_of(&mut ids, name).await?;
+                post.insert("author", author);
+                post.remove("name");
+            }
+            if !post.contains_key("created_at") {
+                post.insert("created_at", id.timestamp().timestamp_millis() / 1000);
+            }
+            let mut comments = vec![];
+            for comment in post.get_array("comment").cloned().unwrap_or_default() {
+                let Some(mut comment) = comment.as_document().cloned() else {
+                    continue;
+                };
+                if !comment.contains_key("author") {
+                    let name = comment.get_str("name").unwrap_or_default().to_string();
+                    let author = self.user_id_of(&mut ids, name).await?;
+                    comment.insert("author", author);
+                    comment.remove("name");
+                }
+                if !comment.contains_key("id") {
+                    comment.insert("id", ObjectId::new());
+                    comment.insert("created_at", 0_i64);
+                    comment.insert("parent", Bson::Null);
+                }
+                comments.push(comment);
+            }
+            post.insert("comment", comments);
+            db_post.replace_one(doc! {"_id": id}, post).await?;
+        }
+        Ok(())
+    }
+
+    // 経験者かどうか (is_advanced) だったものを経験の度合い (level) にする
+    async fn migrate_levels(&self) -> Result<(), StorageError> {
+        for name in ["posts", "post_revisions", "drafts"] {
+            let collection = self.db.collection::<Document>(name);
+            collection
+                .update_many(
+                    doc! {"is_advanced": true},
+                    doc! {"$set": {"level": "advanced"}, "$unset": {"is_advanced": ""}},
+                )
+                .await?;
+            collection
+                .update_many(
+                    doc! {"is_advanced": {"$exists": true}},
+                    doc! {"$set": {"level": "beginner"}, "$unset": {"is_advanced": ""}},
+                )
+                .await?;
+        }
+        Ok(())
+    }
+
+    // 消えたユーザーは UNKNOWN_USER にする
+    async fn user_id_of(
+        &self,
+        cache: &mut HashMap<String, ObjectId>,
+        name: String,
+    ) -> Result<ObjectId, StorageError> {
+        if let Some(id) = cache.get(&name) {
+            return Ok(*id);
+        }
+        let id = self
+            .find_user(&name)
+            .await?
+            .map_or(UNKNOWN_USER, |user| user.id);
+        cache.insert(name, id);
+        Ok(id)
+    }
+}
+
+// 正規表現の特別な文字をエスケープする (入力をそのままの文字列として探す)
+fn escape_regex(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        if "\\^$.|?*+()[]{}".contains(c) {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
+// 一意なインデックスに引っかかった (E11000)
+fn is_duplicate_key(e: &Error) -> bool {
+    matches!(&*e.kind, ErrorKind::Write(WriteFailure::WriteError(w)) if w.code == 11000)
+}
+
+#[async_trait]
+impl Storage for MongoStorage {
+    async fn find_user(&self, name: &str) -> Result<Option<User>, StorageError> {
+        let db_user = self.db.collection::<User>("users");
+        Ok(db_user.find_one(doc! {"name": name}).await?)
+    }
+
+    async fn find_users_by_id(&self, ids: &[ObjectId]) -> Result<Vec<User>, StorageError> {
+        let db_user = self.db.collection::<User>("users");
+        let result = db_user.find(doc! {"_id": {"$in": ids}}).await?;
+        Ok(result.try_collect().await?)
+    }
+
+    async fn insert_user(&self, user: User) -> Result<bool, StorageError> {
+        let db_user = self.db.collection::<User>("users");
+        match db_user.insert_one(user).await {
+            Ok(_) => Ok(true),
+            Err(e) if is_duplicate_key(&e) => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn update_profile(&self, user: User) -> Result<bool, StorageError> {
+        let db_user = self.db.collection::<User>("users");
+        let result = db_user
+            .update_one(
+                doc! {"_id": user.id},
+                doc! {"$set": {
+                    "display_name": user.display_name,
+                    "bio": user.bio,
+                    "avatar": user.avatar,
+                }},
+            )
+            .await?;
+        Ok(result.matched_count > 0)
+    }
+
+    async fn find_tag(&self, tag: &str) -> Result<Option<Tag>, StorageError> {
+        let db_tag = self.db.collection::<Tag>("tags");
+        Ok(db_tag.find_one(doc! {"tag": tag}).await?)
+    }
+
+    async fn all_tags(&self) -> Result<Vec<Tag>, StorageError> {
+        let db_tag = self.db.collection::<Tag>("tags");
+        Ok(db_tag.find(doc! {}).await?.try_collect().await?)
+    }
+
+    async fn find_tags_with_prefix(
+        &self,
+        prefix: &str,
+        amount: i64,
+    ) -> Result<Vec<Tag>, StorageError> {
+        // limit(0) は上限なしになってしまう
+        if amount <= 0 {
+            return Ok(vec![]);
+        }
+        let db_tag = self.db.collection::<Tag>("tags");
+        let result = db_tag
+            .find(doc! {"tag": {"$regex": format!("^{}", escape_regex(prefix)), "$options": "i"}})
+            .sort(doc! {"count": -1, "tag": 1})
+            .limit(amount)
+            .await?;
+        Ok(result.try_collect().await?)
+    }
+
+    async fn merge_tag(&self, from: &str, into: &str) -> Result<u64, StorageError> {
+        let db_post = self.db.collection::<Document>("posts");
+        let merged = db_post
+            .update_many(doc! {"tag": from}, doc! {"$addToSet": {"tag": into}})
+            .await?
+            .matched_count;
+        db_post
+            .update_many(doc! {"tag": from}, doc! {"$pull": {"tag": from}})
+            .await?;
+        let count = db_post
+            .count_documents(doc! {"tag": into, "deleted": {"$ne": true}})
+            .await? as i64;
+        let db_tag = self.db.collection::<Tag>("tags");
+        db_tag
+            .update_one(
+                doc! {"tag": into},
+                doc! {"$set": {"count": count, "alias_of": Bson::Null}},
+            )
+            .upsert(true)
+            .await?;
+        db_tag
+            .update_one(
+                doc! {"tag": from},
+                doc! {"$set": {"count": 0, "alias_of": into, "parent": Bson::Null}},
+            )
+            .upsert(true)
+            .await?;
+        db_tag
+            .update_many(doc! {"alias_of": from}, doc! {"$set": {"alias_of": into}})
+            .await?;
+        db_tag
+            .update_many(doc! {"parent": from}, doc! {"$set": {"parent": into}})
+            .await?;
+        Ok(merged)
+    }
+
+    async fn set_tag_parent(&self, tag: &str, parent: Option<&str>) -> Result<(), StorageError> {
+        let db_tag = self.db.collection::<Tag>("tags");
+        db_tag
+            .update_one(doc! {"tag": tag}, doc! {"$set": {"parent": parent}})
+            .await?;
+        Ok(())
+    }
+
+    async fn set_tag_reading(&self, tag: &str, reading: Option<&str>) -> Result<(), StorageError> {
+        let db_tag = self.db.collection::<Tag>("tags");
+        db_tag
+            .update_one(doc! {"tag": tag}, doc! {"$set": {"reading": reading}})
+            .await?;
+        Ok(())
+    }
+
+    async fn find_tag_preference(&self, user_id: ObjectId) -> Result<TagPreference, StorageError> {
+        let db_preference = self.db.collection::<TagPreference>("tag_preferences");
+        Ok(db_preference
+            .find_one(doc! {"_id": user_id})
+            .await?
+            .unwrap_or_else(|| TagPreference::empty(user_id)))
+    }
+
+    async fn save_tag_preference(&self, preference: TagPreference) -> Result<(), StorageError> {
+        let db_preference = self.db.collection::<TagPreference>("tag_preferences");
+        db_preference
+            .replace_one(doc! {"_id": preference.user_id}, preference)
+            .upsert(true)
+            .await?;
+        Ok(())
+    }
+
+    // ユーザーごとに {_id: ユーザーID, experiences: [...]} の形で持つ
+    async fn find_experiences(
+        &self,
+        user_id: ObjectId,
+    ) -> Result<Vec<TagExperience>, StorageError> {
+        let db_experience = self.db.collection::<Document>("experiences");
+        let Some(document) = db_experience.find_one(doc! {"_id": user_id}).await? else {
+            return Ok(vec![]);
+        };
+        let experiences = document
+            .get("experiences")
+            .cloned()
+            .unwrap_or(Bson::Array(vec![]));
+        mongodb::bson::from_bson(experiences).map_err(|e| StorageError(e.to_string()))
+    }
+
+    async fn save_experiences(
+        &self,
+        user_id: ObjectId,
+        experiences: Vec<TagExperience>,
+    ) -> Result<(), StorageError> {
+        let db_experience = self.db.collection::<Document>("experiences");
+        let experiences = to_bson(&experiences).map_err(|e| StorageError(e.to_string()))?;
+        db_experience
+            .replace_one(
+                doc! {"_id": user_id},
+                doc! {"_id": user_id, "experiences": experiences},
+            )
+            .upsert(true)
+            .await?;
+        Ok(())
+    }
+
+    async fn insert_post(&self, post: ServerPost) -> Result<(), StorageError> {
+        let db_post = self.db.collection::<Document>("posts");
+        let mut document = to_document(&post).map_err(|e| StorageError(e.to_string()))?;
+        document.insert("terms", search::index_terms(&search::post_text(&post)));
+        db_post.insert_one(document).await?;
+        let db_tag = self.db.collection::<Tag>("tags");
+        for tag in &post.tag {
+            db_tag
+                .update_one(doc! {"tag": tag}, doc! {"$inc": {"count": 1}})
+                .upsert(true)
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn find_post(&self, id: &str) -> Result<Option<ServerPost>, StorageError> {
+        let id = ObjectId::parse_str(id)?;
+        let db_post = self.db.collection::<ServerPost>("posts");
+        Ok(db_post.find_one(doc! {"_id": id}).await?)
+    }
+
+    async fn update_post(
+        &self,
+        post: ServerPost,
+        revision: PostRevision,
+    ) -> Result<(), StorageError> {
+        let db_post = self.db.collection::<ServerPost>("posts");
+        let Some(old) = db_post.find_one(doc! {"_id": post.id}).await? else {
+            return Ok(());
+        };
+        db_post
+            .update_one(
+                doc! {"_id": post.id},
+                doc! {"$set": {
+                    "title": &post.title,
+                    "body": &post.body,
+                    "body_html": &post.body_html,
+                    "tag": &post.tag,
+                    "level": post.level.as_str(),
+                    "updated_at": post.updated_at,
+                    "terms": search::index_terms(&search::post_text(&post)),
+                }},
+            )
+            .await?;
+        let db_tag = self.db.collection::<Tag>("tags");
+        for tag in post.tag.iter().filter(|t| !old.tag.contains(t)) {
+            db_tag
+                .update_one(doc! {"tag": tag}, doc! {"$inc": {"count": 1}})
+                .upsert(true)
+                .await?;
+        }
+        for tag in old.tag.iter().filter(|t| !post.tag.contains(t)) {
+            db_tag
+                .update_one(doc! {"tag": tag}, doc! {"$inc": {"count": -1}})
+                .await?;
+        }
+        let db_revision = self.db.collection::<PostRevision>("post_revisions");
+        db_revision.insert_one(revision).await?;
+        Ok(())
+    }
+
+    async fn delete_post(&self, id: &str) -> Result<bool, StorageError> {
+        let id = ObjectId::parse_str(id)?;
+        let db_post = self.db.collection::<ServerPost>("posts");
+        let Some(post) = db_post
+            .find_one_and_update(
+                doc! {"_id": id, "deleted": {"$ne": true}},
+                doc! {"$set": {"deleted": true}},
+            )
+            .await?
+        else {
+            return Ok(false);
+        };
+        let db_tag = self.db.collection::<Tag>("tags");
+        db_tag
+            .update_many(
+                doc! {"tag": {"$in": &post.tag}},
+                doc! {"$inc": {"count": -1}},
+            )
+            .await?;
+        Ok(true)
+    }
+
+    async fn find_revisions(&self, post_id: &str) -> Result<Vec<PostRevision>, StorageError> {
+        let post_id = ObjectId::parse_str(post_id)?;
+        let db_revision = self.db.collection::<PostRevision>("post_revisions");
+        let result = db_revision
+            .find(doc! {"post_id": post_id})
+            .sort(doc! {"created_at": 1, "_id": 1})
+            .await?;
+        Ok(result.try_collect().await?)
+    }
+
+    async fn find_posts(
+        &self,
+        filter: &PostFilter,
+        after: Option<PostCursor>,
+        limit: i64,
+    ) -> Result<Vec<ServerPost>, StorageError> {
+        let db_post = self.db.collection::<Document>("posts");
+        let mut conditions = vec![doc! {"deleted": {"$ne": true}}];
+        for group in &filter.all_tags {
+            conditions.push(doc! {"tag": {"$in": group}});
+        }
+        if !filter.not_tags.is_empty() {
+            conditions.push(doc! {"tag": {"$nin": &filter.not_tags}});
+        }
+        if let Some(level) = filter.level {
+            conditions.push(doc! {"level": level.as_str()});
+        }
+        if let Some(author) = filter.author {
+            conditions.push(doc! {"author": author});
+        }
+        if let Some(commenter) = filter.commenter {
+            conditions.push(doc! {"comment": {"$elemMatch": {
+                "author": commenter,
+                "deleted": {"$ne": true},
+                "hidden": {"$ne": true},
+            }}});
+        }
+        if let Some(since) = filter.since {
+            conditions.push(doc! {"created_at": {"$gte": since}});
+        }
+        if let Some(until) = filter.until {
+            conditions.push(doc! {"created_at": {"$lt": until}});
+        }
+        if !filter.terms.is_empty() {
+            conditions.push(doc! {"terms": {"$all": &filter.terms}});
+        }
+        if let Some(replied) = filter.replied {
+            let visible = doc! {"$elemMatch": {"deleted": {"$ne": true}, "hidden": {"$ne": true}}};
+            conditions.push(if replied {
+                doc! {"comment": visible}
+            } else {
+                doc! {"comment": {"$not": visible}}
+            });
+        }
+        let sort_key = match filter.sort {
+            PostSort::Newest => Bson::from("$created_at"),
+            PostSort::MostReplied => doc! {"$size": {"$filter": {
+                "input": {"$ifNull": ["$comment", []]},
+                "cond": {"$and": [
+                    {"$ne": ["$$this.deleted", true]},
+                    {"$ne": ["$$this.hidden", true]},
+                ]},
+            }}}
+            .into(),
+            PostSort::MostActive => {
+                doc! {"$max": ["$created_at", {"$max": "$comment.created_at"}]}.into()
+            }
+        };
+        let mut pipeline = vec![doc! {"$addFields": {"sort_key": sort_key}}];
+        if let Some(after) = after {
+            conditions.push(doc! {"$or": [
+                {"sort_key": {"$lt": after.key}},
+                {"sort_key": after.key, "_id": {"$lt": after.id}},
+            ]});
+        }
+        pipeline.push(doc! {"$match": {"$and": conditions}});
+        pipeline.push(doc! {"$sort": {"sort_key": -1, "_id": -1}});
+        pipeline.push(doc! {"$limit": limit});
+        let documents: Vec<Document> = db_post.aggregate(pipeline).await?.try_collect().await?;
+        documents
+            .into_iter()
+            .map(|d| from_document(d).map_err(|e| StorageError(e.to_string())))
+            .collect()
+    }
+
+    async fn push_comment(
+        &self,
+        post_id: &str,
+        comment: ServerComment,
+    ) -> Result<bool, StorageError> {
+        let id = ObjectId::parse_str(post_id)?;
+        let db_post = self.db.collection::<ServerPost>("posts");
+        let comment_body = comment.body.clone();
+        let comment = to_bson(&comment).map_err(|e| StorageError(e.to_string()))?;
+        let terms = search::index_terms(&comment_body);
+        let result = db_post
+            .update_one(
+                doc! {"_id": id},
+                doc! {
+                    "$push": {"comment": comment},
+                    "$addToSet": {"terms": {"$each": terms}},
+                },
+            )
+            .await?;
+        Ok(result.matched_count == 1)
+    }
+
+    async fn update_comment(
+        &self,
+        post_id: &str,
+        comment: ServerComment,
+    ) -> Result<bool, StorageError> {
+        let id = ObjectId::parse_str(post_id)?;
+        let db_post = self.db.collection::<ServerPost>("posts");
+        let result = db_post
+            .update_one(
+                doc! {"_id": id, "comment.id": comment.id},
+                doc! {"$set": {
+                    "comment.$.body": comment.body,
+                    "comment.$.body_html": comment.body_html,
+                    "comment.$.updated_at": comment.updated_at,
+                    "comment.$.deleted": comment.deleted,
+                    "comment.$.hidden": comment.hidden,
+                }},
+            )
+            .await?;
+        if result.matched_count == 0 {
+            return Ok(false);
+        }
+        // 消えた本文を検索に出さないように語を入れ直す
+        if let Some(post) = db_post.find_one(doc! {"_id": id}).await? {
+            let terms = search::index_terms(&search::post_text(&post));
+            db_post
+                .update_one(doc! {"_id": id}, doc! {"$set": {"terms": terms}})
+                .await?;
+        }
+        Ok(true)
+    }
+
+    async fn save_draft(&self, draft: ServerDraft) -> Result<(), StorageError> {
+        let db_draft = self.db.collection::<ServerDraft>("drafts");
+        db_draft
+            .replace_one(doc! {"_id": draft.id}, draft)
+            .upsert(true)
+            .await?;
+        Ok(())
+    }
+
+    async fn find_draft(&self, id: &str) -> Result<Option<ServerDraft>, StorageError> {
+        let id = ObjectId::parse_str(id)?;
+        let db_draft = self.db.collection::<ServerDraft>("drafts");
+        Ok(db_draft.find_one(doc! {"_id": id}).await?)
+    }
+
+    async fn find_drafts(&self, author: ObjectId) -> Result<Vec<ServerDraft>, StorageError> {
+        let db_draft = self.db.collection::<ServerDraft>("drafts");
+        let result = db_draft
+            .find(doc! {"author": author})
+            .sort(doc! {"updated_at": -1, "_id": -1})
+            .await?;
+        Ok(result.try_collect().await?)
+    }
+
+    async fn delete_draft(&self, id: &str) -> Result<(), StorageError> {
+        let id = ObjectId::parse_str(id)?;
+        let db_draft = self.db.collection::<ServerDraft>("drafts");
+        db_draft.delete_one(doc! {"_id": id}).await?;
+        Ok(())
+    }
+
+    async fn insert_session(&self, session: Session) -> Result<(), StorageError> {
+        let db_session = self.db.collection::<Session>("sessions");
+        db_session.insert_one(session).await?;
+        Ok(())
+    }
+
+    async fn find_session(&self, id: &str) -> Result<Option<Session>, StorageError> {
+        let db_session = self.db.collection::<Session>("sessions");
+        Ok(db_session.find_one(doc! {"_id": id}).await?)
+    }
+
+    async fn rotate_session(
+        &self,
+        id: &str,
+        old_hash: &str,
+        new_hash: &str,
+        expires_at: i64,
+        rotated_at: i64,
+    ) -> Result<bool, StorageError> {
+        let db_session = self.db.collection::<Session>("sessions");
+        let result = db_session
+            .update_one(
+                doc! {"_id": id, "refresh_hash": old_hash},
+                doc! {"$set": {
+                    "refresh_hash": new_hash,
+                    "previous_hash": old_hash,
+                    "expires_at": expires_at,
+                    "rotated_at": rotated_at,
+                }},
+            )
+            .await?;
+        Ok(result.modified_count == 1)
+    }
+
+    async fn delete_session(&self, id: &str) -> Result<(), StorageError> {
+        let db_session = self.db.collection::<Session>("sessions");
+        db_session.delete_one(doc! {"_id": id}).await?;
+        Ok(())
+    }
+
+    async fn delete_sessions_of(&self, user_id: &str) -> Result<(), StorageError> {
+        let db_session = self.db.collection::<Session>("sessions");
+        db_session.delete_many(doc! {"user_id": user_id}).await?;
+        Ok(())
+    }
+
+    async fn setting(&self) -> Result<Option<DbSetting>, StorageError> {
+        let collection = self.db.collection::<DbSetting>("config");
+        Ok(collection.find_one(doc! {}).await?)
+    }
+}
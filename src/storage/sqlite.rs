@@ -0,0 +1,1276 @@
+// SQLite実装
+// MongoDBサーバーを立てたくない小規模な環境向け
+
+use super::{
+    Attachment, DbSetting, ExperienceLevel, PostCursor, PostFilter, PostRevision, PostSort,
+    ServerComment, ServerDraft, ServerPost, Session, Storage, StorageError, Tag, TagExperience,
+    TagPreference, User, UNKNOWN_USER,
+};
+use crate::search;
+use argon2::password_hash::{rand_core::OsRng, SaltString};
+use async_trait::async_trait;
+use mongodb::bson::oid::ObjectId;
+use rusqlite::{params, params_from_iter, types::Value, Connection, OptionalExtension};
+use std::sync::{Arc, Mutex};
+
+// スキーマのマイグレーション
+// 順番に適用され、適用済みの数は PRAGMA user_version に記録される
+// 既存の要素は書き換えず、変更は末尾に追加すること
+const MIGRATIONS: &[&str] = &[
+    // 1: 初期スキーマ
+    "CREATE TABLE users (
+        name TEXT PRIMARY KEY,
+        password_hash TEXT NOT NULL
+    );
+    CREATE TABLE tags (
+        tag TEXT PRIMARY KEY
+    );
+    CREATE TABLE posts (
+        id TEXT PRIMARY KEY,
+        name TEXT NOT NULL,
+        title TEXT NOT NULL,
+        body TEXT NOT NULL,
+        is_advanced INTEGER NOT NULL
+    );
+    CREATE TABLE post_tags (
+        post_id TEXT NOT NULL REFERENCES posts(id),
+        tag TEXT NOT NULL,
+        PRIMARY KEY (post_id, tag)
+    );
+    CREATE INDEX post_tags_tag ON post_tags(tag);
+    CREATE TABLE comments (
+        post_id TEXT NOT NULL REFERENCES posts(id),
+        name TEXT NOT NULL,
+        body TEXT NOT NULL
+    );
+    CREATE INDEX comments_post_id ON comments(post_id);
+    CREATE TABLE config (
+        password_salt TEXT NOT NULL,
+        jwt TEXT NOT NULL
+    );",
+    // 2: ログインのセッション
+    "CREATE TABLE sessions (
+        id TEXT PRIMARY KEY,
+        name TEXT NOT NULL,
+        refresh_hash TEXT NOT NULL,
+        expires_at INTEGER NOT NULL
+    );
+    CREATE INDEX sessions_name ON sessions(name);",
+    // 3: 投稿者・返信者を名前ではなくユーザーIDで持つ
+    "ALTER TABLE users ADD COLUMN id TEXT;
+    UPDATE users SET id = lower(hex(randomblob(12)));
+    CREATE UNIQUE INDEX users_id ON users(id);
+    ALTER TABLE posts ADD COLUMN author_id TEXT NOT NULL DEFAULT '';
+    UPDATE posts SET author_id = COALESCE((SELECT id FROM users WHERE users.name = posts.name), '');
+    ALTER TABLE posts DROP COLUMN name;
+    ALTER TABLE comments ADD COLUMN author_id TEXT NOT NULL DEFAULT '';
+    UPDATE comments
+        SET author_id = COALESCE((SELECT id FROM users WHERE users.name = comments.name), '');
+    ALTER TABLE comments DROP COLUMN name;
+    DELETE FROM sessions;
+    ALTER TABLE sessions ADD COLUMN user_id TEXT NOT NULL DEFAULT '';
+    CREATE INDEX sessions_user_id ON sessions(user_id);",
+    // 4: 返信のIDと日時、返信先 (スレッド表示用)
+    "ALTER TABLE comments ADD COLUMN id TEXT;
+    UPDATE comments SET id = lower(hex(randomblob(12)));
+    CREATE UNIQUE INDEX comments_id ON comments(id);
+    ALTER TABLE comments ADD COLUMN created_at INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE comments ADD COLUMN parent_id TEXT;",
+    // 5: 全文検索の索引 (中身は起動時にRust側で作る)
+    "CREATE TABLE post_terms (
+        post_id TEXT NOT NULL REFERENCES posts(id),
+        term TEXT NOT NULL,
+        PRIMARY KEY (post_id, term)
+    );
+    CREATE INDEX post_terms_term ON post_terms(term);",
+    // 6: 投稿の日時 (既存の投稿は起動時にIDの時刻から入れる)
+    "ALTER TABLE posts ADD COLUMN created_at INTEGER NOT NULL DEFAULT 0;",
+    // 7: タグの使われた数 (投稿に付いているタグはすべて tags に入れる)
+    "ALTER TABLE tags ADD COLUMN count INTEGER NOT NULL DEFAULT 0;
+    INSERT OR IGNORE INTO tags (tag) SELECT DISTINCT tag FROM post_tags;
+    UPDATE tags SET count = (SELECT COUNT(*) FROM post_tags WHERE post_tags.tag = tags.tag);",
+    // 8: タグの別名
+    "ALTER TABLE tags ADD COLUMN alias_of TEXT;",
+    // 9: タグの親子
+    "ALTER TABLE tags ADD COLUMN parent TEXT;",
+    // 10: フォロー・ミュートしているタグ
+    "CREATE TABLE tag_preferences (
+        user_id TEXT NOT NULL,
+        tag TEXT NOT NULL,
+        muted INTEGER NOT NULL,
+        PRIMARY KEY (user_id, tag)
+    );",
+    // 11: 投稿の編集と削除
+    "ALTER TABLE posts ADD COLUMN updated_at INTEGER;
+    ALTER TABLE posts ADD COLUMN deleted INTEGER NOT NULL DEFAULT 0;
+    CREATE TABLE post_revisions (
+        id TEXT PRIMARY KEY,
+        post_id TEXT NOT NULL REFERENCES posts(id),
+        title TEXT NOT NULL,
+        body TEXT NOT NULL,
+        is_advanced INTEGER NOT NULL,
+        created_at INTEGER NOT NULL
+    );
+    CREATE INDEX post_revisions_post_id ON post_revisions(post_id);
+    CREATE TABLE post_revision_tags (
+        revision_id TEXT NOT NULL REFERENCES post_revisions(id),
+        tag TEXT NOT NULL
+    );
+    CREATE INDEX post_revision_tags_revision_id ON post_revision_tags(revision_id);",
+    // 12: 返信の編集・削除・非表示
+    "ALTER TABLE comments ADD COLUMN updated_at INTEGER;
+    ALTER TABLE comments ADD COLUMN deleted INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE comments ADD COLUMN hidden INTEGER NOT NULL DEFAULT 0;",
+    // 13: 下書き
+    "CREATE TABLE drafts (
+        id TEXT PRIMARY KEY,
+        author_id TEXT NOT NULL,
+        title TEXT NOT NULL,
+        body TEXT NOT NULL,
+        is_advanced INTEGER NOT NULL,
+        updated_at INTEGER NOT NULL
+    );
+    CREATE INDEX drafts_author_id ON drafts(author_id);
+    CREATE TABLE draft_tags (
+        draft_id TEXT NOT NULL REFERENCES drafts(id),
+        tag TEXT NOT NULL
+    );
+    CREATE INDEX draft_tags_draft_id ON draft_tags(draft_id);",
+    // 14: 投稿・返信の添付ファイル (返信のものはcomment_idが入る)
+    "CREATE TABLE attachments (
+        post_id TEXT NOT NULL REFERENCES posts(id),
+        comment_id TEXT,
+        blob_id TEXT NOT NULL,
+        name TEXT NOT NULL,
+        content_type TEXT NOT NULL,
+        size INTEGER NOT NULL
+    );
+    CREATE INDEX attachments_post_id ON attachments(post_id);",
+    // 15: プロフィール
+    "ALTER TABLE users ADD COLUMN display_name TEXT NOT NULL DEFAULT '';
+    ALTER TABLE users ADD COLUMN bio TEXT NOT NULL DEFAULT '';
+    ALTER TABLE users ADD COLUMN avatar TEXT;",
+    // 16: 経験者かどうか (is_advanced) を経験の度合い (level) にし、タグごとの経験を持つ
+    "ALTER TABLE posts ADD COLUMN level TEXT NOT NULL DEFAULT 'beginner';
+    UPDATE posts SET level = 'advanced' WHERE is_advanced = 1;
+    ALTER TABLE posts DROP COLUMN is_advanced;
+    ALTER TABLE post_revisions ADD COLUMN level TEXT NOT NULL DEFAULT 'beginner';
+    UPDATE post_revisions SET level = 'advanced' WHERE is_advanced = 1;
+    ALTER TABLE post_revisions DROP COLUMN is_advanced;
+    ALTER TABLE drafts ADD COLUMN level TEXT NOT NULL DEFAULT 'beginner';
+    UPDATE drafts SET level = 'advanced' WHERE is_advanced = 1;
+    ALTER TABLE drafts DROP COLUMN is_advanced;
+    CREATE TABLE experiences (
+        user_id TEXT NOT NULL,
+        tag TEXT NOT NULL,
+        level TEXT NOT NULL,
+        years INTEGER,
+        PRIMARY KEY (user_id, tag)
+    );",
+    // 17: ひとつ前のリフレッシュトークン
+    "ALTER TABLE sessions ADD COLUMN previous_hash TEXT NOT NULL DEFAULT '';
+    ALTER TABLE sessions ADD COLUMN rotated_at INTEGER NOT NULL DEFAULT 0;",
+    // 18: タグの読み
+    "ALTER TABLE tags ADD COLUMN reading TEXT;",
+    // 19: 本文をHTMLにしたもの
+    "ALTER TABLE posts ADD COLUMN body_html TEXT NOT NULL DEFAULT '';
+    ALTER TABLE comments ADD COLUMN body_html TEXT NOT NULL DEFAULT '';",
+];
+
+// 全文検索の索引が入ったマイグレーションの番号
+const TERMS_VERSION: usize = 5;
+// 投稿の日時が入ったマイグレーションの番号
+const CREATED_AT_VERSION: usize = 6;
+
+impl From<rusqlite::Error> for StorageError {
+    fn from(e: rusqlite::Error) -> Self {
+        StorageError(e.to_string())
+    }
+}
+
+pub struct SqliteStorage {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl SqliteStorage {
+    pub fn open(path: &str) -> Result<SqliteStorage, StorageError> {
+        let mut conn = Connection::open(path)?;
+        conn.pragma_update(None, "foreign_keys", true)?;
+        migrate(&mut conn)?;
+        Ok(SqliteStorage {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    // rusqliteは同期APIなので、ブロッキング用のスレッドで実行する
+    async fn call<T, F>(&self, f: F) -> Result<T, StorageError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> rusqlite::Result<T> + Send + 'static,
+    {
+        let conn = self.conn.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut conn = conn
+                .lock()
+                .map_err(|_| StorageError("sqlite connection is poisoned".to_string()))?;
+            Ok(f(&mut conn)?)
+        })
+        .await
+        .map_err(|e| StorageError(e.to_string()))?
+    }
+}
+
+fn migrate(conn: &mut Connection) -> Result<(), StorageError> {
+    let version: usize = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    if version > MIGRATIONS.len() {
+        return Err(StorageError(format!(
+            "database schema version {version} is newer than this build ({})",
+            MIGRATIONS.len()
+        )));
+    }
+
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration)?;
+        tx.pragma_update(None, "user_version", i + 1)?;
+        tx.commit()?;
+    }
+
+    // 索引ができる前からある投稿を索引に入れる
+    if version < TERMS_VERSION {
+        let tx = conn.transaction()?;
+        let ids = tx
+            .prepare("SELECT id FROM posts")?
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()?;
+        for id in ids {
+            let (title, body): (String, String) = tx.query_row(
+                "SELECT title, body FROM posts WHERE id = ?1",
+                [&id],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )?;
+            let comments = tx
+                .prepare("SELECT body FROM comments WHERE post_id = ?1")?
+                .query_map([&id], |row| row.get(0))?
+                .collect::<rusqlite::Result<Vec<String>>>()?;
+            index_text(
+                &tx,
+                &id,
+                &format!("{title}\n{body}\n{}", comments.join("\n")),
+            )?;
+        }
+        tx.commit()?;
+    }
+
+    if version < CREATED_AT_VERSION {
+        let tx = conn.transaction()?;
+        let ids = tx
+            .prepare("SELECT id FROM posts WHERE created_at = 0")?
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()?;
+        for id in ids {
+            if let Ok(oid) = ObjectId::parse_str(&id) {
+                tx.execute(
+                    "UPDATE posts SET created_at = ?2 WHERE id = ?1",
+                    params![id, oid.timestamp().timestamp_millis() / 1000],
+                )?;
+            }
+        }
+        tx.commit()?;
+    }
+
+    // MongoDBと違って手で設定を入れる手間がないように、初回起動時に鍵を作っておく
+    let has_setting: bool =
+        conn.query_row("SELECT EXISTS (SELECT 1 FROM config)", [], |row| row.get(0))?;
+    if !has_setting {
+        conn.execute(
+            "INSERT INTO config (password_salt, jwt) VALUES (?1, ?2)",
+            params![
+                SaltString::generate(&mut OsRng).to_string(),
+                SaltString::generate(&mut OsRng).to_string()
+            ],
+        )?;
+    }
+    Ok(())
+}
+
+// 文字列のIDを読む (ユーザーが見つからなかった古いデータは UNKNOWN_USER)
+fn parse_id(id: &str) -> ObjectId {
+    ObjectId::parse_str(id).unwrap_or(UNKNOWN_USER)
+}
+
+// 経験の度合いは ExperienceLevel::as_str の文字列で持つ
+fn read_level(row: &rusqlite::Row, index: usize) -> rusqlite::Result<ExperienceLevel> {
+    Ok(ExperienceLevel::parse(&row.get::<_, String>(index)?).unwrap_or_default())
+}
+
+// posts から読む列 (load_post に渡す行はこの並び)
+const POST_COLUMNS: &str =
+    "id, author_id, title, body, level, created_at, updated_at, deleted, body_html";
+
+fn load_post(conn: &Connection, row: &rusqlite::Row) -> rusqlite::Result<ServerPost> {
+    let id: String = row.get(0)?;
+    let tag = conn
+        .prepare_cached("SELECT tag FROM post_tags WHERE post_id = ?1 ORDER BY rowid")?
+        .query_map([&id], |row| row.get(0))?
+        .collect::<rusqlite::Result<Vec<String>>>()?;
+    // 添付ファイルは投稿の分と返信の分をまとめて読んで振り分ける
+    let mut attachments = conn
+        .prepare_cached(
+            "SELECT comment_id, blob_id, name, content_type, size
+             FROM attachments WHERE post_id = ?1 ORDER BY rowid",
+        )?
+        .query_map([&id], |row| {
+            Ok((
+                row.get::<_, Option<String>>(0)?,
+                Attachment {
+                    id: row.get(1)?,
+                    name: row.get(2)?,
+                    content_type: row.get(3)?,
+                    size: row.get(4)?,
+                },
+            ))
+        })?
+        .collect::<rusqlite::Result<Vec<(Option<String>, Attachment)>>>()?;
+    let mut attachments_of = |comment_id: Option<&str>| {
+        let (of, rest) = attachments
+            .drain(..)
+            .partition(|(c, _)| c.as_deref() == comment_id);
+        attachments = rest;
+        of.into_iter().map(|(_, a)| a).collect::<Vec<Attachment>>()
+    };
+    let mut comment = conn
+        .prepare_cached(
+            "SELECT id, author_id, body, created_at, parent_id, updated_at, deleted, hidden,
+                body_html
+             FROM comments WHERE post_id = ?1 ORDER BY rowid",
+        )?
+        .query_map([&id], |row| {
+            Ok(ServerComment {
+                id: parse_id(&row.get::<_, String>(0)?),
+                author: parse_id(&row.get::<_, String>(1)?),
+                body: row.get(2)?,
+                created_at: row.get(3)?,
+                parent: row
+                    .get::<_, Option<String>>(4)?
+                    .map(|parent| parse_id(&parent)),
+                updated_at: row.get(5)?,
+                deleted: row.get(6)?,
+                hidden: row.get(7)?,
+                body_html: row.get(8)?,
+                attachments: vec![],
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<ServerComment>>>()?;
+    for c in &mut comment {
+        c.attachments = attachments_of(Some(&c.id.to_hex()));
+    }
+    Ok(ServerPost {
+        author: parse_id(&row.get::<_, String>(1)?),
+        body: row.get(3)?,
+        attachments: attachments_of(None),
+        tag,
+        title: row.get(2)?,
+        comment,
+        level: read_level(row, 4)?,
+        created_at: row.get(5)?,
+        updated_at: row.get(6)?,
+        deleted: row.get(7)?,
+        body_html: row.get(8)?,
+        id: ObjectId::parse_str(&id).ok(),
+    })
+}
+
+fn insert_attachments(
+    conn: &Connection,
+    post_id: &str,
+    comment_id: Option<ObjectId>,
+    attachments: &[Attachment],
+) -> rusqlite::Result<()> {
+    let mut stmt = conn.prepare_cached(
+        "INSERT INTO attachments (post_id, comment_id, blob_id, name, content_type, size)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+    )?;
+    for a in attachments {
+        stmt.execute(params![
+            post_id,
+            comment_id.map(|id| id.to_hex()),
+            a.id,
+            a.name,
+            a.content_type,
+            a.size
+        ])?;
+    }
+    Ok(())
+}
+
+// drafts から読む列 (load_draft に渡す行はこの並び)
+const DRAFT_COLUMNS: &str = "id, author_id, title, body, level, updated_at";
+
+fn load_draft(conn: &Connection, row: &rusqlite::Row) -> rusqlite::Result<ServerDraft> {
+    let id: String = row.get(0)?;
+    let tag = conn
+        .prepare_cached("SELECT tag FROM draft_tags WHERE draft_id = ?1 ORDER BY rowid")?
+        .query_map([&id], |row| row.get(0))?
+        .collect::<rusqlite::Result<Vec<String>>>()?;
+    Ok(ServerDraft {
+        id: parse_id(&id),
+        author: parse_id(&row.get::<_, String>(1)?),
+        title: row.get(2)?,
+        body: row.get(3)?,
+        tag,
+        level: read_level(row, 4)?,
+        updated_at: row.get(5)?,
+    })
+}
+
+// POST_COLUMNS を選ぶSQLを実行して投稿を読む
+fn load_posts(
+    conn: &Connection,
+    sql: &str,
+    values: Vec<Value>,
+) -> rusqlite::Result<Vec<ServerPost>> {
+    let mut stmt = conn.prepare(sql)?;
+    let mut rows = stmt.query(params_from_iter(values))?;
+    let mut posts = vec![];
+    while let Some(row) = rows.next()? {
+        posts.push(load_post(conn, row)?);
+    }
+    Ok(posts)
+}
+
+// 動的に組み立てるSQLの値を足して、そのプレースホルダーを返す
+fn bind(values: &mut Vec<Value>, value: Value) -> String {
+    values.push(value);
+    format!("?{}", values.len())
+}
+
+fn bind_tags(values: &mut Vec<Value>, tags: &[String]) -> String {
+    tags.iter()
+        .map(|tag| bind(values, Value::Text(tag.clone())))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn index_text(conn: &Connection, post_id: &str, text: &str) -> rusqlite::Result<()> {
+    let mut stmt =
+        conn.prepare_cached("INSERT OR IGNORE INTO post_terms (post_id, term) VALUES (?1, ?2)")?;
+    for term in search::index_terms(text) {
+        stmt.execute(params![post_id, term])?;
+    }
+    Ok(())
+}
+
+const USER_COLUMNS: &str = "id, name, password_hash, display_name, bio, avatar";
+
+fn read_user(row: &rusqlite::Row) -> rusqlite::Result<User> {
+    Ok(User {
+        id: parse_id(&row.get::<_, String>(0)?),
+        name: row.get(1)?,
+        password_hash: row.get(2)?,
+        display_name: row.get(3)?,
+        bio: row.get(4)?,
+        avatar: row.get(5)?,
+    })
+}
+
+fn read_tag(row: &rusqlite::Row) -> rusqlite::Result<Tag> {
+    Ok(Tag {
+        tag: row.get(0)?,
+        count: row.get(1)?,
+        alias_of: row.get(2)?,
+        parent: row.get(3)?,
+        reading: row.get(4)?,
+    })
+}
+
+// LIKE のワイルドカードをエスケープする
+fn escape_like(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        if matches!(c, '%' | '_' | '\\') {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
+#[async_trait]
+impl Storage for SqliteStorage {
+    async fn find_user(&self, name: &str) -> Result<Option<User>, StorageError> {
+        let name = name.to_string();
+        self.call(move |conn| {
+            conn.query_row(
+                &format!("SELECT {USER_COLUMNS} FROM users WHERE name = ?1"),
+                [name],
+                read_user,
+            )
+            .optional()
+        })
+        .await
+    }
+
+    async fn find_users_by_id(&self, ids: &[ObjectId]) -> Result<Vec<User>, StorageError> {
+        let ids: Vec<String> = ids.iter().map(|id| id.to_hex()).collect();
+        self.call(move |conn| {
+            let mut stmt =
+                conn.prepare_cached(&format!("SELECT {USER_COLUMNS} FROM users WHERE id = ?1"))?;
+            let mut users = vec![];
+            for id in ids {
+                if let Some(user) = stmt.query_row([id], read_user).optional()? {
+                    users.push(user);
+                }
+            }
+            Ok(users)
+        })
+        .await
+    }
+
+    async fn insert_user(&self, user: User) -> Result<bool, StorageError> {
+        self.call(move |conn| {
+            let inserted = conn.execute(
+                "INSERT INTO users (id, name, password_hash, display_name, bio, avatar)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+                ON CONFLICT (name) DO NOTHING",
+                params![
+                    user.id.to_hex(),
+                    user.name,
+                    user.password_hash,
+                    user.display_name,
+                    user.bio,
+                    user.avatar
+                ],
+            )?;
+            Ok(inserted == 1)
+        })
+        .await
+    }
+
+    async fn update_profile(&self, user: User) -> Result<bool, StorageError> {
+        self.call(move |conn| {
+            let changed = conn.execute(
+                "UPDATE users SET display_name = ?2, bio = ?3, avatar = ?4 WHERE id = ?1",
+                params![user.id.to_hex(), user.display_name, user.bio, user.avatar],
+            )?;
+            Ok(changed > 0)
+        })
+        .await
+    }
+
+    async fn find_tag(&self, tag: &str) -> Result<Option<Tag>, StorageError> {
+        let tag = tag.to_string();
+        self.call(move |conn| {
+            conn.query_row(
+                "SELECT tag, count, alias_of, parent, reading FROM tags WHERE tag = ?1",
+                [tag],
+                read_tag,
+            )
+            .optional()
+        })
+        .await
+    }
+
+    async fn all_tags(&self) -> Result<Vec<Tag>, StorageError> {
+        self.call(|conn| {
+            conn.prepare_cached("SELECT tag, count, alias_of, parent, reading FROM tags")?
+                .query_map([], read_tag)?
+                .collect()
+        })
+        .await
+    }
+
+    async fn find_tags_with_prefix(
+        &self,
+        prefix: &str,
+        amount: i64,
+    ) -> Result<Vec<Tag>, StorageError> {
+        // LIMIT -1 は上限なしになってしまう
+        if amount <= 0 {
+            return Ok(vec![]);
+        }
+        let pattern = format!("{}%", escape_like(prefix));
+        self.call(move |conn| {
+            conn.prepare_cached(
+                "SELECT tag, count, alias_of, parent, reading FROM tags WHERE tag LIKE ?1 ESCAPE '\\'
+                 ORDER BY count DESC, tag LIMIT ?2",
+            )?
+            .query_map(params![pattern, amount], read_tag)?
+            .collect()
+        })
+        .await
+    }
+
+    async fn merge_tag(&self, from: &str, into: &str) -> Result<u64, StorageError> {
+        let (from, into) = (from.to_string(), into.to_string());
+        self.call(move |conn| {
+            let tx = conn.transaction()?;
+            let merged = tx.execute(
+                "UPDATE post_tags SET tag = ?2
+                 WHERE tag = ?1 AND post_id NOT IN (SELECT post_id FROM post_tags WHERE tag = ?2)",
+                params![from, into],
+            )?;
+            // もう両方付いていた投稿
+            let both = tx.execute("DELETE FROM post_tags WHERE tag = ?1", [&from])?;
+            tx.execute(
+                "INSERT INTO tags (tag, count) VALUES (?1, 0), (?2, 0) ON CONFLICT (tag) DO NOTHING",
+                params![from, into],
+            )?;
+            tx.execute(
+                "UPDATE tags SET alias_of = NULL,
+                    count = (SELECT COUNT(*) FROM post_tags JOIN posts ON posts.id = post_id
+                             WHERE tag = ?1 AND deleted = 0)
+                 WHERE tag = ?1",
+                [&into],
+            )?;
+            tx.execute(
+                "UPDATE tags SET alias_of = ?2, count = 0, parent = NULL
+                 WHERE tag = ?1 OR alias_of = ?1",
+                params![from, into],
+            )?;
+            tx.execute(
+                "UPDATE tags SET parent = ?2 WHERE parent = ?1",
+                params![from, into],
+            )?;
+            tx.commit()?;
+            Ok((merged + both) as u64)
+        })
+        .await
+    }
+
+    async fn set_tag_parent(&self, tag: &str, parent: Option<&str>) -> Result<(), StorageError> {
+        let (tag, parent) = (tag.to_string(), parent.map(str::to_string));
+        self.call(move |conn| {
+            conn.execute(
+                "UPDATE tags SET parent = ?2 WHERE tag = ?1",
+                params![tag, parent],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn set_tag_reading(&self, tag: &str, reading: Option<&str>) -> Result<(), StorageError> {
+        let (tag, reading) = (tag.to_string(), reading.map(str::to_string));
+        self.call(move |conn| {
+            conn.execute(
+                "UPDATE tags SET reading = ?2 WHERE tag = ?1",
+                params![tag, reading],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn find_tag_preference(&self, user_id: ObjectId) -> Result<TagPreference, StorageError> {
+        self.call(move |conn| {
+            let mut preference = TagPreference::empty(user_id);
+            let mut stmt = conn.prepare_cached(
+                "SELECT tag, muted FROM tag_preferences WHERE user_id = ?1 ORDER BY rowid",
+            )?;
+            let mut rows = stmt.query([user_id.to_hex()])?;
+            while let Some(row) = rows.next()? {
+                let tag: String = row.get(0)?;
+                if row.get(1)? {
+                    preference.muted.push(tag);
+                } else {
+                    preference.followed.push(tag);
+                }
+            }
+            Ok(preference)
+        })
+        .await
+    }
+
+    async fn save_tag_preference(&self, preference: TagPreference) -> Result<(), StorageError> {
+        self.call(move |conn| {
+            let tx = conn.transaction()?;
+            let user_id = preference.user_id.to_hex();
+            tx.execute("DELETE FROM tag_preferences WHERE user_id = ?1", [&user_id])?;
+            {
+                let mut stmt = tx.prepare(
+                    "INSERT OR IGNORE INTO tag_preferences (user_id, tag, muted) VALUES (?1, ?2, ?3)",
+                )?;
+                for tag in &preference.followed {
+                    stmt.execute(params![user_id, tag, false])?;
+                }
+                for tag in &preference.muted {
+                    stmt.execute(params![user_id, tag, true])?;
+                }
+            }
+            tx.commit()
+        })
+        .await
+    }
+
+    async fn find_experiences(
+        &self,
+        user_id: ObjectId,
+    ) -> Result<Vec<TagExperience>, StorageError> {
+        self.call(move |conn| {
+            conn.prepare(
+                "SELECT tag, level, years FROM experiences WHERE user_id = ?1 ORDER BY rowid",
+            )?
+            .query_map([user_id.to_hex()], |row| {
+                Ok(TagExperience {
+                    tag: row.get(0)?,
+                    level: read_level(row, 1)?,
+                    years: row.get(2)?,
+                })
+            })?
+            .collect()
+        })
+        .await
+    }
+
+    async fn save_experiences(
+        &self,
+        user_id: ObjectId,
+        experiences: Vec<TagExperience>,
+    ) -> Result<(), StorageError> {
+        self.call(move |conn| {
+            let tx = conn.transaction()?;
+            let user_id = user_id.to_hex();
+            tx.execute("DELETE FROM experiences WHERE user_id = ?1", [&user_id])?;
+            {
+                let mut stmt = tx.prepare(
+                    "INSERT OR IGNORE INTO experiences (user_id, tag, level, years)
+                     VALUES (?1, ?2, ?3, ?4)",
+                )?;
+                for e in &experiences {
+                    stmt.execute(params![user_id, e.tag, e.level.as_str(), e.years])?;
+                }
+            }
+            tx.commit()
+        })
+        .await
+    }
+
+    async fn insert_post(&self, post: ServerPost) -> Result<(), StorageError> {
+        let id = post.id.unwrap_or_default().to_hex();
+        self.call(move |conn| {
+            let tx = conn.transaction()?;
+            tx.execute(
+                "INSERT INTO posts (id, author_id, title, body, level, created_at, body_html)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![
+                    id,
+                    post.author.to_hex(),
+                    post.title,
+                    post.body,
+                    post.level.as_str(),
+                    post.created_at,
+                    post.body_html
+                ],
+            )?;
+            for tag in &post.tag {
+                let added = tx.execute(
+                    "INSERT OR IGNORE INTO post_tags (post_id, tag) VALUES (?1, ?2)",
+                    params![id, tag],
+                )?;
+                if added == 1 {
+                    tx.execute(
+                        "INSERT INTO tags (tag, count) VALUES (?1, 1)
+                         ON CONFLICT (tag) DO UPDATE SET count = count + 1",
+                        [tag],
+                    )?;
+                }
+            }
+            for comment in &post.comment {
+                tx.execute(
+                    "INSERT INTO comments
+                        (id, post_id, author_id, body, created_at, parent_id,
+                         updated_at, deleted, hidden, body_html)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+                    params![
+                        comment.id.to_hex(),
+                        id,
+                        comment.author.to_hex(),
+                        comment.body,
+                        comment.created_at,
+                        comment.parent.map(|p| p.to_hex()),
+                        comment.updated_at,
+                        comment.deleted,
+                        comment.hidden,
+                        comment.body_html
+                    ],
+                )?;
+            }
+            insert_attachments(&tx, &id, None, &post.attachments)?;
+            for comment in &post.comment {
+                insert_attachments(&tx, &id, Some(comment.id), &comment.attachments)?;
+            }
+            index_text(&tx, &id, &search::post_text(&post))?;
+            tx.commit()
+        })
+        .await
+    }
+
+    async fn find_posts(
+        &self,
+        filter: &PostFilter,
+        after: Option<PostCursor>,
+        limit: i64,
+    ) -> Result<Vec<ServerPost>, StorageError> {
+        let mut values: Vec<Value> = vec![];
+        let mut conditions = vec!["deleted = 0".to_string()];
+        for group in &filter.all_tags {
+            conditions.push(format!(
+                "id IN (SELECT post_id FROM post_tags WHERE tag IN ({}))",
+                bind_tags(&mut values, group)
+            ));
+        }
+        if !filter.not_tags.is_empty() {
+            conditions.push(format!(
+                "id NOT IN (SELECT post_id FROM post_tags WHERE tag IN ({}))",
+                bind_tags(&mut values, &filter.not_tags)
+            ));
+        }
+        if let Some(level) = filter.level {
+            conditions.push(format!(
+                "level = {}",
+                bind(&mut values, Value::Text(level.as_str().to_string()))
+            ));
+        }
+        if let Some(author) = filter.author {
+            conditions.push(format!(
+                "author_id = {}",
+                bind(&mut values, Value::Text(author.to_hex()))
+            ));
+        }
+        if let Some(commenter) = filter.commenter {
+            conditions.push(format!(
+                "id IN (SELECT post_id FROM comments
+                    WHERE author_id = {} AND deleted = 0 AND hidden = 0)",
+                bind(&mut values, Value::Text(commenter.to_hex()))
+            ));
+        }
+        if let Some(since) = filter.since {
+            conditions.push(format!(
+                "created_at >= {}",
+                bind(&mut values, Value::Integer(since))
+            ));
+        }
+        if let Some(until) = filter.until {
+            conditions.push(format!(
+                "created_at < {}",
+                bind(&mut values, Value::Integer(until))
+            ));
+        }
+        if !filter.terms.is_empty() {
+            conditions.push(format!(
+                "id IN (SELECT post_id FROM post_terms WHERE term IN ({})
+                    GROUP BY post_id HAVING COUNT(*) = {})",
+                bind_tags(&mut values, &filter.terms),
+                bind(&mut values, Value::Integer(filter.terms.len() as i64))
+            ));
+        }
+        match filter.replied {
+            Some(true) => conditions.push("reply_count > 0".to_string()),
+            Some(false) => conditions.push("reply_count = 0".to_string()),
+            None => {}
+        }
+        let key = match filter.sort {
+            PostSort::Newest => "created_at",
+            PostSort::MostReplied => "reply_count",
+            PostSort::MostActive => "active_at",
+        };
+        // 並びの値が同じならIDの降順 (IDはObjectIdの16進数なので文字列の順番が作成順)
+        if let Some(after) = after {
+            conditions.push(format!(
+                "({key}, id) < ({}, {})",
+                bind(&mut values, Value::Integer(after.key)),
+                bind(&mut values, Value::Text(after.id.to_hex()))
+            ));
+        }
+        let limit = bind(&mut values, Value::Integer(limit));
+        let conditions = format!("WHERE {}", conditions.join(" AND "));
+        let sql = format!(
+            "SELECT {POST_COLUMNS} FROM (
+                SELECT posts.*,
+                    COUNT(CASE WHEN comments.deleted = 0 AND comments.hidden = 0 THEN 1 END)
+                        AS reply_count,
+                    max(posts.created_at, COALESCE(MAX(comments.created_at), 0)) AS active_at
+                FROM posts LEFT JOIN comments ON comments.post_id = posts.id
+                GROUP BY posts.id
+             )
+             {conditions}
+             ORDER BY {key} DESC, id DESC LIMIT {limit}"
+        );
+        self.call(move |conn| load_posts(conn, &sql, values)).await
+    }
+
+    async fn find_post(&self, id: &str) -> Result<Option<ServerPost>, StorageError> {
+        let id = ObjectId::parse_str(id)?.to_hex();
+        self.call(move |conn| {
+            conn.query_row(
+                &format!("SELECT {POST_COLUMNS} FROM posts WHERE id = ?1"),
+                [id],
+                |row| load_post(conn, row),
+            )
+            .optional()
+        })
+        .await
+    }
+
+    async fn update_post(
+        &self,
+        post: ServerPost,
+        revision: PostRevision,
+    ) -> Result<(), StorageError> {
+        let id = post.id.unwrap_or_default().to_hex();
+        self.call(move |conn| {
+            let tx = conn.transaction()?;
+            let old_tags = tx
+                .prepare("SELECT tag FROM post_tags WHERE post_id = ?1")?
+                .query_map([&id], |row| row.get(0))?
+                .collect::<rusqlite::Result<Vec<String>>>()?;
+            tx.execute(
+                "UPDATE posts SET title = ?2, body = ?3, level = ?4, updated_at = ?5,
+                    body_html = ?6
+                 WHERE id = ?1",
+                params![
+                    id,
+                    post.title,
+                    post.body,
+                    post.level.as_str(),
+                    post.updated_at,
+                    post.body_html
+                ],
+            )?;
+            // 並び順を保つために付け直す
+            tx.execute("DELETE FROM post_tags WHERE post_id = ?1", [&id])?;
+            for tag in &post.tag {
+                tx.execute(
+                    "INSERT OR IGNORE INTO post_tags (post_id, tag) VALUES (?1, ?2)",
+                    params![id, tag],
+                )?;
+                if !old_tags.contains(tag) {
+                    tx.execute(
+                        "INSERT INTO tags (tag, count) VALUES (?1, 1)
+                         ON CONFLICT (tag) DO UPDATE SET count = count + 1",
+                        [tag],
+                    )?;
+                }
+            }
+            for tag in old_tags.iter().filter(|t| !post.tag.contains(t)) {
+                tx.execute("UPDATE tags SET count = count - 1 WHERE tag = ?1", [tag])?;
+            }
+            tx.execute("DELETE FROM post_terms WHERE post_id = ?1", [&id])?;
+            index_text(&tx, &id, &search::post_text(&post))?;
+
+            let revision_id = revision.id.to_hex();
+            tx.execute(
+                "INSERT INTO post_revisions (id, post_id, title, body, level, created_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![
+                    revision_id,
+                    id,
+                    revision.title,
+                    revision.body,
+                    revision.level.as_str(),
+                    revision.created_at
+                ],
+            )?;
+            for tag in &revision.tag {
+                tx.execute(
+                    "INSERT INTO post_revision_tags (revision_id, tag) VALUES (?1, ?2)",
+                    params![revision_id, tag],
+                )?;
+            }
+            tx.commit()
+        })
+        .await
+    }
+
+    async fn delete_post(&self, id: &str) -> Result<bool, StorageError> {
+        let id = ObjectId::parse_str(id)?.to_hex();
+        self.call(move |conn| {
+            let tx = conn.transaction()?;
+            let deleted = tx.execute(
+                "UPDATE posts SET deleted = 1 WHERE id = ?1 AND deleted = 0",
+                [&id],
+            )?;
+            if deleted == 1 {
+                tx.execute(
+                    "UPDATE tags SET count = count - 1
+                     WHERE tag IN (SELECT tag FROM post_tags WHERE post_id = ?1)",
+                    [&id],
+                )?;
+                tx.execute("DELETE FROM post_terms WHERE post_id = ?1", [&id])?;
+            }
+            tx.commit()?;
+            Ok(deleted == 1)
+        })
+        .await
+    }
+
+    async fn find_revisions(&self, post_id: &str) -> Result<Vec<PostRevision>, StorageError> {
+        let post_id = ObjectId::parse_str(post_id)?;
+        self.call(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT id, title, body, level, created_at FROM post_revisions
+                 WHERE post_id = ?1 ORDER BY created_at, rowid",
+            )?;
+            let mut rows = stmt.query([post_id.to_hex()])?;
+            let mut revisions = vec![];
+            while let Some(row) = rows.next()? {
+                let id: String = row.get(0)?;
+                let tag = conn
+                    .prepare_cached(
+                        "SELECT tag FROM post_revision_tags WHERE revision_id = ?1 ORDER BY rowid",
+                    )?
+                    .query_map([&id], |row| row.get(0))?
+                    .collect::<rusqlite::Result<Vec<String>>>()?;
+                revisions.push(PostRevision {
+                    id: parse_id(&id),
+                    post_id,
+                    title: row.get(1)?,
+                    body: row.get(2)?,
+                    tag,
+                    level: read_level(row, 3)?,
+                    created_at: row.get(4)?,
+                });
+            }
+            Ok(revisions)
+        })
+        .await
+    }
+
+    async fn push_comment(
+        &self,
+        post_id: &str,
+        comment: ServerComment,
+    ) -> Result<bool, StorageError> {
+        let id = ObjectId::parse_str(post_id)?.to_hex();
+        self.call(move |conn| {
+            let tx = conn.transaction()?;
+            let inserted = tx.execute(
+                "INSERT INTO comments
+                    (id, post_id, author_id, body, created_at, parent_id, body_html)
+                 SELECT ?2, id, ?3, ?4, ?5, ?6, ?7 FROM posts WHERE id = ?1",
+                params![
+                    id,
+                    comment.id.to_hex(),
+                    comment.author.to_hex(),
+                    comment.body,
+                    comment.created_at,
+                    comment.parent.map(|p| p.to_hex()),
+                    comment.body_html
+                ],
+            )?;
+            if inserted == 1 {
+                insert_attachments(&tx, &id, Some(comment.id), &comment.attachments)?;
+                index_text(&tx, &id, &comment.body)?;
+            }
+            tx.commit()?;
+            Ok(inserted == 1)
+        })
+        .await
+    }
+
+    async fn update_comment(
+        &self,
+        post_id: &str,
+        comment: ServerComment,
+    ) -> Result<bool, StorageError> {
+        let id = ObjectId::parse_str(post_id)?.to_hex();
+        self.call(move |conn| {
+            let tx = conn.transaction()?;
+            let updated = tx.execute(
+                "UPDATE comments SET body = ?3, updated_at = ?4, deleted = ?5, hidden = ?6,
+                    body_html = ?7
+                 WHERE post_id = ?1 AND id = ?2",
+                params![
+                    id,
+                    comment.id.to_hex(),
+                    comment.body,
+                    comment.updated_at,
+                    comment.deleted,
+                    comment.hidden,
+                    comment.body_html
+                ],
+            )?;
+            if updated == 1 {
+                // 消えた本文を検索に出さないように索引を作り直す
+                let post = tx.query_row(
+                    &format!("SELECT {POST_COLUMNS} FROM posts WHERE id = ?1"),
+                    [&id],
+                    |row| load_post(&tx, row),
+                )?;
+                tx.execute("DELETE FROM post_terms WHERE post_id = ?1", [&id])?;
+                index_text(&tx, &id, &search::post_text(&post))?;
+            }
+            tx.commit()?;
+            Ok(updated == 1)
+        })
+        .await
+    }
+
+    async fn save_draft(&self, draft: ServerDraft) -> Result<(), StorageError> {
+        self.call(move |conn| {
+            let tx = conn.transaction()?;
+            let id = draft.id.to_hex();
+            tx.execute(
+                "INSERT INTO drafts (id, author_id, title, body, level, updated_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+                 ON CONFLICT (id) DO UPDATE SET title = ?3, body = ?4, level = ?5,
+                    updated_at = ?6",
+                params![
+                    id,
+                    draft.author.to_hex(),
+                    draft.title,
+                    draft.body,
+                    draft.level.as_str(),
+                    draft.updated_at
+                ],
+            )?;
+            tx.execute("DELETE FROM draft_tags WHERE draft_id = ?1", [&id])?;
+            for tag in &draft.tag {
+                tx.execute(
+                    "INSERT INTO draft_tags (draft_id, tag) VALUES (?1, ?2)",
+                    params![id, tag],
+                )?;
+            }
+            tx.commit()
+        })
+        .await
+    }
+
+    async fn find_draft(&self, id: &str) -> Result<Option<ServerDraft>, StorageError> {
+        let id = ObjectId::parse_str(id)?.to_hex();
+        self.call(move |conn| {
+            conn.query_row(
+                &format!("SELECT {DRAFT_COLUMNS} FROM drafts WHERE id = ?1"),
+                [id],
+                |row| load_draft(conn, row),
+            )
+            .optional()
+        })
+        .await
+    }
+
+    async fn find_drafts(&self, author: ObjectId) -> Result<Vec<ServerDraft>, StorageError> {
+        self.call(move |conn| {
+            let mut stmt = conn.prepare(&format!(
+                "SELECT {DRAFT_COLUMNS} FROM drafts WHERE author_id = ?1
+                 ORDER BY updated_at DESC, id DESC"
+            ))?;
+            let mut rows = stmt.query([author.to_hex()])?;
+            let mut drafts = vec![];
+            while let Some(row) = rows.next()? {
+                drafts.push(load_draft(conn, row)?);
+            }
+            Ok(drafts)
+        })
+        .await
+    }
+
+    async fn delete_draft(&self, id: &str) -> Result<(), StorageError> {
+        let id = ObjectId::parse_str(id)?.to_hex();
+        self.call(move |conn| {
+            let tx = conn.transaction()?;
+            tx.execute("DELETE FROM draft_tags WHERE draft_id = ?1", [&id])?;
+            tx.execute("DELETE FROM drafts WHERE id = ?1", [&id])?;
+            tx.commit()
+        })
+        .await
+    }
+
+    async fn insert_session(&self, session: Session) -> Result<(), StorageError> {
+        self.call(move |conn| {
+            conn.execute(
+                "INSERT INTO sessions
+                 (id, user_id, name, refresh_hash, expires_at, previous_hash, rotated_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![
+                    session.id,
+                    session.user_id,
+                    session.name,
+                    session.refresh_hash,
+                    session.expires_at,
+                    session.previous_hash,
+                    session.rotated_at
+                ],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn find_session(&self, id: &str) -> Result<Option<Session>, StorageError> {
+        let id = id.to_string();
+        self.call(move |conn| {
+            conn.query_row(
+                "SELECT id, user_id, name, refresh_hash, expires_at, previous_hash, rotated_at
+                 FROM sessions WHERE id = ?1",
+                [id],
+                |row| {
+                    Ok(Session {
+                        id: row.get(0)?,
+                        user_id: row.get(1)?,
+                        name: row.get(2)?,
+                        refresh_hash: row.get(3)?,
+                        expires_at: row.get(4)?,
+                        previous_hash: row.get(5)?,
+                        rotated_at: row.get(6)?,
+                    })
+                },
+            )
+            .optional()
+        })
+        .await
+    }
+
+    async fn rotate_session(
+        &self,
+        id: &str,
+        old_hash: &str,
+        new_hash: &str,
+        expires_at: i64,
+        rotated_at: i64,
+    ) -> Result<bool, StorageError> {
+        let (id, old_hash, new_hash) = (id.to_string(), old_hash.to_string(), new_hash.to_string());
+        self.call(move |conn| {
+            let changed = conn.execute(
+                "UPDATE sessions
+                 SET refresh_hash = ?3, expires_at = ?4, previous_hash = ?2, rotated_at = ?5
+                 WHERE id = ?1 AND refresh_hash = ?2",
+                params![id, old_hash, new_hash, expires_at, rotated_at],
+            )?;
+            Ok(changed == 1)
+        })
+        .await
+    }
+
+    async fn delete_session(&self, id: &str) -> Result<(), StorageError> {
+        let id = id.to_string();
+        self.call(move |conn| {
+            conn.execute("DELETE FROM sessions WHERE id = ?1", [id])?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn delete_sessions_of(&self, user_id: &str) -> Result<(), StorageError> {
+        let user_id = user_id.to_string();
+        self.call(move |conn| {
+            conn.execute("DELETE FROM sessions WHERE user_id = ?1", [user_id])?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn setting(&self) -> Result<Option<DbSetting>, StorageError> {
+        self.call(|conn| {
+            conn.query_row("SELECT password_salt, jwt FROM config LIMIT 1", [], |row| {
+                Ok(DbSetting {
+                    password_salt: row.get(0)?,
+                    jwt: row.get(1)?,
+                })
+            })
+            .optional()
+        })
+        .await
+    }
+}
@@ -0,0 +1,715 @@
+// Storage の実装が同じように振る舞うかを確かめる
+// どのテストもインメモリと (ファイルを作らない) SQLite の両方で動かす
+
+use super::*;
+use std::future::Future;
+
+fn backends() -> Vec<(&'static str, Arc<dyn Storage>)> {
+    vec![
+        ("memory", Arc::new(MemoryStorage::new())),
+        ("sqlite", Arc::new(SqliteStorage::open(":memory:").unwrap())),
+    ]
+}
+
+// SQLite は spawn_blocking を使うので tokio のランタイムの上で動かす
+fn each_backend<F, Fut>(test: F)
+where
+    F: Fn(&'static str, Arc<dyn Storage>) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    for (name, storage) in backends() {
+        runtime.block_on(test(name, storage));
+    }
+}
+
+fn user(name: &str) -> User {
+    User {
+        id: ObjectId::new(),
+        name: name.to_string(),
+        password_hash: "hash".to_string(),
+        display_name: String::new(),
+        bio: String::new(),
+        avatar: None,
+    }
+}
+
+fn post(author: ObjectId, tags: &[&str], created_at: i64) -> ServerPost {
+    ServerPost {
+        author,
+        body: "body".to_string(),
+        attachments: vec![],
+        tag: tags.iter().map(|t| t.to_string()).collect(),
+        title: "title".to_string(),
+        comment: vec![],
+        level: ExperienceLevel::Beginner,
+        created_at,
+        updated_at: None,
+        deleted: false,
+        body_html: "<p>body</p>".to_string(),
+        id: Some(ObjectId::new()),
+    }
+}
+
+fn comment(author: ObjectId, created_at: i64) -> ServerComment {
+    ServerComment {
+        id: ObjectId::new(),
+        author,
+        body: "reply".to_string(),
+        created_at,
+        parent: None,
+        updated_at: None,
+        deleted: false,
+        hidden: false,
+        body_html: "<p>reply</p>".to_string(),
+        attachments: vec![],
+    }
+}
+
+fn ids(posts: &[ServerPost]) -> Vec<ObjectId> {
+    posts.iter().filter_map(|p| p.id).collect()
+}
+
+async fn tag_count(storage: &Arc<dyn Storage>, tag: &str) -> i64 {
+    storage.find_tag(tag).await.unwrap().map_or(0, |t| t.count)
+}
+
+#[test]
+fn insert_user_rejects_taken_name() {
+    each_backend(|backend, storage| async move {
+        let alice = user("alice");
+        assert!(
+            storage.insert_user(alice.clone()).await.unwrap(),
+            "{backend}"
+        );
+        assert!(
+            !storage.insert_user(user("alice")).await.unwrap(),
+            "{backend}"
+        );
+        assert!(storage.insert_user(user("bob")).await.unwrap(), "{backend}");
+
+        let found = storage.find_user("alice").await.unwrap().unwrap();
+        assert_eq!(found.id, alice.id, "{backend}");
+        assert!(
+            storage.find_user("carol").await.unwrap().is_none(),
+            "{backend}"
+        );
+        let users = storage.find_users_by_id(&[alice.id]).await.unwrap();
+        assert_eq!(users.len(), 1, "{backend}");
+    });
+}
+
+#[test]
+fn sessions_rotate_only_from_current_hash() {
+    each_backend(|backend, storage| async move {
+        let session = |id: &str, user_id: &str| Session {
+            id: id.to_string(),
+            user_id: user_id.to_string(),
+            name: "alice".to_string(),
+            refresh_hash: "first".to_string(),
+            expires_at: 100,
+            previous_hash: String::new(),
+            rotated_at: 0,
+        };
+        storage.insert_session(session("a", "alice")).await.unwrap();
+        storage.insert_session(session("b", "alice")).await.unwrap();
+        storage.insert_session(session("c", "bob")).await.unwrap();
+
+        assert!(
+            storage
+                .rotate_session("a", "first", "second", 200, 50)
+                .await
+                .unwrap(),
+            "{backend}"
+        );
+        assert!(
+            !storage
+                .rotate_session("a", "first", "third", 300, 60)
+                .await
+                .unwrap(),
+            "{backend}"
+        );
+        let found = storage.find_session("a").await.unwrap().unwrap();
+        assert_eq!(found.refresh_hash, "second", "{backend}");
+        assert_eq!(found.expires_at, 200, "{backend}");
+        assert_eq!(found.previous_hash, "first", "{backend}");
+        assert_eq!(found.rotated_at, 50, "{backend}");
+
+        storage.delete_session("c").await.unwrap();
+        assert!(
+            storage.find_session("c").await.unwrap().is_none(),
+            "{backend}"
+        );
+        storage.delete_sessions_of("alice").await.unwrap();
+        assert!(
+            storage.find_session("a").await.unwrap().is_none(),
+            "{backend}"
+        );
+        assert!(
+            storage.find_session("b").await.unwrap().is_none(),
+            "{backend}"
+        );
+    });
+}
+
+#[test]
+fn replied_counts_only_visible_comments() {
+    each_backend(|backend, storage| async move {
+        let author = ObjectId::new();
+        let none = post(author, &[], 1);
+        let mut deleted = post(author, &[], 2);
+        let mut c = comment(author, 3);
+        c.deleted = true;
+        deleted.comment.push(c);
+        let mut hidden = post(author, &[], 4);
+        let mut c = comment(author, 5);
+        c.hidden = true;
+        hidden.comment.push(c);
+        let mut visible = post(author, &[], 6);
+        let mut c = comment(author, 7);
+        c.deleted = true;
+        visible.comment.push(c);
+        visible.comment.push(comment(author, 8));
+        for p in [&none, &deleted, &hidden, &visible] {
+            storage.insert_post(p.clone()).await.unwrap();
+        }
+        let find = |replied| {
+            let storage = storage.clone();
+            let filter = PostFilter {
+                replied: Some(replied),
+                ..Default::default()
+            };
+            async move { ids(&storage.find_posts(&filter, None, 10).await.unwrap()) }
+        };
+
+        assert_eq!(find(true).await, vec![visible.id.unwrap()], "{backend}");
+        assert_eq!(
+            find(false).await,
+            vec![hidden.id.unwrap(), deleted.id.unwrap(), none.id.unwrap()],
+            "{backend}"
+        );
+    });
+}
+
+#[test]
+fn find_posts_pages_newest_first() {
+    each_backend(|backend, storage| async move {
+        let author = ObjectId::new();
+        let posts: Vec<ServerPost> = (1..=3).map(|t| post(author, &[], t)).collect();
+        for p in &posts {
+            storage.insert_post(p.clone()).await.unwrap();
+        }
+        let (first, second, third) = (
+            posts[0].id.unwrap(),
+            posts[1].id.unwrap(),
+            posts[2].id.unwrap(),
+        );
+
+        // 前のページの最後の位置から続きを読む
+        let filter = PostFilter::default();
+        let page = storage.find_posts(&filter, None, 2).await.unwrap();
+        assert_eq!(ids(&page), vec![third, second], "{backend}");
+        let last = page.last().unwrap();
+        let cursor = PostCursor {
+            key: filter.sort_key(last),
+            id: last.id.unwrap(),
+        };
+        let page = storage.find_posts(&filter, Some(cursor), 2).await.unwrap();
+        assert_eq!(ids(&page), vec![first], "{backend}");
+    });
+}
+
+#[test]
+fn find_posts_filters_by_terms() {
+    each_backend(|backend, storage| async move {
+        let (alice, bob) = (ObjectId::new(), ObjectId::new());
+        let text = |author, title: &str, created_at| ServerPost {
+            title: title.to_string(),
+            ..post(author, &[], created_at)
+        };
+        let rust = text(alice, "Rustの入門", 1);
+        let go = text(alice, "Goの入門", 2);
+        let bobs = text(bob, "Rustの入門", 3);
+        let mut reply = text(alice, "質問", 4);
+        reply.comment.push(ServerComment {
+            body: "Rust入門の本".to_string(),
+            ..comment(bob, 5)
+        });
+        for p in [&rust, &go, &bobs, &reply] {
+            storage.insert_post(p.clone()).await.unwrap();
+        }
+        let find = |terms: &str, author| {
+            let storage = storage.clone();
+            let filter = PostFilter {
+                terms: search::query_terms(terms),
+                author,
+                ..Default::default()
+            };
+            async move { ids(&storage.find_posts(&filter, None, 10).await.unwrap()) }
+        };
+
+        // 候補を読む前にほかの条件でも絞る
+        assert_eq!(
+            find("rust 入門", Some(alice)).await,
+            vec![reply.id.unwrap(), rust.id.unwrap()],
+            "{backend}"
+        );
+        assert_eq!(find("入門", None).await.len(), 4, "{backend}");
+        assert_eq!(find("python", None).await, vec![], "{backend}");
+    });
+}
+
+#[test]
+fn find_posts_filters() {
+    each_backend(|backend, storage| async move {
+        let (alice, bob) = (ObjectId::new(), ObjectId::new());
+        let mut advanced = post(alice, &["rust"], 10);
+        advanced.level = ExperienceLevel::Advanced;
+        let mut replied = post(bob, &["go"], 20);
+        replied.comment.push(comment(alice, 30));
+        let plain = post(bob, &["rust", "go"], 40);
+        for p in [&advanced, &replied, &plain] {
+            storage.insert_post(p.clone()).await.unwrap();
+        }
+        let find = |filter: PostFilter| {
+            let storage = storage.clone();
+            async move { ids(&storage.find_posts(&filter, None, 10).await.unwrap()) }
+        };
+        let (advanced, replied, plain) =
+            (advanced.id.unwrap(), replied.id.unwrap(), plain.id.unwrap());
+
+        assert_eq!(
+            find(PostFilter::default()).await,
+            vec![plain, replied, advanced],
+            "{backend}"
+        );
+        let filter = PostFilter {
+            all_tags: vec![vec!["rust".to_string()]],
+            ..Default::default()
+        };
+        assert_eq!(find(filter).await, vec![plain, advanced], "{backend}");
+        // tag:rust|java tag:go|c は「rustかjava」かつ「goかc」
+        let filter = PostFilter {
+            all_tags: vec![
+                vec!["rust".to_string(), "java".to_string()],
+                vec!["go".to_string(), "c".to_string()],
+            ],
+            ..Default::default()
+        };
+        assert_eq!(find(filter).await, vec![plain], "{backend}");
+        let filter = PostFilter {
+            all_tags: vec![vec!["rust".to_string(), "go".to_string()]],
+            ..Default::default()
+        };
+        assert_eq!(
+            find(filter).await,
+            vec![plain, replied, advanced],
+            "{backend}"
+        );
+        let filter = PostFilter {
+            not_tags: vec!["rust".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(find(filter).await, vec![replied], "{backend}");
+        let filter = PostFilter {
+            level: Some(ExperienceLevel::Advanced),
+            ..Default::default()
+        };
+        assert_eq!(find(filter).await, vec![advanced], "{backend}");
+        let filter = PostFilter {
+            author: Some(bob),
+            ..Default::default()
+        };
+        assert_eq!(find(filter).await, vec![plain, replied], "{backend}");
+        let filter = PostFilter {
+            commenter: Some(alice),
+            ..Default::default()
+        };
+        assert_eq!(find(filter).await, vec![replied], "{backend}");
+        let filter = PostFilter {
+            since: Some(10),
+            until: Some(40),
+            ..Default::default()
+        };
+        assert_eq!(find(filter).await, vec![replied, advanced], "{backend}");
+        let filter = PostFilter {
+            replied: Some(false),
+            ..Default::default()
+        };
+        assert_eq!(find(filter).await, vec![plain, advanced], "{backend}");
+        let filter = PostFilter {
+            sort: PostSort::MostActive,
+            ..Default::default()
+        };
+        assert_eq!(
+            find(filter).await,
+            vec![plain, replied, advanced],
+            "{backend}"
+        );
+    });
+}
+
+#[test]
+fn posts_count_tags() {
+    each_backend(|backend, storage| async move {
+        let author = ObjectId::new();
+        storage
+            .insert_post(post(author, &["rust", "web"], 1))
+            .await
+            .unwrap();
+        storage
+            .insert_post(post(author, &["rust"], 2))
+            .await
+            .unwrap();
+        assert_eq!(tag_count(&storage, "rust").await, 2, "{backend}");
+        assert_eq!(tag_count(&storage, "web").await, 1, "{backend}");
+        assert_eq!(tag_count(&storage, "go").await, 0, "{backend}");
+        let tags = storage.find_tags_with_prefix("r", 10).await.unwrap();
+        assert_eq!(tags.len(), 1, "{backend}");
+        assert_eq!(tags[0].tag, "rust", "{backend}");
+    });
+}
+
+#[test]
+fn tag_reading_can_be_set_and_cleared() {
+    each_backend(|backend, storage| async move {
+        storage
+            .insert_post(post(ObjectId::new(), &["料理"], 1))
+            .await
+            .unwrap();
+        storage
+            .set_tag_reading("料理", Some("りょうり"))
+            .await
+            .unwrap();
+        let tag = storage.find_tag("料理").await.unwrap().unwrap();
+        assert_eq!(tag.reading.as_deref(), Some("りょうり"), "{backend}");
+        let all = storage.all_tags().await.unwrap();
+        assert_eq!(all[0].reading.as_deref(), Some("りょうり"), "{backend}");
+        storage.set_tag_reading("料理", None).await.unwrap();
+        let tag = storage.find_tag("料理").await.unwrap().unwrap();
+        assert_eq!(tag.reading, None, "{backend}");
+    });
+}
+
+#[test]
+fn find_tags_with_prefix_limits_amount() {
+    each_backend(|backend, storage| async move {
+        let author = ObjectId::new();
+        for (i, tag) in ["rust", "ruby", "react", "go"].iter().enumerate() {
+            storage
+                .insert_post(post(author, &[tag], i as i64))
+                .await
+                .unwrap();
+        }
+        storage
+            .insert_post(post(author, &["ruby"], 10))
+            .await
+            .unwrap();
+        let tags = |amount| {
+            let storage = storage.clone();
+            async move {
+                storage
+                    .find_tags_with_prefix("r", amount)
+                    .await
+                    .unwrap()
+                    .into_iter()
+                    .map(|t| t.tag)
+                    .collect::<Vec<_>>()
+            }
+        };
+        assert_eq!(tags(10).await, vec!["ruby", "react", "rust"], "{backend}");
+        assert_eq!(tags(2).await, vec!["ruby", "react"], "{backend}");
+        // 負の数や0で上限がなくならない
+        assert!(tags(0).await.is_empty(), "{backend}");
+        assert!(tags(-1).await.is_empty(), "{backend}");
+    });
+}
+
+#[test]
+fn merge_tag_moves_posts_and_aliases() {
+    each_backend(|backend, storage| async move {
+        let author = ObjectId::new();
+        let p = post(author, &["js", "web"], 1);
+        let id = p.id.unwrap().to_hex();
+        storage.insert_post(p).await.unwrap();
+        storage
+            .insert_post(post(author, &["ecmascript"], 2))
+            .await
+            .unwrap();
+        storage.merge_tag("ecmascript", "js").await.unwrap();
+
+        assert_eq!(
+            storage.merge_tag("js", "javascript").await.unwrap(),
+            2,
+            "{backend}"
+        );
+        let found = storage.find_post(&id).await.unwrap().unwrap();
+        assert!(found.tag.contains(&"javascript".to_string()), "{backend}");
+        assert!(!found.tag.contains(&"js".to_string()), "{backend}");
+        assert_eq!(tag_count(&storage, "javascript").await, 2, "{backend}");
+        assert_eq!(tag_count(&storage, "js").await, 0, "{backend}");
+        // jsの別名だったタグもjavascriptの別名になる
+        for alias in ["js", "ecmascript"] {
+            let tag = storage.find_tag(alias).await.unwrap().unwrap();
+            assert_eq!(tag.alias_of.as_deref(), Some("javascript"), "{backend}");
+        }
+    });
+}
+
+#[test]
+fn merge_tag_moves_children() {
+    each_backend(|backend, storage| async move {
+        let author = ObjectId::new();
+        for tag in ["js", "react", "web"] {
+            storage.insert_post(post(author, &[tag], 1)).await.unwrap();
+        }
+        storage.set_tag_parent("react", Some("js")).await.unwrap();
+        storage.set_tag_parent("js", Some("web")).await.unwrap();
+        let js = storage.find_tag("js").await.unwrap().unwrap();
+        assert_eq!(js.parent.as_deref(), Some("web"), "{backend}");
+
+        storage.merge_tag("js", "javascript").await.unwrap();
+        let react = storage.find_tag("react").await.unwrap().unwrap();
+        assert_eq!(react.parent.as_deref(), Some("javascript"), "{backend}");
+
+        storage.set_tag_parent("react", None).await.unwrap();
+        let react = storage.find_tag("react").await.unwrap().unwrap();
+        assert_eq!(react.parent, None, "{backend}");
+    });
+}
+
+#[test]
+fn tag_preferences_are_replaced() {
+    each_backend(|backend, storage| async move {
+        let user_id = ObjectId::new();
+        let empty = storage.find_tag_preference(user_id).await.unwrap();
+        assert!(
+            empty.followed.is_empty() && empty.muted.is_empty(),
+            "{backend}"
+        );
+        let preference = |followed: &[&str], muted: &[&str]| TagPreference {
+            user_id,
+            followed: followed.iter().map(|t| t.to_string()).collect(),
+            muted: muted.iter().map(|t| t.to_string()).collect(),
+        };
+        storage
+            .save_tag_preference(preference(&["rust", "web"], &["go"]))
+            .await
+            .unwrap();
+        storage
+            .save_tag_preference(preference(&["rust"], &[]))
+            .await
+            .unwrap();
+        let found = storage.find_tag_preference(user_id).await.unwrap();
+        assert_eq!(found.followed, vec!["rust".to_string()], "{backend}");
+        assert!(found.muted.is_empty(), "{backend}");
+        let other = storage.find_tag_preference(ObjectId::new()).await.unwrap();
+        assert!(other.followed.is_empty(), "{backend}");
+    });
+}
+
+#[test]
+fn edits_keep_revisions_and_recount_tags() {
+    each_backend(|backend, storage| async move {
+        let author = ObjectId::new();
+        let first = post(author, &["rust", "web"], 1);
+        let second = post(author, &["rust"], 2);
+        storage.insert_post(first.clone()).await.unwrap();
+        storage.insert_post(second.clone()).await.unwrap();
+
+        // タグを付け替えると数も付け直す
+        let mut edited = second.clone();
+        edited.title = "edited".to_string();
+        edited.body_html = "<p>edited</p>".to_string();
+        edited.tag = vec!["web".to_string()];
+        edited.updated_at = Some(3);
+        let revision = PostRevision {
+            id: ObjectId::new(),
+            post_id: second.id.unwrap(),
+            title: second.title.clone(),
+            body: second.body.clone(),
+            tag: second.tag.clone(),
+            level: second.level,
+            created_at: second.created_at,
+        };
+        storage.update_post(edited, revision).await.unwrap();
+        assert_eq!(tag_count(&storage, "rust").await, 1, "{backend}");
+        assert_eq!(tag_count(&storage, "web").await, 2, "{backend}");
+        let id = second.id.unwrap().to_hex();
+        let found = storage.find_post(&id).await.unwrap().unwrap();
+        assert_eq!(found.title, "edited", "{backend}");
+        assert_eq!(found.body_html, "<p>edited</p>", "{backend}");
+        assert_eq!(found.updated_at, Some(3), "{backend}");
+        let revisions = storage.find_revisions(&id).await.unwrap();
+        assert_eq!(revisions.len(), 1, "{backend}");
+        assert_eq!(revisions[0].title, "title", "{backend}");
+        assert_eq!(revisions[0].tag, vec!["rust".to_string()], "{backend}");
+
+        // 削除しても find_post では読めるが、一覧には出ない
+        let id = first.id.unwrap().to_hex();
+        assert!(storage.delete_post(&id).await.unwrap(), "{backend}");
+        assert!(!storage.delete_post(&id).await.unwrap(), "{backend}");
+        assert_eq!(tag_count(&storage, "rust").await, 0, "{backend}");
+        assert_eq!(tag_count(&storage, "web").await, 1, "{backend}");
+        assert!(
+            storage.find_post(&id).await.unwrap().unwrap().deleted,
+            "{backend}"
+        );
+        let posts = storage
+            .find_posts(&PostFilter::default(), None, 10)
+            .await
+            .unwrap();
+        assert_eq!(ids(&posts), vec![second.id.unwrap()], "{backend}");
+    });
+}
+
+#[test]
+fn comments_are_updated_in_place() {
+    each_backend(|backend, storage| async move {
+        let author = ObjectId::new();
+        let p = post(author, &[], 1);
+        let id = p.id.unwrap().to_hex();
+        storage.insert_post(p).await.unwrap();
+        let mut c = comment(author, 2);
+        assert!(
+            storage.push_comment(&id, c.clone()).await.unwrap(),
+            "{backend}"
+        );
+        let missing = ObjectId::new().to_hex();
+        assert!(
+            !storage.push_comment(&missing, c.clone()).await.unwrap(),
+            "{backend}"
+        );
+
+        c.body = "edited".to_string();
+        c.body_html = "<p>edited</p>".to_string();
+        c.updated_at = Some(3);
+        c.hidden = true;
+        assert!(
+            storage.update_comment(&id, c.clone()).await.unwrap(),
+            "{backend}"
+        );
+        let other = comment(author, 4);
+        assert!(
+            !storage.update_comment(&id, other).await.unwrap(),
+            "{backend}"
+        );
+
+        let found = storage.find_post(&id).await.unwrap().unwrap();
+        assert_eq!(found.comment.len(), 1, "{backend}");
+        assert_eq!(found.comment[0].body, "edited", "{backend}");
+        assert_eq!(found.comment[0].body_html, "<p>edited</p>", "{backend}");
+        assert_eq!(found.comment[0].updated_at, Some(3), "{backend}");
+        assert!(found.comment[0].hidden, "{backend}");
+    });
+}
+
+#[test]
+fn drafts_are_listed_newest_first() {
+    each_backend(|backend, storage| async move {
+        let author = ObjectId::new();
+        let draft = |updated_at| ServerDraft {
+            id: ObjectId::new(),
+            author,
+            title: "title".to_string(),
+            body: "body".to_string(),
+            tag: vec![],
+            level: ExperienceLevel::Beginner,
+            updated_at,
+        };
+        let (mut old, new) = (draft(1), draft(2));
+        storage.save_draft(old.clone()).await.unwrap();
+        storage.save_draft(new.clone()).await.unwrap();
+        let listed: Vec<_> = storage
+            .find_drafts(author)
+            .await
+            .unwrap()
+            .iter()
+            .map(|d| d.id)
+            .collect();
+        assert_eq!(listed, vec![new.id, old.id], "{backend}");
+
+        // 同じIDで保存すると書き換わる
+        old.title = "saved".to_string();
+        old.updated_at = 3;
+        storage.save_draft(old.clone()).await.unwrap();
+        let drafts = storage.find_drafts(author).await.unwrap();
+        assert_eq!(drafts.len(), 2, "{backend}");
+        assert_eq!(drafts[0].title, "saved", "{backend}");
+
+        storage.delete_draft(&old.id.to_hex()).await.unwrap();
+        let found = storage.find_draft(&old.id.to_hex()).await.unwrap();
+        assert!(found.is_none(), "{backend}");
+        assert_eq!(
+            storage.find_drafts(author).await.unwrap().len(),
+            1,
+            "{backend}"
+        );
+    });
+}
+
+#[test]
+fn update_profile_keeps_name() {
+    each_backend(|backend, storage| async move {
+        let mut alice = user("alice");
+        storage.insert_user(alice.clone()).await.unwrap();
+        alice.display_name = "Alice".to_string();
+        alice.bio = "hello".to_string();
+        alice.avatar = Some("avatar".to_string());
+        assert!(
+            storage.update_profile(alice.clone()).await.unwrap(),
+            "{backend}"
+        );
+        assert!(
+            !storage.update_profile(user("nobody")).await.unwrap(),
+            "{backend}"
+        );
+
+        let found = storage.find_user("alice").await.unwrap().unwrap();
+        assert_eq!(found.display_name, "Alice", "{backend}");
+        assert_eq!(found.bio, "hello", "{backend}");
+        assert_eq!(found.avatar.as_deref(), Some("avatar"), "{backend}");
+    });
+}
+
+#[test]
+fn experiences_are_replaced() {
+    each_backend(|backend, storage| async move {
+        let user_id = ObjectId::new();
+        assert!(
+            storage.find_experiences(user_id).await.unwrap().is_empty(),
+            "{backend}"
+        );
+        let experience = |tag: &str, level, years| TagExperience {
+            tag: tag.to_string(),
+            level,
+            years,
+        };
+        let experiences = vec![
+            experience("rust", ExperienceLevel::Advanced, Some(3)),
+            experience("go", ExperienceLevel::Intermediate, None),
+        ];
+        storage
+            .save_experiences(user_id, experiences.clone())
+            .await
+            .unwrap();
+        let found = storage.find_experiences(user_id).await.unwrap();
+        assert_eq!(found, experiences, "{backend}");
+        // 前の内容は残さずに置き換える
+        storage
+            .save_experiences(user_id, experiences[1..].to_vec())
+            .await
+            .unwrap();
+        let found = storage.find_experiences(user_id).await.unwrap();
+        assert_eq!(found, experiences[1..], "{backend}");
+        // ほかのユーザーの分は見えない
+        assert!(
+            storage
+                .find_experiences(ObjectId::new())
+                .await
+                .unwrap()
+                .is_empty(),
+            "{backend}"
+        );
+    });
+}